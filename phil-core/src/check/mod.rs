@@ -0,0 +1,101 @@
+#[cfg(test)]
+mod test;
+
+use std::{path::Path, process::Stdio};
+
+use monger_core::Monger;
+
+use crate::{
+    error::{Error, Result},
+    version::resolve_version,
+};
+
+/// The outcome of a single pre-flight check.
+#[derive(Debug)]
+pub struct CheckResult {
+    pub description: String,
+    pub passed: bool,
+}
+
+impl CheckResult {
+    fn new(description: impl Into<String>, passed: bool) -> Self {
+        Self {
+            description: description.into(),
+            passed,
+        }
+    }
+}
+
+/// Checks that the given version (or version range) is installed in monger along with the
+/// binaries needed to start a cluster, without starting any processes. `monger_home` has the same
+/// meaning as in `ClusterOptions`.
+pub fn check_installation(
+    version_id: String,
+    sharded: bool,
+    monger_home: Option<&Path>,
+) -> Result<Vec<CheckResult>> {
+    let monger = match monger_home {
+        Some(home) => Monger::with_home(home)?,
+        None => Monger::new()?,
+    };
+
+    let mut results = Vec::new();
+
+    let version = match resolve_version(&monger, version_id.clone()) {
+        Ok(version) => {
+            results.push(CheckResult::new(
+                format!("a version matching '{}' is installed", version_id),
+                true,
+            ));
+
+            version
+        }
+        Err(Error::NoMatchingVersion { .. }) => {
+            results.push(CheckResult::new(
+                format!("a version matching '{}' is installed", version_id),
+                false,
+            ));
+
+            return Ok(results);
+        }
+        Err(error) => return Err(error),
+    };
+
+    let mut binaries = vec!["mongod"];
+
+    if sharded {
+        binaries.push("mongos");
+    }
+
+    // Running each binary through monger checks that it exists and can actually be executed.
+    for binary in binaries {
+        let runs = match monger.command(binary, vec!["--version".into()], &version) {
+            Ok(mut command) => command
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map_or(false, |status| status.success()),
+            Err(..) => false,
+        };
+
+        results.push(CheckResult::new(
+            format!("{} {} runs", binary, version),
+            runs,
+        ));
+    }
+
+    Ok(results)
+}
+
+/// Checks that each of the given TLS files exists.
+pub fn check_tls_files<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Vec<CheckResult> {
+    paths
+        .into_iter()
+        .map(|path| {
+            CheckResult::new(
+                format!("TLS file {} exists", path.display()),
+                path.is_file(),
+            )
+        })
+        .collect()
+}
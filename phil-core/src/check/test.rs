@@ -0,0 +1,9 @@
+use super::*;
+
+#[test]
+fn missing_tls_files_fail() {
+    let results = check_tls_files(vec![Path::new("/nonexistent/ca.pem")]);
+
+    assert_eq!(results.len(), 1);
+    assert!(!results[0].passed);
+}
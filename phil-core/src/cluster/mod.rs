@@ -1,44 +1,247 @@
 #[cfg(test)]
 mod test;
 
-use std::{ffi::OsString, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::File,
+    io::{BufRead, BufReader},
+    num::NonZeroU8,
+    path::{Path, PathBuf},
+    process::Child,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 use monger_core::Monger;
 use mongodb::{
-    options::{ClientOptions, Credential as DriverCredential, Tls, TlsOptions as DriverTlsOptions},
+    bson::{doc, Bson, Document},
+    error::ErrorKind,
+    options::{
+        ClientOptions,
+        Credential as DriverCredential,
+        ReadPreference,
+        StreamAddress,
+        Tls,
+        TlsOptions as DriverTlsOptions,
+    },
     sync::Client,
 };
+use serde::Deserialize;
 use typed_builder::TypedBuilder;
 
 use crate::{
-    error::Result,
-    launch::{Launcher, Node},
+    display::ClientOptionsWrapper,
+    error::{Error, Result},
+    event::EventWriter,
+    launch::{
+        admin_db,
+        advertised_address,
+        allocate_port,
+        arg_value,
+        assembly_option_problems,
+        check_deadline,
+        command_error_response,
+        config_version,
+        initiate_repl_set,
+        local_client_options,
+        localhost_address,
+        log_path,
+        option_problems,
+        replace_arg_value,
+        router_log_file,
+        terminate,
+        terminate_running_processes,
+        track_process,
+        untrack_process,
+        validate_advertise_host,
+        CommandResponse,
+        Launcher,
+        ListShardsResponse,
+        MongodOptions,
+        Node,
+        ReplSetMember,
+        ReplSetStatus,
+        Router,
+        DEFAULT_PORT,
+    },
+    shutdown::{ClusterState, ProcessState},
 };
 
+#[derive(Debug, Deserialize)]
+struct BuildInfo {
+    version: String,
+}
+
 #[derive(Debug, Clone)]
 pub enum Topology {
     Single,
     ReplicaSet {
         set_name: String,
         db_paths: Vec<PathBuf>,
+
+        /// The configuration for each member, indexed in the same order as the `db_paths`. Members
+        /// without an entry use the server defaults.
+        members: Vec<ReplSetMemberConfig>,
     },
     Sharded {
-        num_mongos: u8,
+        num_mongos: NonZeroU8,
         shard_db_paths: Vec<Vec<PathBuf>>,
-        config_db_path: PathBuf,
+
+        /// The data directory of each config server node.
+        config_db_paths: Vec<PathBuf>,
     },
 }
 
+/// Overrides for the configuration of a single replica set member.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplSetMemberConfig {
+    pub priority: Option<f64>,
+    pub votes: Option<i32>,
+    pub hidden: bool,
+
+    /// Arbiters still need a data directory, since the server stores the replica set
+    /// configuration in it.
+    pub arbiter_only: bool,
+}
+
+impl ReplSetMemberConfig {
+    /// Whether the member is able to become primary.
+    pub(crate) fn electable(&self) -> bool {
+        !self.arbiter_only
+            && !self.hidden
+            && self.votes != Some(0)
+            && self.priority.map_or(true, |priority| priority > 0.0)
+    }
+
+    pub(crate) fn apply(&self, member: &mut Document) {
+        match self.priority {
+            Some(priority) => {
+                member.insert("priority", priority);
+            }
+            // The server's default priority of 1 isn't allowed for hidden or non-voting members.
+            None if self.hidden || self.votes == Some(0) => {
+                member.insert("priority", 0);
+            }
+            None => {}
+        }
+
+        if let Some(votes) = self.votes {
+            member.insert("votes", votes);
+        }
+
+        if self.hidden {
+            member.insert("hidden", true);
+        }
+
+        if self.arbiter_only {
+            member.insert("arbiterOnly", true);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Cluster {
-    pub(crate) monger: Monger,
+    pub(crate) version: String,
+    pub(crate) shard_version: String,
+    pub(crate) shard_name_prefix: String,
     pub(crate) client: Client,
     pub(crate) client_options: ClientOptions,
     pub(crate) topology: Topology,
     pub(crate) tls: Option<TlsOptions>,
     pub(crate) auth: Option<Credential>,
     pub(crate) nodes: Vec<Node>,
+    pub(crate) routers: Vec<Router>,
+    pub(crate) uninitiated_repl_set_config: Option<Document>,
+    pub(crate) log_dir: Option<PathBuf>,
+    pub(crate) temp_log_dir: bool,
     pub(crate) cluster_id: String,
+    pub(crate) startup_timeout: Option<Duration>,
+    pub(crate) load_balanced: bool,
+    pub(crate) max_connecting: Option<u32>,
+    pub(crate) socket_paths: Vec<PathBuf>,
+    pub(crate) srv_hostname: Option<String>,
+    pub(crate) advertise_host: Option<String>,
+    pub(crate) bind_ip: Option<String>,
+    pub(crate) auto_port: bool,
+    pub(crate) primary: Option<StreamAddress>,
+    pub(crate) log_tailers: Vec<JoinHandle<()>>,
+    pub(crate) stop_log_tailers: Arc<AtomicBool>,
+
+    /// The launcher that started the cluster, which is kept to start new shards with the same
+    /// options. The cluster's processes are owned by the `Cluster` rather than the launcher.
+    pub(crate) launcher: Launcher,
+}
+
+/// The log file of one of the cluster's processes.
+#[derive(Debug)]
+struct LogFilePath {
+    port: u16,
+    path: PathBuf,
+
+    /// The name to give the file when collecting the cluster's logs.
+    name: String,
+}
+
+/// A line written to the log of one of the cluster's processes.
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    /// The port of the mongod or mongos that wrote the line.
+    pub port: u16,
+    pub line: String,
+}
+
+/// A mongod started as part of a cluster.
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    pub port: u16,
+
+    /// The address to connect to the mongod with, which uses the advertised host if one was set.
+    pub address: StreamAddress,
+
+    /// The data directory, or `None` for a single server using the server's default.
+    pub db_path: Option<PathBuf>,
+
+    /// The replica set the mongod belongs to, if any. This is the shard's replica set name for
+    /// replica set shards and the config server replica set name for config servers.
+    pub repl_set_name: Option<String>,
+
+    pub config_server: bool,
+
+    /// The index of the shard the mongod belongs to, or `None` if it isn't a shard member.
+    pub shard_num: Option<usize>,
+}
+
+impl NodeInfo {
+    pub fn shard_server(&self) -> bool {
+        self.shard_num.is_some()
+    }
+}
+
+/// A mongos started as part of a sharded cluster.
+#[derive(Debug, Clone)]
+pub struct RouterInfo {
+    pub port: u16,
+
+    /// The address to connect to the mongos with, which uses the advertised host if one was set.
+    pub address: StreamAddress,
+}
+
+/// Starts the components of a sharded cluster one at a time, which allows assembling topologies
+/// that can't be expressed with `Topology::Sharded` (e.g. a mix of singleton and replica set
+/// shards, or multiple config server replica sets). The `topology` of the `ClusterOptions` used to
+/// create the assembler is ignored. If the assembler is dropped without being finished, every
+/// process it started is killed.
+#[derive(Debug)]
+pub struct ShardedClusterAssembler {
+    // This is only `None` once the assembler has been finished.
+    launcher: Option<Launcher>,
+    credential: Option<Credential>,
 }
 
 #[derive(Clone, Debug, TypedBuilder)]
@@ -47,6 +250,19 @@ pub struct ClusterOptions {
 
     pub version_id: String,
 
+    /// Overrides the version used for the config servers of a sharded cluster. Like `version_id`,
+    /// this can be a monger ID or a version range.
+    #[builder(default)]
+    pub(crate) config_server_version_id: Option<String>,
+
+    /// Overrides the version used for the shard servers of a sharded cluster.
+    #[builder(default)]
+    pub(crate) shard_version_id: Option<String>,
+
+    /// Overrides the version used for the mongos routers of a sharded cluster.
+    #[builder(default)]
+    pub(crate) mongos_version_id: Option<String>,
+
     #[builder(default)]
     pub paths: Vec<PathBuf>,
 
@@ -57,33 +273,363 @@ pub struct ClusterOptions {
     pub auth: Option<Credential>,
 
     #[builder(default)]
-    extra_mongod_args: Vec<OsString>,
+    pub(crate) extra_mongod_args: Vec<OsString>,
+
+    /// Deprecated: phil's progress is now reported through the `log` crate, so configure the
+    /// logger instead. Setting this raises the maximum log level to debug, where the messages it
+    /// used to enable are logged. This is independent of `mongod_verbosity`, which controls the
+    /// servers' own logs.
+    #[builder(default)]
+    pub(crate) verbose: bool,
+
+    #[builder(default)]
+    pub(crate) deprecated_tls_options: bool,
+
+    #[builder(default)]
+    pub(crate) save_logs: bool,
+
+    /// How long (in milliseconds) each mongos waits on the config servers when refreshing the
+    /// routing info in its catalog cache, set via the `findChunksOnConfigTimeoutMS` server
+    /// parameter. This must be positive.
+    #[builder(default)]
+    pub(crate) mongos_config_refresh_ms: Option<u32>,
+
+    /// The maximum number of incoming connections each mongod and mongos accepts, passed as
+    /// `--maxConns`. This must be positive.
+    #[builder(default)]
+    pub(crate) max_incoming_connections: Option<u32>,
+
+    /// Overrides `max_incoming_connections` for the mongos routers of a sharded cluster.
+    #[builder(default)]
+    pub(crate) mongos_max_incoming_connections: Option<u32>,
+
+    /// Extra arguments passed verbatim to each mongos. The extra mongod arguments aren't passed to
+    /// mongos.
+    #[builder(default)]
+    pub(crate) extra_mongos_args: Vec<OsString>,
+
+    /// Emit a `loadBalanced=true` connection string pointing at a single mongos. This only has an
+    /// effect for sharded topologies. Note that phil does not start an actual load balancer, so
+    /// the mongos must be configured to accept load-balanced connections (e.g. via the
+    /// `loadBalancerPort` server parameter on 5.0+) for drivers to connect successfully.
+    #[builder(default)]
+    pub(crate) load_balanced: bool,
+
+    /// The tags for each member of a replica set topology, indexed in the same order as the
+    /// `db_paths`.
+    #[builder(default)]
+    pub(crate) member_tags: Vec<Option<Document>>,
+
+    /// The maximum amount of time to spend starting and configuring the cluster. If this is
+    /// exceeded, any processes that have been started are killed. Defaults to 60 seconds; `None`
+    /// waits indefinitely.
+    #[builder(default = Some(Duration::from_secs(60)))]
+    pub(crate) startup_timeout: Option<Duration>,
+
+    /// The feature compatibility version (e.g. "4.4") to set once the cluster is ready.
+    #[builder(default)]
+    pub(crate) feature_compatibility_version: Option<String>,
+
+    /// The cluster-wide default write concern `w` value (e.g. "majority" or "1") to set with
+    /// `setDefaultRWConcern` once the cluster is ready. This requires MongoDB 4.4 or later and a
+    /// replica set or sharded cluster.
+    #[builder(default)]
+    pub(crate) default_write_concern: Option<String>,
+
+    /// The cluster-wide default read concern level (one of "local", "available", or "majority")
+    /// to set with `setDefaultRWConcern` once the cluster is ready. This requires MongoDB 4.4 or
+    /// later and a replica set or sharded cluster.
+    #[builder(default)]
+    pub(crate) default_read_concern: Option<String>,
+
+    /// Enables encryption at rest using the given key file. This requires MongoDB Enterprise.
+    #[builder(default)]
+    pub(crate) encryption_key_file: Option<PathBuf>,
+
+    /// The replica set name of the config server for sharded topologies. Defaults to
+    /// "<shard_name_prefix>-config" if a shard name prefix is set, or "phil-config-server"
+    /// otherwise.
+    #[builder(default)]
+    pub(crate) config_set_name: Option<String>,
+
+    /// The prefix of the shard names in sharded topologies, which are numbered from 0 (e.g.
+    /// "phil-replset-shard-0"). Setting distinct prefixes keeps the shards of concurrently running
+    /// clusters distinguishable. Defaults to "phil-replset-shard".
+    #[builder(default)]
+    pub(crate) shard_name_prefix: Option<String>,
+
+    /// Start shards with a single data directory as one-node replica sets rather than as
+    /// standalone shard servers. Shards with more than one data directory are always replica sets.
+    /// This only applies to sharded topologies.
+    #[builder(default)]
+    pub(crate) one_node_replset_shards: bool,
+
+    /// Start the config server as a standalone mongod (SCCC) rather than as a replica set (CSRS),
+    /// which is needed to test against servers older than 3.2. Servers from 3.4 onward only
+    /// support CSRS, so this is rejected for them. This only applies to sharded topologies.
+    #[builder(default)]
+    pub(crate) legacy_config_servers: bool,
+
+    /// Have each mongod create its unix domain socket in its data directory and report those
+    /// socket paths from `Cluster::socket_paths`. The servers still listen on TCP as well, since
+    /// the driver used to configure the cluster doesn't support unix domain sockets.
+    #[builder(default)]
+    pub(crate) unix_socket: bool,
+
+    /// Emit a `mongodb+srv://` connection string for the given hostname rather than listing the
+    /// hosts. phil can't create DNS records, so the SRV records returned by `Cluster::srv_records`
+    /// have to be set up separately for drivers to connect. This can't be combined with
+    /// `unix_socket`.
+    #[builder(default)]
+    pub(crate) srv_hostname: Option<String>,
+
+    /// The hostname to use in place of `localhost` in the emitted connection string and the
+    /// replica set configs, e.g. a Docker service name so that other containers can reach the
+    /// cluster. The servers still bind to their usual addresses, so binding to a reachable
+    /// interface (e.g. with `--bind_ip_all`) has to be configured separately.
+    #[builder(default)]
+    pub(crate) advertise_host: Option<String>,
+
+    /// The address for the servers to listen on in addition to localhost, e.g. `0.0.0.0` to listen
+    /// on every interface. Unless `advertise_host` is set, a specific address is also used in the
+    /// connection string and replica set configs.
+    #[builder(default)]
+    pub(crate) bind_ip: Option<String>,
+
+    /// Whether the emitted connection string should enable retryable writes. If unset, the
+    /// driver's default is used.
+    #[builder(default)]
+    pub(crate) retry_writes: Option<bool>,
+
+    /// The IP addresses/CIDR ranges that are allowed to authenticate as cluster members. Note that
+    /// this must include the addresses the cluster members themselves connect from (e.g.
+    /// 127.0.0.1).
+    #[builder(default)]
+    pub(crate) cluster_ip_source_allowlist: Vec<String>,
+
+    /// Sets the `enableTestCommands` server parameter on every mongod and mongos.
+    #[builder(default)]
+    pub(crate) enable_test_commands: bool,
+
+    /// Skips ports that are already in use on localhost when assigning ports to the servers,
+    /// rather than assigning them strictly in order from the starting port.
+    #[builder(default)]
+    pub(crate) auto_port: bool,
+
+    /// A file to write lifecycle events to as JSON lines (e.g. `/dev/fd/3` to write to an
+    /// inherited file descriptor on unix).
+    #[builder(default)]
+    pub(crate) events_file: Option<PathBuf>,
+
+    /// A writer to send lifecycle events to as JSON lines, e.g. a pipe to a supervising process.
+    /// This can be used along with `events_file`, in which case events are written to both.
+    #[builder(default)]
+    pub(crate) events_writer: Option<EventWriter>,
+
+    /// The interval in milliseconds between journal commits on each mongod. This must be between 1
+    /// and 500.
+    #[builder(default)]
+    pub(crate) journal_commit_interval_ms: Option<u32>,
+
+    /// The profiling level (0 to 2) each data-bearing mongod starts with, passed as `--profile`
+    /// so that profiling is active before the first operation.
+    #[builder(default)]
+    pub(crate) startup_profile_level: Option<u8>,
+
+    /// The slow operation threshold in milliseconds each data-bearing mongod starts with, passed
+    /// as `--slowms`.
+    #[builder(default)]
+    pub(crate) startup_slow_ms: Option<u32>,
+
+    /// The WiredTiger block compressor (snappy, zlib, zstd, or none) for collection data on each
+    /// data-bearing mongod.
+    #[builder(default)]
+    pub(crate) collection_block_compressor: Option<String>,
 
+    /// The WiredTiger compressor (snappy, zlib, zstd, or none) for the journal on each
+    /// data-bearing mongod.
     #[builder(default)]
-    verbose: bool,
+    pub(crate) journal_compressor: Option<String>,
 
+    /// The storage engine of each mongod, including the config servers, passed as
+    /// `--storageEngine`.
     #[builder(default)]
-    deprecated_tls_options: bool,
+    pub(crate) storage_engine: Option<String>,
 
+    /// The maximum size in gigabytes of the WiredTiger cache of each mongod, including the config
+    /// servers.
     #[builder(default)]
-    save_logs: bool,
+    pub(crate) wired_tiger_cache_size_gb: Option<f64>,
+
+    /// The log verbosity level (0 to 5) of each mongod, which is passed as the corresponding
+    /// number of `-v` flags.
+    #[builder(default)]
+    pub(crate) mongod_verbosity: Option<u8>,
+
+    /// The directory containing the `.monger` directory to use instead of the one in the user's
+    /// home directory.
+    #[builder(default)]
+    pub(crate) monger_home: Option<PathBuf>,
+
+    /// Start the replica set members without initiating the replica set. The set can be initiated
+    /// later with `Cluster::initiate_replset`. This only applies to replica set topologies and
+    /// can't be combined with auth or a feature compatibility version.
+    #[builder(default)]
+    pub(crate) no_initiate: bool,
+
+    /// Whether to wait for a replica set topology to elect a primary after initiating it. When
+    /// false, `Cluster::new` returns as soon as `replSetInitiate` succeeds, so the cluster may not
+    /// have a primary yet and operations that need one will fail until the election completes.
+    /// This only applies to replica set topologies and can't be combined with auth or a feature
+    /// compatibility version, since both need a primary during startup.
+    #[builder(default = true)]
+    pub(crate) wait_for_primary: bool,
+
+    /// The `version` of the initial config for a replica set topology. If unset, the server picks
+    /// the version (currently 1). This must be at least 1.
+    #[builder(default)]
+    pub(crate) repl_set_config_version: Option<i32>,
+
+    /// The `maxPoolSize` to use in the emitted connection string.
+    #[builder(default)]
+    pub(crate) max_pool_size: Option<u32>,
+
+    /// The `minPoolSize` to use in the emitted connection string.
+    #[builder(default)]
+    pub(crate) min_pool_size: Option<u32>,
+
+    /// The `maxConnecting` to use in the emitted connection string.
+    #[builder(default)]
+    pub(crate) max_connecting: Option<u32>,
+
+    /// Have each mongod and mongos write its log to a file in this directory named after its role
+    /// and port (e.g. `shard0-27020.log`). The logs can then be copied elsewhere with
+    /// `Cluster::collect_logs`.
+    #[builder(default)]
+    pub(crate) log_dir: Option<PathBuf>,
+
+    /// Keep the servers' startup warnings (e.g. about transparent huge pages) out of phil's
+    /// stdout by having them log to files. If `log_dir` isn't set, the logs are written to a new
+    /// directory under the system temp directory, which is removed when the cluster is shut down.
+    #[builder(default)]
+    pub(crate) suppress_startup_warnings: bool,
+
+    /// CPU cores to pin the mongods to, assigned round-robin in the order the mongods are started.
+    /// This is only supported on Linux (using `taskset`) and is ignored with a warning elsewhere.
+    #[builder(default)]
+    pub(crate) cpu_affinity: Vec<usize>,
+
+    /// The network compressors for the servers to accept and for the emitted connection string
+    /// to request. Each must be one of "snappy", "zlib", or "zstd".
+    #[builder(default)]
+    pub(crate) compressors: Vec<String>,
+
+    /// The read preference to use in the emitted connection string. This isn't used for the
+    /// commands phil runs to set up the cluster.
+    #[builder(default)]
+    pub(crate) read_preference: Option<ReadPreference>,
+
+    /// Require a topology that supports sessions (i.e. not a single server), and use a default
+    /// read concern of "majority" in the emitted connection string for causal consistency.
+    #[builder(default)]
+    pub(crate) require_sessions: bool,
+
+    /// Start data-bearing mongods with `--enableMajorityReadConcern false`. Config servers always
+    /// support majority read concern, so they're unaffected. This can't be combined with
+    /// `require_sessions`.
+    #[builder(default)]
+    pub(crate) disable_majority_read_concern: bool,
+
+    /// A label for the cluster that's included in progress output and lifecycle events.
+    #[builder(default)]
+    pub(crate) name: Option<String>,
+
+    /// The port of the first process started. Every other process gets the port after the
+    /// previous one, so the cluster uses a contiguous range of ports beginning here. Defaults to
+    /// 27017.
+    #[builder(default = DEFAULT_PORT)]
+    pub(crate) start_port: u16,
+
+    /// The maximum number of mongod and mongos processes that may be started. Defaults to 12.
+    #[builder(default)]
+    pub(crate) max_nodes: Option<usize>,
+
+    /// Start the cluster even if it needs more than `max_nodes` processes.
+    #[builder(default)]
+    pub(crate) allow_large: bool,
+}
+
+impl ClusterOptions {
+    /// Checks the options for problems that would prevent the cluster from starting without
+    /// starting any processes. Every problem found is reported in the returned error rather than
+    /// only the first one. This is called by `Cluster::new`, so it only needs to be called
+    /// directly to check options ahead of time.
+    pub fn validate(&self) -> Result<()> {
+        let problems = option_problems(self);
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidOptions {
+                message: problems.join("; "),
+            })
+        }
+    }
+
+    /// The extra arguments passed to each mongod.
+    pub fn extra_mongod_args(&self) -> &[OsString] {
+        &self.extra_mongod_args
+    }
+
+    /// Allows modifying the extra arguments passed to each mongod after the options are built.
+    pub fn extra_mongod_args_mut(&mut self) -> &mut Vec<OsString> {
+        &mut self.extra_mongod_args
+    }
+}
+
+/// Asks every mongod and mongos that phil has started and not yet shut down to shut down. This is
+/// intended to be called from a signal handler if phil is interrupted while starting a cluster.
+/// This is a no-op on Windows, where processes are left running.
+pub fn terminate_started_processes() {
+    terminate_running_processes();
 }
 
 #[derive(Debug, Clone)]
 pub struct TlsOptions {
     pub weak_tls: bool,
+
+    /// Whether phil's own client (and the emitted connection string) accepts invalid server
+    /// certificates.
     pub allow_invalid_certificates: bool,
+
+    /// Whether the servers accept invalid certificates from their peers. Servers validate peer
+    /// certificates unless this is set.
+    pub server_allow_invalid_certificates: bool,
+
     pub ca_file_path: PathBuf,
     pub server_cert_file_path: PathBuf,
     pub client_cert_file_path: PathBuf,
+
+    /// The passphrase for an encrypted certificate key file, which is passed to the servers. The
+    /// driver version phil uses can't decrypt key files, so phil's own client still needs an
+    /// unencrypted one.
+    pub cert_key_password: Option<String>,
 }
 
 impl From<TlsOptions> for Tls {
+    /// When `weak_tls` is set, the servers don't require clients to present a certificate, so
+    /// only the CA file is configured.
     fn from(opts: TlsOptions) -> Self {
+        let cert_key_file_path = if opts.weak_tls {
+            None
+        } else {
+            Some(opts.server_cert_file_path.to_string_lossy().into_owned())
+        };
+
         DriverTlsOptions::builder()
             .allow_invalid_certificates(opts.allow_invalid_certificates)
             .ca_file_path(opts.ca_file_path.to_string_lossy().into_owned())
-            .cert_key_file_path(opts.server_cert_file_path.to_string_lossy().into_owned())
+            .cert_key_file_path(cert_key_file_path)
             .build()
             .into()
     }
@@ -94,6 +640,27 @@ pub struct Credential {
     pub username: String,
     pub password: String,
     pub key_file: PathBuf,
+
+    /// The database to create the user in. Defaults to "admin".
+    pub source: Option<String>,
+
+    /// The roles to grant the user. If empty, the user is granted `root` on the admin database.
+    /// Setting the feature compatibility version or the default read/write concern requires the
+    /// `root` role.
+    pub roles: Vec<Role>,
+}
+
+/// A built-in or user-defined role on a specific database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Role {
+    pub role: String,
+    pub db: String,
+}
+
+impl Role {
+    pub(crate) fn is_root(&self) -> bool {
+        self.role == "root" && self.db == "admin"
+    }
 }
 
 impl From<Credential> for DriverCredential {
@@ -101,22 +668,95 @@ impl From<Credential> for DriverCredential {
         Self::builder()
             .username(credential.username)
             .password(credential.password)
+            .source(credential.source)
             .build()
     }
 }
 
+impl ShardedClusterAssembler {
+    pub fn new(options: ClusterOptions) -> Result<Self> {
+        let problems = assembly_option_problems(&options);
+
+        if !problems.is_empty() {
+            return Err(Error::InvalidOptions {
+                message: problems.join("; "),
+            });
+        }
+
+        let mut launcher = Launcher::new(options)?;
+        let credential = launcher.prepare()?;
+
+        Ok(Self {
+            launcher: Some(launcher),
+            credential,
+        })
+    }
+
+    fn launcher(&mut self) -> &mut Launcher {
+        self.launcher
+            .as_mut()
+            .expect("the launcher is only taken when the assembler is finished")
+    }
+
+    pub fn start_config_replset(&mut self, set_name: &str, db_paths: Vec<PathBuf>) -> Result<()> {
+        self.launcher().start_config_repl_set(set_name, db_paths)
+    }
+
+    /// Starts a mongos router using the given config server replica set, returning its port.
+    pub fn start_mongos_router(&mut self, config_set_name: &str) -> Result<u16> {
+        self.launcher().start_router(config_set_name)
+    }
+
+    /// Starts a new shard and adds it to the cluster via the mongos on the given port. A single
+    /// data directory will start a singleton shard, and multiple will start a replica set shard.
+    pub fn add_shard(&mut self, mongos_port: u16, db_paths: Vec<PathBuf>) -> Result<()> {
+        let launcher = self.launcher();
+        launcher.check_assembled_process_count(db_paths.len())?;
+        launcher.add_shard(mongos_port, db_paths)
+    }
+
+    pub fn finish(mut self) -> Result<Cluster> {
+        let credential = self.credential.take();
+        let mut launcher = self
+            .launcher
+            .take()
+            .expect("the launcher is only taken when the assembler is finished");
+
+        let client_options = launcher.finish_assembly(credential);
+        launcher.into_cluster(client_options)
+    }
+}
+
+impl Drop for ShardedClusterAssembler {
+    fn drop(&mut self) {
+        if let Some(ref mut launcher) = self.launcher {
+            launcher.kill_all_processes();
+        }
+    }
+}
+
 impl Cluster {
     pub fn new(options: ClusterOptions) -> Result<Self> {
-        let launcher = Launcher::new(
-            options.topology,
-            options.version_id,
-            options.tls,
-            options.auth,
-            options.verbose,
-            options.deprecated_tls_options,
-            options.save_logs,
-            options.extra_mongod_args,
-        )?;
+        options.validate()?;
+
+        let launcher = Launcher::new(options)?;
+
+        launcher.initialize_cluster()
+    }
+
+    /// Like `Cluster::new`, but uses the given `Monger` to find and run the server binaries rather
+    /// than creating one. This can't be combined with `monger_home`, since that's only used to
+    /// create the `Monger`.
+    pub fn new_with_monger(options: ClusterOptions, monger: Monger) -> Result<Self> {
+        options.validate()?;
+
+        if options.monger_home.is_some() {
+            return Err(Error::InvalidOptions {
+                message: "a monger home can't be set when providing a Monger".into(),
+            });
+        }
+
+        let launcher = Launcher::with_monger(options, monger)?;
 
         launcher.initialize_cluster()
     }
@@ -124,4 +764,1256 @@ impl Cluster {
     pub fn client_options(&self) -> &ClientOptions {
         &self.client_options
     }
+
+    pub fn connection_string(&self) -> String {
+        ClientOptionsWrapper {
+            options: &self.client_options,
+            load_balanced: if self.load_balanced { Some(true) } else { None },
+            max_connecting: self.max_connecting,
+            socket_paths: &self.socket_paths,
+            srv_hostname: self.srv_hostname.as_deref(),
+        }
+        .to_string()
+    }
+
+    /// What's needed to stop the cluster once this process has exited (e.g. with
+    /// `shutdown::stop`). The recorded connection string points at the routers (or the nodes, if
+    /// there are none) over localhost, so that it works regardless of the advertised host or SRV
+    /// hostname.
+    pub fn state(&self) -> ClusterState {
+        let ports: Vec<_> = if self.routers.is_empty() {
+            self.nodes.iter().map(|node| node.options.port).collect()
+        } else {
+            self.routers
+                .iter()
+                .map(|router| router.options.port)
+                .collect()
+        };
+
+        let mut client_options = self.client_options.clone();
+        client_options.hosts = ports.into_iter().map(localhost_address).collect();
+
+        let uri = ClientOptionsWrapper {
+            options: &client_options,
+            load_balanced: None,
+            max_connecting: None,
+            socket_paths: &[],
+            srv_hostname: None,
+        }
+        .to_string();
+
+        let nodes = self.nodes.iter().map(|node| ProcessState {
+            pid: node.process.id(),
+            port: node.options.port,
+            db_path: node.options.db_path.clone(),
+        });
+        let routers = self.routers.iter().map(|router| ProcessState {
+            pid: router.process.id(),
+            port: router.options.port,
+            db_path: None,
+        });
+
+        ClusterState {
+            uri,
+            processes: nodes.chain(routers).collect(),
+        }
+    }
+
+    /// The DNS records (in zone file format) needed for the `mongodb+srv://` connection string to
+    /// resolve to the cluster's hosts, or an empty list if no SRV hostname was configured.
+    pub fn srv_records(&self) -> Vec<String> {
+        let srv_hostname = match self.srv_hostname {
+            Some(ref hostname) => hostname,
+            None => return Vec::new(),
+        };
+
+        self.client_options
+            .hosts
+            .iter()
+            .map(|host| {
+                format!(
+                    "_mongodb._tcp.{}. 60 IN SRV 0 0 {} {}.",
+                    srv_hostname,
+                    host.port.unwrap_or(DEFAULT_PORT),
+                    host.hostname
+                )
+            })
+            .collect()
+    }
+
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// The ID of the monger-managed version the cluster was started with. If a version range was
+    /// given, this is the installed version it resolved to.
+    pub fn version_id(&self) -> &str {
+        &self.version
+    }
+
+    /// The version reported by the running servers' `buildInfo`, which may be more specific than
+    /// (or otherwise differ from) the monger ID the cluster was started with.
+    pub fn version(&self) -> Result<String> {
+        let response = admin_db(&self.client).run_command(doc! { "buildInfo": 1 }, None)?;
+        let BuildInfo { version } = mongodb::bson::from_document(response)?;
+
+        Ok(version)
+    }
+
+    pub fn load_balanced(&self) -> bool {
+        self.load_balanced
+    }
+
+    pub fn socket_paths(&self) -> &[PathBuf] {
+        &self.socket_paths
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn auth_enabled(&self) -> bool {
+        self.auth.is_some()
+    }
+
+    /// The username of the user created when auth is enabled. The password is deliberately not
+    /// exposed.
+    pub fn auth_username(&self) -> Option<&str> {
+        self.auth.as_ref().map(|auth| auth.username.as_str())
+    }
+
+    /// Shuts down every process in the cluster. For sharded clusters, the mongos routers are shut
+    /// down first, then the shards, and then the config servers.
+    pub fn shutdown(mut self) -> Result<()> {
+        for (_, process) in self.processes_in_shutdown_order() {
+            terminate(process)?;
+        }
+
+        self.stop_tailing_logs();
+        self.remove_temp_log_dir()?;
+
+        self.routers.clear();
+        self.nodes.clear();
+
+        Ok(())
+    }
+
+    /// Removes the log directory if it was created under the system temp directory to suppress
+    /// startup warnings. This should only be called once the processes have exited.
+    fn remove_temp_log_dir(&mut self) -> Result<()> {
+        if !std::mem::take(&mut self.temp_log_dir) {
+            return Ok(());
+        }
+
+        match self.log_dir {
+            Some(ref log_dir) => match std::fs::remove_dir_all(log_dir) {
+                Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Leaves the cluster's processes running after the `Cluster` is dropped rather than shutting
+    /// them down, e.g. so that they outlive the program that started them.
+    pub fn detach(mut self) {
+        self.stop_tailing_logs();
+
+        // The processes keep writing to their log files after we exit.
+        self.temp_log_dir = false;
+
+        for (_, process) in self.processes_in_shutdown_order() {
+            untrack_process(process);
+        }
+
+        self.routers.clear();
+        self.nodes.clear();
+    }
+
+    /// Shuts down the cluster and starts it again with the given options, reusing the existing
+    /// data directories. The topology of `new_options` is ignored in favor of the current one.
+    pub fn restart_with(self, mut new_options: ClusterOptions) -> Result<Cluster> {
+        new_options.topology = self.topology.clone();
+        self.shutdown()?;
+
+        Cluster::new(new_options)
+    }
+
+    /// A `phil` command line that would start a cluster equivalent to this one, using the
+    /// resolved version and explicit values for the topology defaults. Passwords are omitted, as
+    /// are the options that only affect the `phil` invocation rather than the cluster:
+    /// `--data-dir`, `--config-data-dir`, `--tmpfs`, `--strict-tls`, `--events-file`,
+    /// `--monger-home`, `--version-lock-file`, `--uri-file`, `--state-file` and `--verbose`.
+    pub fn reproduce_command(&self) -> String {
+        let mut args: Vec<String> = vec!["phil".into()];
+
+        match self.topology {
+            Topology::Single => args.extend(vec!["single".into(), self.version.clone()]),
+            Topology::ReplicaSet {
+                ref set_name,
+                ref db_paths,
+                ..
+            } => args.extend(vec![
+                "replset".into(),
+                self.version.clone(),
+                "--nodes".into(),
+                db_paths.len().to_string(),
+                "--set-name".into(),
+                set_name.clone(),
+            ]),
+            Topology::Sharded {
+                num_mongos,
+                ref shard_db_paths,
+                ref config_db_paths,
+            } => {
+                // A one-node shard may still be a replica set, so the shard type is taken from the
+                // shard servers themselves rather than the number of nodes.
+                let replset_shards = self.nodes.iter().any(|node| {
+                    node.options.shard_num.is_some() && node.options.repl_set_name.is_some()
+                });
+                let shard_type = if replset_shards { "replset" } else { "single" };
+
+                args.extend(vec![
+                    "sharded".into(),
+                    self.version.clone(),
+                    "--num-mongos".into(),
+                    num_mongos.to_string(),
+                    "--num-shards".into(),
+                    shard_db_paths.len().to_string(),
+                    "--shard-type".into(),
+                    shard_type.into(),
+                ]);
+
+                if let Some(nodes) = shard_db_paths.first().map(Vec::len) {
+                    if shard_type == "replset" && nodes != 3 {
+                        args.extend(vec!["--nodes-per-shard".into(), nodes.to_string()]);
+                    }
+                }
+
+                if config_db_paths.len() > 1 {
+                    args.extend(vec![
+                        "--config-nodes".into(),
+                        config_db_paths.len().to_string(),
+                    ]);
+                }
+
+                let config_set_name = self
+                    .nodes
+                    .iter()
+                    .find(|node| node.options.config_server)
+                    .and_then(|node| node.options.repl_set_name.clone());
+
+                match config_set_name {
+                    Some(name) => args.extend(vec!["--config-set-name".into(), name]),
+                    None => args.push("--legacy-config-servers".into()),
+                }
+            }
+        }
+
+        // Ports are allocated sequentially, so the lowest one is the port the cluster started at.
+        let start_port = self
+            .nodes
+            .iter()
+            .map(|node| node.options.port)
+            .chain(self.routers.iter().map(|router| router.options.port))
+            .min();
+
+        if let Some(port) = start_port.filter(|&port| port != DEFAULT_PORT) {
+            args.extend(vec!["--start-port".into(), port.to_string()]);
+        }
+
+        if let Some(ref tls) = self.tls {
+            args.extend(vec![
+                "--tls".into(),
+                "--ca-file".into(),
+                tls.ca_file_path.display().to_string(),
+                "--server-cert-file".into(),
+                tls.server_cert_file_path.display().to_string(),
+                "--client-cert-file".into(),
+                tls.client_cert_file_path.display().to_string(),
+            ]);
+
+            if tls.weak_tls {
+                args.push("--allow-clients-without-certs".into());
+            }
+
+            if !tls.allow_invalid_certificates {
+                args.push("--strict-phil-client-tls".into());
+            }
+
+            if tls.server_allow_invalid_certificates {
+                args.push("--server-allow-invalid-certs".into());
+            }
+        }
+
+        if let Some(ref auth) = self.auth {
+            args.push("--auth".into());
+
+            if let Some(ref source) = auth.source {
+                args.extend(vec!["--auth-source".into(), source.clone()]);
+            }
+
+            // The password is deliberately left out, as with `auth_username`.
+            if auth.username != "phil" {
+                args.extend(vec!["--username".into(), auth.username.clone()]);
+            }
+
+            for role in &auth.roles {
+                args.extend(vec!["--role".into(), format!("{}@{}", role.role, role.db)]);
+            }
+        }
+
+        if self.load_balanced {
+            args.push("--load-balanced".into());
+        }
+
+        if !self.socket_paths.is_empty() {
+            args.push("--unix-socket".into());
+        }
+
+        if let Some(ref hostname) = self.srv_hostname {
+            args.extend(vec!["--srv-hostname".into(), hostname.clone()]);
+        }
+
+        if let Some(ref host) = self.advertise_host {
+            args.extend(vec!["--advertise-host".into(), host.clone()]);
+        }
+
+        if let Some(ref bind_ip) = self.bind_ip {
+            args.extend(vec!["--bind-ip".into(), bind_ip.clone()]);
+        }
+
+        if self.auto_port {
+            args.push("--auto-port".into());
+        }
+
+        // A temporary log directory is created by `--suppress-startup-warnings`, which the
+        // launcher's arguments include.
+        if let Some(log_dir) = self.log_dir.as_ref().filter(|_| !self.temp_log_dir) {
+            args.extend(vec!["--log-dir".into(), log_dir.display().to_string()]);
+        }
+
+        let client_options = &self.client_options;
+
+        if let Some(retry_writes) = client_options.retry_writes {
+            args.extend(vec!["--retry-writes".into(), retry_writes.to_string()]);
+        }
+
+        if let Some(max_pool_size) = client_options.max_pool_size {
+            args.extend(vec!["--max-pool-size".into(), max_pool_size.to_string()]);
+        }
+
+        if let Some(min_pool_size) = client_options.min_pool_size {
+            args.extend(vec!["--min-pool-size".into(), min_pool_size.to_string()]);
+        }
+
+        if let Some(max_connecting) = self.max_connecting {
+            args.extend(vec!["--max-connecting".into(), max_connecting.to_string()]);
+        }
+
+        if let Some(ref compressors) = client_options.compressors {
+            args.extend(vec!["--compressors".into(), compressors.join(",")]);
+        }
+
+        args.extend(self.launcher.reproduce_args());
+
+        args.iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn processes_in_shutdown_order(&mut self) -> Vec<(u16, &mut Child)> {
+        let (config_nodes, shard_nodes): (Vec<_>, Vec<_>) = self
+            .nodes
+            .iter_mut()
+            .partition(|node| node.options.config_server);
+
+        self.routers
+            .iter_mut()
+            .map(|router| (router.options.port, &mut router.process))
+            .chain(
+                shard_nodes
+                    .into_iter()
+                    .chain(config_nodes)
+                    .map(|node| (node.options.port, &mut node.process)),
+            )
+            .collect()
+    }
+
+    /// The members of each replica set shard, keyed by the shard's replica set name. Singleton
+    /// shards aren't included.
+    pub fn shard_replica_sets(&self) -> BTreeMap<String, Vec<StreamAddress>> {
+        let mut shards: BTreeMap<String, Vec<StreamAddress>> = BTreeMap::new();
+
+        for node in &self.nodes {
+            if let (Some(_), Some(set_name)) = (node.options.shard_num, &node.options.repl_set_name)
+            {
+                shards
+                    .entry(set_name.clone())
+                    .or_default()
+                    .push(self.advertised_address(node.options.port));
+            }
+        }
+
+        shards
+    }
+
+    /// The address of the replica set's primary as of startup or the last call to
+    /// `refresh_primary`. This is `None` for non-replica set topologies, and also if no primary had
+    /// been elected yet (e.g. when the cluster was started without waiting for one).
+    pub fn primary_address(&self) -> Option<&StreamAddress> {
+        self.primary.as_ref()
+    }
+
+    /// Queries the replica set for its current primary and updates the address returned by
+    /// `primary_address`.
+    pub fn refresh_primary(&mut self) -> Result<Option<&StreamAddress>> {
+        if !matches!(self.topology, Topology::ReplicaSet { .. }) {
+            return Ok(None);
+        }
+
+        self.primary = self
+            .current_primary_port()?
+            .map(|port| self.advertised_address(port));
+
+        // With an advertised host, the client is connected directly to the primary, so it has to
+        // follow the primary to a new member.
+        if self.advertise_host.is_some() {
+            self.client = Client::with_options(self.local_client_options())?;
+        }
+
+        Ok(self.primary.as_ref())
+    }
+
+    /// The port of the replica set member that currently reports itself as the primary, if any.
+    fn current_primary_port(&self) -> Result<Option<u16>> {
+        let response = admin_db(&self.client).run_command(doc! { "replSetGetStatus": 1 }, None)?;
+        let ReplSetStatus { members } = mongodb::bson::from_document(response)?;
+
+        Ok(members
+            .iter()
+            .find(|member| member.state_str == "PRIMARY")
+            .and_then(ReplSetMember::port)
+            .filter(|port| self.nodes.iter().any(|node| node.options.port == *port)))
+    }
+
+    /// Asks the replica set's current primary to step down, forcing an election. The old primary
+    /// can't be re-elected until `timeout` has passed. Note that this doesn't wait for a new
+    /// primary to be elected; use `refresh_primary` to find it afterwards.
+    pub fn step_down_primary(&self, timeout: Duration) -> Result<()> {
+        if !matches!(self.topology, Topology::ReplicaSet { .. }) {
+            return Err(Error::InvalidOptions {
+                message: "only replica sets have a primary to step down".into(),
+            });
+        }
+
+        let port = self
+            .current_primary_port()?
+            .ok_or_else(|| Error::NoPrimary {
+                set_name: self
+                    .client_options
+                    .repl_set_name
+                    .clone()
+                    .unwrap_or_default(),
+            })?;
+
+        let options = ClientOptions::builder()
+            .hosts(vec![localhost_address(port)])
+            .tls(self.tls.clone().map(Into::into))
+            .credential(self.client_options.credential.clone())
+            .direct_connection(true)
+            .build();
+        let client = Client::with_options(options)?;
+
+        // The server rejects a catch-up period longer than the step down period.
+        let step_down_secs = timeout.as_secs() as i64;
+        let command = doc! {
+            "replSetStepDown": step_down_secs,
+            "secondaryCatchUpPeriodSecs": step_down_secs.min(10),
+        };
+
+        // Older servers close every connection when the primary steps down, so a network error
+        // means the step down succeeded.
+        match admin_db(&client).run_command(command, None) {
+            Err(error) if matches!(error.kind.as_ref(), ErrorKind::Io(..)) => Ok(()),
+            Err(error) => Err(error.into()),
+            Ok(..) => Ok(()),
+        }
+    }
+
+    /// Initiates a replica set that was started with `no_initiate` and waits for it to elect a
+    /// primary.
+    pub fn initiate_replset(&mut self) -> Result<()> {
+        let config =
+            self.uninitiated_repl_set_config
+                .take()
+                .ok_or_else(|| Error::InvalidOptions {
+                    message: "the cluster has no uninitiated replica set".into(),
+                })?;
+
+        let port = self.client_options.hosts[0].port.unwrap_or_default();
+        let options = ClientOptions::builder()
+            .hosts(vec![localhost_address(port)])
+            .tls(self.tls.clone().map(Into::into))
+            .direct_connection(true)
+            .build();
+
+        initiate_repl_set(options, config, self.deadline())?;
+        self.refresh_primary()?;
+
+        Ok(())
+    }
+
+    /// Runs `replSetReconfig` with the given config against the primary. The config's version is
+    /// set to one more than the current config's version, so it doesn't need to be specified.
+    pub fn reconfigure_replset(&self, mut config: Document, force: bool) -> Result<()> {
+        if !matches!(self.topology, Topology::ReplicaSet { .. }) {
+            return Err(Error::InvalidOptions {
+                message: "only replica sets can be reconfigured".into(),
+            });
+        }
+
+        let current_version = config_version(&self.current_repl_set_config()?);
+
+        config.insert("version", current_version + 1);
+
+        let set_name = config.get_str("_id").ok().map(Into::into);
+        let command = doc! {
+            "replSetReconfig": config,
+            "force": force,
+        };
+
+        let response = match admin_db(&self.client).run_command(command, None) {
+            Ok(response) => response,
+            Err(error) => command_error_response(&error).ok_or(error)?,
+        };
+
+        let CommandResponse { ok, .. } = mongodb::bson::from_document(response.clone())?;
+
+        if ok != 1.0 {
+            return Err(Error::ReplicaSetConfigError {
+                response,
+                set_name,
+                port: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the host of every replica set member to `new_host` (keeping the ports) and applies
+    /// the new config with a forced `replSetReconfig`. Afterwards, the addresses reported by the
+    /// cluster and the emitted connection string use `new_host` as well.
+    pub fn rewrite_member_hosts(&mut self, new_host: &str) -> Result<()> {
+        validate_advertise_host(new_host)?;
+
+        let mut config = self.current_repl_set_config()?;
+        let members: Vec<_> = config
+            .get_array("members")
+            .map(Clone::clone)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|member| match member {
+                Bson::Document(mut member) => {
+                    let port = member
+                        .get_str("host")
+                        .ok()
+                        .and_then(|host| host.rsplit(':').next())
+                        .map(ToString::to_string);
+
+                    if let Some(port) = port {
+                        member.insert("host", format!("{}:{}", new_host, port));
+                    }
+
+                    Bson::Document(member)
+                }
+                other => other,
+            })
+            .collect();
+        config.insert("members", members);
+
+        self.reconfigure_replset(config, true)?;
+
+        self.advertise_host = Some(new_host.into());
+        self.client_options.hosts = self
+            .client_options
+            .hosts
+            .iter()
+            .map(|host| advertised_address(Some(new_host), host.port.unwrap_or(DEFAULT_PORT)))
+            .collect();
+        self.primary = self.primary.take().map(|primary| {
+            advertised_address(Some(new_host), primary.port.unwrap_or(DEFAULT_PORT))
+        });
+        self.client = Client::with_options(self.local_client_options())?;
+
+        Ok(())
+    }
+
+    /// Copies the log file of each of the cluster's mongods and mongos routers into `dest`, which
+    /// is created if it doesn't exist. The copies are named after the process's role, e.g.
+    /// `config.log`, `shard1-node0.log` or `mongos0.log`. This requires the cluster to have been
+    /// started with a `log_dir`.
+    pub fn collect_logs(&self, dest: &Path) -> Result<()> {
+        if self.log_dir.is_none() {
+            return Err(Error::InvalidOptions {
+                message: "logs can only be collected from clusters started with a log directory"
+                    .into(),
+            });
+        }
+
+        std::fs::create_dir_all(dest)?;
+
+        for log_file in self.log_files() {
+            std::fs::copy(&log_file.path, dest.join(log_file.name))?;
+        }
+
+        Ok(())
+    }
+
+    /// The log file of every mongod and mongos that was started with a `--logpath`, along with a
+    /// descriptive name for it. Nodes are numbered within their shard or replica set, and routers
+    /// in the order they were started.
+    fn log_files(&self) -> Vec<LogFilePath> {
+        let num_config_servers = self
+            .nodes
+            .iter()
+            .filter(|node| node.options.config_server)
+            .count();
+        let mut node_counts: BTreeMap<Option<usize>, usize> = BTreeMap::new();
+        let mut num_config_nodes = 0;
+
+        let nodes = self.nodes.iter().map(|node| {
+            let name = if node.options.config_server {
+                num_config_nodes += 1;
+
+                if num_config_servers == 1 {
+                    "config.log".into()
+                } else {
+                    format!("config-node{}.log", num_config_nodes - 1)
+                }
+            } else {
+                let count = node_counts.entry(node.options.shard_num).or_default();
+                *count += 1;
+
+                match node.options.shard_num {
+                    Some(shard_num) => format!("shard{}-node{}.log", shard_num, *count - 1),
+                    None => format!("node{}.log", *count - 1),
+                }
+            };
+
+            (node.options.port, &node.args, name)
+        });
+
+        let routers = self.routers.iter().enumerate().map(|(i, router)| {
+            (
+                router.options.port,
+                &router.args,
+                format!("mongos{}.log", i),
+            )
+        });
+
+        nodes
+            .chain(routers)
+            .filter_map(|(port, args, name)| {
+                arg_value(args, "--logpath").map(|path| LogFilePath {
+                    port,
+                    path: path.into(),
+                    name,
+                })
+            })
+            .collect()
+    }
+
+    /// Streams the lines of every mongod and mongos log file, starting from the beginning of each
+    /// file, to the returned receiver. Each file is read by its own thread, which exits once the
+    /// receiver is dropped or the cluster is shut down. This requires the cluster to have been
+    /// started with a `log_dir`.
+    pub fn tail_logs(&mut self) -> Result<Receiver<LogLine>> {
+        if self.log_dir.is_none() {
+            return Err(Error::InvalidOptions {
+                message: "logs can only be tailed for clusters started with a log directory".into(),
+            });
+        }
+
+        let (sender, receiver) = mpsc::channel();
+
+        for LogFilePath { port, path, .. } in self.log_files() {
+            let file = File::open(&path)?;
+            let sender = sender.clone();
+            let stop = self.stop_log_tailers.clone();
+
+            self.log_tailers.push(std::thread::spawn(move || {
+                tail_log_file(port, file, sender, stop)
+            }));
+        }
+
+        Ok(receiver)
+    }
+
+    fn stop_tailing_logs(&mut self) {
+        self.stop_log_tailers.store(true, Ordering::SeqCst);
+
+        for tailer in self.log_tailers.drain(..) {
+            let _ = tailer.join();
+        }
+    }
+
+    /// Starts a new mongod using the given data directory and adds it to the replica set, waiting
+    /// for it to become a secondary. An `Error::Timeout` is returned if that takes longer than the
+    /// cluster's startup timeout.
+    pub fn add_replset_node(&mut self, db_path: PathBuf) -> Result<()> {
+        let set_name = match self.topology {
+            Topology::ReplicaSet { ref set_name, .. } => set_name.clone(),
+            _ => {
+                return Err(Error::InvalidOptions {
+                    message: "nodes can only be added to replica sets".into(),
+                })
+            }
+        };
+
+        let template = self.nodes.first().ok_or_else(|| Error::InvalidOptions {
+            message: "the replica set has no members to copy the options of".into(),
+        })?;
+
+        let port = self.next_port()?;
+
+        let mut args = replace_arg_value(&template.args, "--port", port.to_string().into());
+        args = replace_arg_value(&args, "--dbpath", db_path.clone().into());
+        args = replace_arg_value(&args, "--unixSocketPrefix", db_path.clone().into());
+
+        if let Some(ref log_dir) = self.log_dir {
+            args = replace_arg_value(&args, "--logpath", log_path(log_dir, "mongod", port).into());
+        }
+
+        let process =
+            self.launcher
+                .monger()
+                .start_mongod(args.clone(), &self.version, false, None)?;
+        track_process(&process);
+
+        self.nodes.push(Node {
+            process,
+            options: MongodOptions {
+                port,
+                db_path: Some(db_path),
+                config_server: false,
+                shard_num: None,
+                repl_set_name: Some(set_name),
+            },
+            args,
+        });
+
+        let host = self.advertised_address(port).to_string();
+        let db = admin_db(&self.client);
+
+        let mut config = self.current_repl_set_config()?;
+        let mut members = config
+            .get_array("members")
+            .map(Clone::clone)
+            .unwrap_or_default();
+
+        let next_id = members
+            .iter()
+            .filter_map(|member| member.as_document())
+            .filter_map(|member| member.get_i32("_id").ok())
+            .max()
+            .map_or(0, |id| id + 1);
+
+        members.push(Bson::Document(
+            doc! { "_id": next_id, "host": host.clone() },
+        ));
+        config.insert("members", members);
+
+        self.reconfigure_replset(config, false)?;
+
+        let deadline = self.deadline();
+        let operation = format!("waiting for {} to become a secondary", host);
+
+        loop {
+            check_deadline(deadline, &operation)?;
+
+            let response = db.run_command(doc! { "replSetGetStatus": 1 }, None)?;
+            let ReplSetStatus { members } = mongodb::bson::from_document(response)?;
+
+            if members
+                .iter()
+                .any(|member| member.name == host && member.state_str == "SECONDARY")
+            {
+                return Ok(());
+            }
+
+            std::thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    /// Waits until every part of the cluster is usable: for a sharded cluster, until each mongos
+    /// reports all of the shards, and for a replica set, until every member is either the primary,
+    /// a secondary, or an arbiter.
+    pub fn wait_until_ready(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+
+        match self.topology {
+            Topology::Single => wait_until(deadline, "waiting for the server to respond", || {
+                Ok(admin_db(&self.client)
+                    .run_command(doc! { "ping": 1 }, None)
+                    .is_ok())
+            }),
+            Topology::ReplicaSet { .. } => {
+                let db = admin_db(&self.client);
+
+                wait_until(
+                    deadline,
+                    "waiting for the replica set to be healthy",
+                    || {
+                        let response = match db.run_command(doc! { "replSetGetStatus": 1 }, None) {
+                            Ok(response) => response,
+                            Err(..) => return Ok(false),
+                        };
+                        let ReplSetStatus { members } = mongodb::bson::from_document(response)?;
+
+                        let primaries = members
+                            .iter()
+                            .filter(|member| member.state_str == "PRIMARY")
+                            .count();
+
+                        Ok(primaries == 1
+                            && members.len() == self.nodes.len()
+                            && members.iter().all(|member| {
+                                matches!(
+                                    member.state_str.as_str(),
+                                    "PRIMARY" | "SECONDARY" | "ARBITER"
+                                )
+                            }))
+                    },
+                )
+            }
+            Topology::Sharded {
+                ref shard_db_paths, ..
+            } => {
+                for router in &self.routers {
+                    let port = router.options.port;
+
+                    let options = ClientOptions::builder()
+                        .hosts(vec![localhost_address(port)])
+                        .credential(self.client_options.credential.clone())
+                        .tls(self.tls.clone().map(Into::into))
+                        .build();
+                    let client = Client::with_options(options)?;
+                    let db = admin_db(&client);
+
+                    let operation =
+                        format!("waiting for mongos on port {} to see all shards", port);
+
+                    wait_until(deadline, &operation, || {
+                        match db.run_command(doc! { "listShards": 1 }, None) {
+                            Ok(response) => {
+                                let ListShardsResponse { shards } =
+                                    mongodb::bson::from_document(response)?;
+
+                                Ok(shards.len() >= shard_db_paths.len())
+                            }
+                            Err(..) => Ok(false),
+                        }
+                    })?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Starts a new shard using the given data directories and adds it to the cluster through the
+    /// routers. Like when the cluster is started, several data directories give a replica set
+    /// shard, and a single one gives a standalone shard unless the cluster was started with
+    /// one-node replica set shards.
+    pub fn add_shard(&mut self, db_paths: Vec<PathBuf>) -> Result<()> {
+        if !matches!(self.topology, Topology::Sharded { .. }) {
+            return Err(Error::InvalidOptions {
+                message: "shards can only be added to sharded clusters".into(),
+            });
+        }
+
+        let mongos_port = self
+            .routers
+            .first()
+            .map(|router| router.options.port)
+            .ok_or_else(|| Error::InvalidOptions {
+                message: "the cluster has no routers to add the shard through".into(),
+            })?;
+        let next_port = self.port_after_processes();
+
+        self.launcher.with_processes(
+            &mut self.nodes,
+            &mut self.routers,
+            next_port,
+            |launcher| {
+                launcher.check_assembled_process_count(db_paths.len())?;
+                launcher.add_shard(mongos_port, db_paths.clone())
+            },
+        )?;
+
+        if let Topology::Sharded {
+            ref mut shard_db_paths,
+            ..
+        } = self.topology
+        {
+            shard_db_paths.push(db_paths);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the member on the given port from the replica set and then shuts it down.
+    pub fn remove_replset_node(&mut self, port: u16) -> Result<()> {
+        if !matches!(self.topology, Topology::ReplicaSet { .. }) {
+            return Err(Error::InvalidOptions {
+                message: "nodes can only be removed from replica sets".into(),
+            });
+        }
+
+        let index = self
+            .nodes
+            .iter()
+            .position(|node| node.options.port == port)
+            .ok_or(Error::NoProcess { port })?;
+
+        let host = self.advertised_address(port).to_string();
+        let mut config = self.current_repl_set_config()?;
+        let members = config
+            .get_array("members")
+            .map(Clone::clone)
+            .unwrap_or_default();
+
+        let votes = |member: &Document| match member.get("votes") {
+            Some(Bson::Int32(votes)) => i64::from(*votes),
+            Some(Bson::Int64(votes)) => *votes,
+            Some(Bson::Double(votes)) => *votes as i64,
+            _ => 1,
+        };
+
+        let (removed, remaining): (Vec<_>, Vec<_>) = members
+            .into_iter()
+            .filter_map(|member| member.as_document().cloned())
+            .partition(|member| member.get_str("host").map_or(false, |h| h == host));
+
+        if removed.iter().any(|member| votes(member) > 0)
+            && !remaining.iter().any(|member| votes(member) > 0)
+        {
+            return Err(Error::InvalidOptions {
+                message: format!(
+                    "cannot remove {} since it's the last voting member of the replica set",
+                    host
+                ),
+            });
+        }
+
+        config.insert(
+            "members",
+            remaining
+                .into_iter()
+                .map(Bson::Document)
+                .collect::<Vec<_>>(),
+        );
+        self.reconfigure_replset(config, false)?;
+
+        let mut node = self.nodes.remove(index);
+        terminate(&mut node.process)
+    }
+
+    /// Waits until every secondary in the replica set has replicated up to the primary's optime,
+    /// returning an error if that doesn't happen before the timeout elapses.
+    pub fn wait_for_replication(&self, timeout: Duration) -> Result<()> {
+        if !matches!(self.topology, Topology::ReplicaSet { .. }) {
+            return Err(Error::InvalidOptions {
+                message: "replication can only be waited on for replica sets".into(),
+            });
+        }
+
+        let deadline = Instant::now() + timeout;
+        let db = admin_db(&self.client);
+
+        wait_until(
+            deadline,
+            "waiting for secondaries to catch up to the primary",
+            || {
+                let response = db.run_command(doc! { "replSetGetStatus": 1 }, None)?;
+                let ReplSetStatus { members } = mongodb::bson::from_document(response)?;
+
+                let primary_optime = members
+                    .iter()
+                    .find(|member| member.state_str == "PRIMARY")
+                    .and_then(|member| member.optime.as_ref());
+
+                Ok(primary_optime.map_or(false, |primary_optime| {
+                    members
+                        .iter()
+                        .filter(|member| member.state_str == "SECONDARY")
+                        .all(|member| member.optime.as_ref() == Some(primary_optime))
+                }))
+            },
+        )
+    }
+
+    /// Pings every mongod and mongos in the cluster over a direct connection, returning how long
+    /// each ping took or the error it failed with. The mongods are listed before the routers.
+    pub fn ping_all(&self) -> Vec<(StreamAddress, Result<Duration>)> {
+        let ports = self
+            .nodes
+            .iter()
+            .map(|node| node.options.port)
+            .chain(self.routers.iter().map(|router| router.options.port));
+
+        ports
+            .map(|port| {
+                let address = localhost_address(port);
+                (address.clone(), self.ping(address))
+            })
+            .collect()
+    }
+
+    fn ping(&self, address: StreamAddress) -> Result<Duration> {
+        // Unreachable processes should be reported quickly rather than after the driver's default
+        // server selection timeout.
+        let options = ClientOptions::builder()
+            .hosts(vec![address])
+            .tls(self.tls.clone().map(Into::into))
+            .credential(self.client_options.credential.clone())
+            .direct_connection(true)
+            .server_selection_timeout(Duration::from_secs(2))
+            .build();
+        let client = Client::with_options(options)?;
+        let db = admin_db(&client);
+
+        // The first command also establishes (and authenticates) the connection, so only the
+        // second one is timed.
+        db.run_command(doc! { "ping": 1 }, None)?;
+
+        let start = Instant::now();
+        db.run_command(doc! { "ping": 1 }, None)?;
+
+        Ok(start.elapsed())
+    }
+
+    /// The options for our own connections to the cluster, which go over localhost rather than to
+    /// the advertised addresses.
+    fn local_client_options(&self) -> ClientOptions {
+        local_client_options(
+            &self.client_options,
+            self.advertise_host.as_deref(),
+            self.primary.as_ref().and_then(|primary| primary.port),
+        )
+    }
+
+    /// The deadline for configuring new members or replica sets, which is bounded by the same
+    /// `startup_timeout` as starting the cluster.
+    fn deadline(&self) -> Option<Instant> {
+        self.startup_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    fn current_repl_set_config(&self) -> Result<Document> {
+        let response = admin_db(&self.client).run_command(doc! { "replSetGetConfig": 1 }, None)?;
+
+        match response.get_document("config") {
+            Ok(config) => Ok(config.clone()),
+            Err(..) => Err(Error::ReplicaSetConfigError {
+                response,
+                set_name: None,
+                port: None,
+            }),
+        }
+    }
+
+    /// Kills the mongos router listening on the given port without giving it a chance to shut down
+    /// cleanly.
+    pub fn kill_mongos(&mut self, port: u16) -> Result<()> {
+        let router = self.router_mut(port)?;
+        untrack_process(&router.process);
+        router.process.kill()?;
+        router.process.wait()?;
+
+        Ok(())
+    }
+
+    /// Restarts the mongos router listening on the given port with the same options it was
+    /// originally started with. The router is shut down first if it's still running.
+    pub fn restart_mongos(&mut self, port: u16) -> Result<()> {
+        let index = self
+            .routers
+            .iter()
+            .position(|router| router.options.port == port)
+            .ok_or(Error::NoProcess { port })?;
+
+        if self.routers[index].process.try_wait()?.is_none() {
+            terminate(&mut self.routers[index].process)?;
+        }
+
+        // The router's original arguments already include its `--logpath` when the cluster has a
+        // log directory, but logs saved by monger have to be requested again.
+        let args = self.routers[index].args.clone();
+        let version = self.routers[index].version.clone();
+        let log_file = if self.routers[index].save_logs {
+            Some(router_log_file(&self.cluster_id, port))
+        } else {
+            None
+        };
+        self.routers[index].process = self
+            .launcher
+            .monger()
+            .start_mongos(args, &version, false, log_file)?;
+        track_process(&self.routers[index].process);
+
+        Ok(())
+    }
+
+    /// A port for a new process, allocated like the ones the cluster was started with, beginning
+    /// after the highest port used by any of the cluster's processes.
+    fn next_port(&self) -> Result<u16> {
+        allocate_port(&mut self.port_after_processes(), self.auto_port)
+    }
+
+    /// The port after the highest one used by any of the cluster's processes, or `None` if that's
+    /// the highest port.
+    fn port_after_processes(&self) -> Option<u16> {
+        self.nodes
+            .iter()
+            .map(|node| node.options.port)
+            .chain(self.routers.iter().map(|router| router.options.port))
+            .max()
+            .map_or(Some(DEFAULT_PORT), |port| port.checked_add(1))
+    }
+
+    fn router_mut(&mut self, port: u16) -> Result<&mut Router> {
+        self.routers
+            .iter_mut()
+            .find(|router| router.options.port == port)
+            .ok_or(Error::NoProcess { port })
+    }
+
+    /// The mongods in the cluster in the order they were started, including shard members and
+    /// config servers.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.nodes
+            .iter()
+            .map(|node| NodeInfo {
+                port: node.options.port,
+                address: self.advertised_address(node.options.port),
+                db_path: node.options.db_path.clone(),
+                repl_set_name: node.options.repl_set_name.clone(),
+                config_server: node.options.config_server,
+                shard_num: node.options.shard_num,
+            })
+            .collect()
+    }
+
+    /// The mongos routers in the cluster. This is empty for non-sharded topologies.
+    pub fn routers(&self) -> Vec<RouterInfo> {
+        self.routers
+            .iter()
+            .map(|router| RouterInfo {
+                port: router.options.port,
+                address: self.advertised_address(router.options.port),
+            })
+            .collect()
+    }
+
+    /// The addresses of the mongos routers. This is empty for non-sharded topologies.
+    pub fn mongos_addresses(&self) -> Vec<StreamAddress> {
+        self.routers
+            .iter()
+            .map(|router| self.advertised_address(router.options.port))
+            .collect()
+    }
+
+    /// The addresses of the config server replica set members. This is empty for non-sharded
+    /// topologies.
+    pub fn config_server_addresses(&self) -> Vec<StreamAddress> {
+        self.nodes
+            .iter()
+            .filter(|node| node.options.config_server)
+            .map(|node| self.advertised_address(node.options.port))
+            .collect()
+    }
+
+    fn advertised_address(&self, port: u16) -> StreamAddress {
+        advertised_address(self.advertise_host.as_deref(), port)
+    }
+}
+
+// A cluster that's dropped without being shut down or detached would otherwise leave its servers
+// running and bound to their ports, so any processes that haven't exited are terminated. Errors
+// are ignored, since there's no way to report them here.
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        self.stop_tailing_logs();
+
+        for (_, process) in self.processes_in_shutdown_order() {
+            if let Ok(None) = process.try_wait() {
+                let _ = terminate(process);
+            }
+        }
+
+        let _ = self.remove_temp_log_dir();
+    }
+}
+
+fn tail_log_file(port: u16, file: File, sender: Sender<LogLine>, stop: Arc<AtomicBool>) {
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+
+    loop {
+        match reader.read_line(&mut line) {
+            // A line without a trailing newline is still being written, so we keep what's been
+            // read so far and wait for the rest.
+            Ok(_) if line.ends_with('\n') => {
+                let log_line = LogLine {
+                    port,
+                    line: line.trim_end().to_string(),
+                };
+
+                if sender.send(log_line).is_err() {
+                    return;
+                }
+
+                line.clear();
+            }
+            Ok(_) => {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(..) => return,
+        }
+    }
+}
+
+/// Polls `condition` until it holds, returning a timeout error for `operation` once the deadline
+/// passes.
+fn wait_until(
+    deadline: Instant,
+    operation: &str,
+    mut condition: impl FnMut() -> Result<bool>,
+) -> Result<()> {
+    loop {
+        if condition()? {
+            return Ok(());
+        }
+
+        check_deadline(Some(deadline), operation)?;
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Quotes an argument for a POSIX shell if it contains anything other than characters that are
+/// always safe to leave unquoted.
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./,:=+@%".contains(c);
+
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.into()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
 }
@@ -1,29 +1,42 @@
+use std::{
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
 use mongodb::bson::{self, doc};
 use serde::Deserialize;
 use tempdir::TempDir;
 use uuid::Uuid;
 
 use super::*;
+use crate::error::Error;
 
 #[derive(Debug)]
 struct AutoShutdownCluster {
-    cluster: Cluster,
+    cluster: Option<Cluster>,
 }
 
 impl AutoShutdownCluster {
     fn new(options: ClusterOptions) -> Self {
         Self {
-            cluster: Cluster::new(options).unwrap(),
+            cluster: Some(Cluster::new(options).unwrap()),
         }
     }
+
+    /// Takes ownership of the cluster, e.g. to call a method that consumes it.
+    fn into_inner(mut self) -> Cluster {
+        self.cluster.take().unwrap()
+    }
 }
 
 impl Drop for AutoShutdownCluster {
     fn drop(&mut self) {
-        let _ = self
-            .client
-            .database("admin")
-            .run_command(doc! { "shutdown": 1 }, None);
+        if let Some(ref cluster) = self.cluster {
+            let _ = cluster
+                .client
+                .database("admin")
+                .run_command(doc! { "shutdown": 1 }, None);
+        }
     }
 }
 
@@ -31,7 +44,13 @@ impl std::ops::Deref for AutoShutdownCluster {
     type Target = Cluster;
 
     fn deref(&self) -> &Self::Target {
-        &self.cluster
+        self.cluster.as_ref().unwrap()
+    }
+}
+
+impl std::ops::DerefMut for AutoShutdownCluster {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.cluster.as_mut().unwrap()
     }
 }
 
@@ -44,6 +63,118 @@ fn create_temp_dir() -> TempDir {
     TempDir::new(&Uuid::new_v4().to_string()).unwrap()
 }
 
+fn tls_options(weak_tls: bool) -> TlsOptions {
+    TlsOptions {
+        weak_tls,
+        allow_invalid_certificates: true,
+        server_allow_invalid_certificates: false,
+        ca_file_path: "ca.pem".into(),
+        server_cert_file_path: "server.pem".into(),
+        client_cert_file_path: "client.pem".into(),
+        cert_key_password: None,
+    }
+}
+
+#[test]
+fn weak_tls_client_options_only_use_ca_file() {
+    match Tls::from(tls_options(true)) {
+        Tls::Enabled(options) => {
+            assert_eq!(options.ca_file_path.as_deref(), Some("ca.pem"));
+            assert!(options.cert_key_file_path.is_none());
+        }
+        Tls::Disabled => panic!("expected TLS to be enabled"),
+    }
+}
+
+#[test]
+fn strict_tls_client_options_use_cert_key_file() {
+    match Tls::from(tls_options(false)) {
+        Tls::Enabled(options) => {
+            assert_eq!(options.cert_key_file_path.as_deref(), Some("server.pem"))
+        }
+        Tls::Disabled => panic!("expected TLS to be enabled"),
+    }
+}
+
+#[test]
+fn extra_mongod_args_can_be_inspected_and_modified() {
+    let mut cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .extra_mongod_args(vec!["--quiet".into()])
+        .build();
+
+    assert_eq!(
+        cluster_options.extra_mongod_args(),
+        &[OsString::from("--quiet")]
+    );
+
+    cluster_options
+        .extra_mongod_args_mut()
+        .push("--noscripting".into());
+
+    assert_eq!(
+        cluster_options.extra_mongod_args(),
+        &[OsString::from("--quiet"), OsString::from("--noscripting")]
+    );
+}
+
+#[test]
+fn validate_reports_every_problem() {
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: Vec::new(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .mongod_verbosity(Some(6))
+        .compressors(vec!["lz4".into()])
+        .build();
+
+    match cluster_options.validate() {
+        Err(Error::InvalidOptions { message }) => {
+            assert!(message.contains("at least one node"));
+            assert!(message.contains("verbosity"));
+            assert!(message.contains("lz4"));
+        }
+        other => panic!("expected invalid options error, got {:?}", other),
+    }
+}
+
+#[test]
+fn empty_auth_password_is_rejected() {
+    let key_dir = create_temp_dir();
+    let key_file = key_dir.path().join("keyfile");
+    std::fs::write(&key_file, "phil and ravi").unwrap();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .auth(Some(Credential {
+            username: "admin".into(),
+            password: String::new(),
+            key_file,
+            source: None,
+            roles: Vec::new(),
+        }))
+        .version_id("4.2".into())
+        .build();
+
+    match cluster_options.validate() {
+        Err(Error::InvalidOptions { message }) => {
+            assert_eq!(message, "the password for user 'admin' can't be empty")
+        }
+        other => panic!("expected invalid options error, got {:?}", other),
+    }
+}
+
+#[test]
+fn shell_quote_only_quotes_when_needed() {
+    assert_eq!(shell_quote("--set-name"), "--set-name");
+    assert_eq!(shell_quote("/tmp/my logs"), "'/tmp/my logs'");
+    assert_eq!(shell_quote("it's"), "'it'\\''s'");
+}
+
 #[test]
 fn create_and_initiate_repl_set() {
     let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
@@ -52,6 +183,7 @@ fn create_and_initiate_repl_set() {
         .topology(Topology::ReplicaSet {
             set_name: "test-repl-set".into(),
             db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
         })
         .version_id("4.2".into())
         .build();
@@ -68,3 +200,1652 @@ fn create_and_initiate_repl_set() {
 
     assert_eq!(set, "test-repl-set");
 }
+
+#[derive(Debug, Deserialize)]
+struct ReplSetConfigResponse {
+    config: ReplSetConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplSetConfig {
+    members: Vec<ReplSetConfigMember>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplSetConfigMember {
+    tags: Option<Document>,
+    hidden: bool,
+    #[serde(rename = "arbiterOnly")]
+    arbiter_only: bool,
+}
+
+#[test]
+fn create_repl_set_with_member_tags() {
+    let db_dirs: Vec<_> = (0..2).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set-tags".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .member_tags(vec![Some(doc! { "dc": "east" })])
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let response = cluster
+        .client
+        .database("admin")
+        .run_command(doc! { "replSetGetConfig" : 1 }, None)
+        .unwrap();
+
+    let ReplSetConfigResponse { config } = bson::from_document(response).unwrap();
+
+    assert_eq!(config.members[0].tags, Some(doc! { "dc": "east" }));
+    assert_eq!(
+        config.members[1].tags.as_ref().map(Document::is_empty),
+        Some(true)
+    );
+}
+
+#[test]
+fn create_repl_set_with_member_config() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set-members".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: vec![
+                ReplSetMemberConfig::default(),
+                ReplSetMemberConfig {
+                    hidden: true,
+                    ..Default::default()
+                },
+                ReplSetMemberConfig {
+                    arbiter_only: true,
+                    ..Default::default()
+                },
+            ],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let response = cluster
+        .client
+        .database("admin")
+        .run_command(doc! { "replSetGetConfig" : 1 }, None)
+        .unwrap();
+
+    let ReplSetConfigResponse { config } = bson::from_document(response).unwrap();
+
+    assert!(!config.members[0].hidden && !config.members[0].arbiter_only);
+    assert!(config.members[1].hidden);
+    assert!(config.members[2].arbiter_only);
+}
+
+/// The PIDs of the running processes whose command line mentions any of the given directories.
+#[cfg(target_os = "linux")]
+fn processes_using(dirs: &[TempDir]) -> Vec<u32> {
+    std::fs::read_dir("/proc")
+        .unwrap()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            let stat = std::fs::read_to_string(entry.path().join("stat")).ok()?;
+            let cmdline = std::fs::read(entry.path().join("cmdline")).ok()?;
+            let cmdline = String::from_utf8_lossy(&cmdline);
+
+            // Killed processes that haven't been reaped yet aren't running anymore.
+            let state = stat.rsplit(") ").next()?.chars().next()?;
+
+            if state != 'Z'
+                && dirs
+                    .iter()
+                    .any(|dir| cmdline.contains(dir.path().to_str().unwrap()))
+            {
+                Some(pid)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn failed_startup_kills_started_processes() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    // Occupying the port of the last member makes it fail to start after the others have.
+    let _listener = std::net::TcpListener::bind(("127.0.0.1", 28102)).unwrap();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set-failure".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .start_port(28100)
+        .version_id("4.2".into())
+        .build();
+
+    match Cluster::new(cluster_options) {
+        Err(Error::NodeStartupFailed { port, .. }) => assert_eq!(port, 28102),
+        other => panic!(
+            "expected the last member to fail to start, but got {:?}",
+            other
+        ),
+    }
+
+    for port in 28100..28102 {
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    assert_eq!(processes_using(&db_dirs), Vec::<u32>::new());
+}
+
+#[derive(Debug, Deserialize)]
+struct CmdLineOptsResponse {
+    parsed: Document,
+}
+
+#[test]
+fn create_sharded_cluster_with_custom_config_set_name() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .config_set_name(Some("test-config-set".into()))
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let response = cluster
+        .client
+        .database("admin")
+        .run_command(doc! { "getCmdLineOpts" : 1 }, None)
+        .unwrap();
+
+    let CmdLineOptsResponse { parsed } = bson::from_document(response).unwrap();
+    let config_db = parsed
+        .get_document("sharding")
+        .unwrap()
+        .get_str("configDB")
+        .unwrap();
+
+    assert!(config_db.starts_with("test-config-set/"));
+}
+
+#[test]
+fn mongos_configdb_lists_every_config_server_member() {
+    let config_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+    let shard_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .build();
+
+    let mut assembler = ShardedClusterAssembler::new(cluster_options).unwrap();
+    assembler
+        .start_config_replset(
+            "test-config-set",
+            config_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+        )
+        .unwrap();
+    let mongos_port = assembler.start_mongos_router("test-config-set").unwrap();
+    assembler
+        .add_shard(mongos_port, vec![shard_dir.path().to_path_buf()])
+        .unwrap();
+
+    let cluster = AutoShutdownCluster {
+        cluster: Some(assembler.finish().unwrap()),
+    };
+
+    let response = cluster
+        .client
+        .database("admin")
+        .run_command(doc! { "getCmdLineOpts" : 1 }, None)
+        .unwrap();
+
+    let CmdLineOptsResponse { parsed } = bson::from_document(response).unwrap();
+    let config_db = parsed
+        .get_document("sharding")
+        .unwrap()
+        .get_str("configDB")
+        .unwrap();
+
+    let (set_name, hosts) = config_db.split_once('/').unwrap();
+
+    assert_eq!(set_name, "test-config-set");
+    assert_eq!(hosts.split(',').count(), 3);
+}
+
+#[test]
+fn assemble_cluster_with_mixed_shards() {
+    let config_dir = create_temp_dir();
+    let singleton_dir = create_temp_dir();
+    let replset_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .build();
+
+    let mut assembler = ShardedClusterAssembler::new(cluster_options).unwrap();
+    assembler
+        .start_config_replset("test-config-set", vec![config_dir.path().to_path_buf()])
+        .unwrap();
+    let mongos_port = assembler.start_mongos_router("test-config-set").unwrap();
+    assembler
+        .add_shard(mongos_port, vec![singleton_dir.path().to_path_buf()])
+        .unwrap();
+    assembler
+        .add_shard(
+            mongos_port,
+            replset_dirs
+                .iter()
+                .map(|t| t.path().to_path_buf())
+                .collect(),
+        )
+        .unwrap();
+
+    let cluster = AutoShutdownCluster {
+        cluster: Some(assembler.finish().unwrap()),
+    };
+
+    match cluster.topology() {
+        Topology::Sharded {
+            num_mongos,
+            shard_db_paths,
+            config_db_paths,
+        } => {
+            assert_eq!(num_mongos.get(), 1);
+            assert_eq!(
+                shard_db_paths.iter().map(Vec::len).collect::<Vec<_>>(),
+                vec![1, 3]
+            );
+            assert_eq!(config_db_paths.len(), 1);
+        }
+        other => panic!("expected a sharded topology, got {:?}", other),
+    }
+
+    let response = cluster
+        .client
+        .database("admin")
+        .run_command(doc! { "listShards": 1 }, None)
+        .unwrap();
+    assert_eq!(response.get_array("shards").unwrap().len(), 2);
+}
+
+#[test]
+fn dropping_assembler_kills_started_processes() {
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .start_port(28200)
+        .version_id("4.2".into())
+        .build();
+
+    let mut assembler = ShardedClusterAssembler::new(cluster_options).unwrap();
+    assembler
+        .start_config_replset("test-config-set", vec![config_dir.path().to_path_buf()])
+        .unwrap();
+    let mongos_port = assembler.start_mongos_router("test-config-set").unwrap();
+
+    // Adding a shard without any data directories fails partway through assembly.
+    assert!(assembler.add_shard(mongos_port, Vec::new()).is_err());
+    drop(assembler);
+
+    for port in &[28200, mongos_port] {
+        assert!(TcpStream::connect(("127.0.0.1", *port)).is_err());
+    }
+}
+
+#[test]
+fn assembler_respects_startup_timeout() {
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .startup_timeout(Some(Duration::from_millis(1)))
+        .build();
+
+    let mut assembler = ShardedClusterAssembler::new(cluster_options).unwrap();
+
+    assert!(matches!(
+        assembler.start_config_replset("test-config-set", vec![config_dir.path().to_path_buf()]),
+        Err(Error::Timeout { .. })
+    ));
+}
+
+#[test]
+fn assembler_enforces_max_nodes_on_assembled_processes() {
+    // The placeholder topology would need far more processes than allowed, but it's ignored.
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "ignored".into(),
+            db_paths: vec![PathBuf::new(); 20],
+            members: Vec::new(),
+        })
+        .max_nodes(Some(2))
+        .version_id("4.2".into())
+        .build();
+
+    let mut assembler = ShardedClusterAssembler::new(cluster_options).unwrap();
+    let config_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    match assembler.start_config_replset(
+        "test-config-set",
+        config_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+    ) {
+        Err(Error::InvalidOptions { message }) => assert!(message.contains("maximum of 2")),
+        other => panic!("expected the process limit to be enforced, got {:?}", other),
+    }
+}
+
+#[test]
+fn create_sharded_cluster_with_multi_node_config_server() {
+    let shard_dir = create_temp_dir();
+    let config_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: config_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    assert_eq!(
+        cluster
+            .nodes()
+            .iter()
+            .filter(|node| node.config_server)
+            .count(),
+        3
+    );
+    assert!(cluster.reproduce_command().contains("--config-nodes 3"));
+}
+
+#[test]
+fn one_node_replset_shard_is_reproduced_as_replset() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .one_node_replset_shards(true)
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    assert!(cluster
+        .reproduce_command()
+        .contains("--shard-type replset --nodes-per-shard 1"));
+}
+
+#[test]
+fn sharded_cluster_reports_router_and_config_server_addresses() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(2).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let mongos_addresses = cluster.mongos_addresses();
+    assert_eq!(mongos_addresses.len(), 2);
+    assert_eq!(mongos_addresses, cluster.client_options().hosts);
+
+    assert_eq!(cluster.config_server_addresses().len(), 1);
+}
+
+#[test]
+fn sharded_cluster_shuts_down_routers_before_config_servers() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(2).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let mut cluster = Cluster::new(cluster_options).unwrap();
+
+    let mongos_ports: Vec<_> = cluster
+        .mongos_addresses()
+        .into_iter()
+        .filter_map(|address| address.port)
+        .collect();
+    let config_ports: Vec<_> = cluster
+        .config_server_addresses()
+        .into_iter()
+        .filter_map(|address| address.port)
+        .collect();
+
+    let order: Vec<_> = cluster
+        .processes_in_shutdown_order()
+        .into_iter()
+        .map(|(port, _)| port)
+        .collect();
+    let position = |port: &u16| order.iter().position(|p| p == port).unwrap();
+
+    let last_router = mongos_ports.iter().map(position).max().unwrap();
+    let first_config_server = config_ports.iter().map(position).min().unwrap();
+    assert!(last_router < first_config_server);
+
+    cluster.shutdown().unwrap();
+
+    for port in mongos_ports.into_iter().chain(config_ports) {
+        assert!(TcpStream::connect(("localhost", port)).is_err());
+    }
+}
+
+#[test]
+fn sharded_cluster_reports_shard_replica_sets() {
+    let shard_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![shard_dirs.iter().map(|t| t.path().to_path_buf()).collect()],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    let shards = cluster.shard_replica_sets();
+
+    assert_eq!(shards.len(), 1);
+    assert_eq!(shards["phil-replset-shard-0"].len(), 3);
+}
+
+#[test]
+fn sharded_cluster_uses_shard_name_prefix() {
+    let shard_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![shard_dirs.iter().map(|t| t.path().to_path_buf()).collect()],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .shard_name_prefix(Some("other-cluster".into()))
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    let shards = cluster.shard_replica_sets();
+
+    assert_eq!(shards.len(), 1);
+    assert_eq!(shards["other-cluster-0"].len(), 3);
+}
+
+#[test]
+fn kill_and_restart_mongos() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(2).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let mut cluster = Cluster::new(cluster_options).unwrap();
+    let port = cluster.mongos_addresses()[0].port.unwrap();
+
+    cluster.kill_mongos(port).unwrap();
+    assert!(TcpStream::connect(("localhost", port)).is_err());
+
+    cluster.restart_mongos(port).unwrap();
+
+    let restarted = (0..40).any(|_| {
+        std::thread::sleep(Duration::from_millis(250));
+        TcpStream::connect(("localhost", port)).is_ok()
+    });
+    assert!(restarted);
+
+    assert!(matches!(
+        cluster.kill_mongos(1),
+        Err(Error::NoProcess { port: 1 })
+    ));
+
+    cluster.shutdown().unwrap();
+}
+
+#[test]
+fn restarted_mongos_keeps_writing_its_log() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+    let log_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(2).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .log_dir(Some(log_dir.path().to_path_buf()))
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    let port = cluster.mongos_addresses()[1].port.unwrap();
+    let log_path =
+        PathBuf::from(arg_value(&cluster.router_mut(port).unwrap().args, "--logpath").unwrap());
+
+    cluster.kill_mongos(port).unwrap();
+    let len_before_restart = std::fs::metadata(&log_path).unwrap().len();
+
+    cluster.restart_mongos(port).unwrap();
+
+    let restarted = (0..40).any(|_| {
+        std::thread::sleep(Duration::from_millis(250));
+        TcpStream::connect(("localhost", port)).is_ok()
+    });
+    assert!(restarted);
+    assert!(std::fs::metadata(&log_path).unwrap().len() > len_before_restart);
+}
+
+#[test]
+fn initiate_repl_set_started_without_initiating() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .no_initiate(true)
+        .build();
+
+    let mut cluster = Cluster::new(cluster_options).unwrap();
+
+    cluster.initiate_replset().unwrap();
+    assert!(cluster.initiate_replset().is_err());
+
+    let response = cluster
+        .client
+        .database("admin")
+        .run_command(doc! { "replSetGetStatus" : 1 }, None)
+        .unwrap();
+
+    let ReplSetStatus { set } = bson::from_document(response).unwrap();
+    assert_eq!(set, "test-repl-set");
+
+    cluster.shutdown().unwrap();
+}
+
+#[test]
+fn reconfigure_repl_set_member_votes() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let get_config = || {
+        cluster
+            .client
+            .database("admin")
+            .run_command(doc! { "replSetGetConfig" : 1 }, None)
+            .unwrap()
+            .get_document("config")
+            .unwrap()
+            .clone()
+    };
+
+    let mut config = get_config();
+    let mut members = config.get_array("members").unwrap().clone();
+
+    if let Some(Bson::Document(member)) = members.last_mut() {
+        member.insert("priority", 0);
+        member.insert("votes", 0);
+    }
+
+    config.insert("members", members);
+    cluster.reconfigure_replset(config, false).unwrap();
+
+    let config = get_config();
+    let members = config.get_array("members").unwrap();
+
+    match members.last() {
+        Some(Bson::Document(member)) => assert_eq!(member.get_i32("votes").unwrap(), 0),
+        other => panic!("expected member document, got {:?}", other),
+    }
+}
+
+#[test]
+fn collect_logs_copies_only_server_logs_by_role() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+    let log_dir = create_temp_dir();
+    let dest_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .log_dir(Some(log_dir.path().to_path_buf()))
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    // Logs from anything other than the cluster's own processes shouldn't be collected.
+    std::fs::write(log_dir.path().join("other.log"), "").unwrap();
+
+    cluster.collect_logs(dest_dir.path()).unwrap();
+
+    let mut names: Vec<_> = std::fs::read_dir(dest_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+
+    assert_eq!(names, vec!["config.log", "mongos0.log", "shard0-node0.log"]);
+}
+
+#[test]
+fn suppressed_startup_warnings_are_logged_to_removed_temp_dir() {
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .suppress_startup_warnings(true)
+        .build();
+
+    let cluster = Cluster::new(cluster_options).unwrap();
+    let log_dir = cluster.log_dir.clone().unwrap();
+    assert!(log_dir.starts_with(std::env::temp_dir()));
+
+    let log_path = PathBuf::from(arg_value(&cluster.nodes[0].args, "--logpath").unwrap());
+    assert!(log_path.starts_with(&log_dir));
+    assert!(std::fs::metadata(&log_path).unwrap().len() > 0);
+
+    cluster.shutdown().unwrap();
+    assert!(!log_dir.exists());
+}
+
+#[test]
+fn explicit_log_dir_is_kept_after_shutdown() {
+    let log_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .log_dir(Some(log_dir.path().to_path_buf()))
+        .suppress_startup_warnings(true)
+        .build();
+
+    Cluster::new(cluster_options).unwrap().shutdown().unwrap();
+    assert!(std::fs::read_dir(log_dir.path()).unwrap().next().is_some());
+}
+
+#[test]
+fn tail_logs_streams_server_log_lines() {
+    let log_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .log_dir(Some(log_dir.path().to_path_buf()))
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    let receiver = cluster.tail_logs().unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut found = false;
+
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        match receiver.recv_timeout(timeout) {
+            Ok(log_line) => {
+                if log_line.port == 27017 && log_line.line.contains("waiting for connections") {
+                    found = true;
+                    break;
+                }
+            }
+            Err(..) => break,
+        }
+    }
+
+    assert!(found);
+}
+
+#[test]
+fn restart_repl_set_with_changed_options() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+    let topology = Topology::ReplicaSet {
+        set_name: "test-repl-set".into(),
+        db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+        members: Vec::new(),
+    };
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(topology.clone())
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    cluster
+        .client
+        .database("test")
+        .collection("restart")
+        .insert_one(doc! { "x": 1 }, None)
+        .unwrap();
+
+    let new_options = ClusterOptions::builder()
+        .topology(topology)
+        .version_id("4.2".into())
+        .enable_test_commands(true)
+        .build();
+
+    let cluster = AutoShutdownCluster {
+        cluster: Some(cluster.into_inner().restart_with(new_options).unwrap()),
+    };
+
+    let count = cluster
+        .client
+        .database("test")
+        .collection("restart")
+        .count_documents(doc! {}, None)
+        .unwrap();
+
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn add_node_to_running_repl_set() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+    let new_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    cluster
+        .add_replset_node(new_dir.path().to_path_buf())
+        .unwrap();
+
+    let response = cluster
+        .client
+        .database("admin")
+        .run_command(doc! { "replSetGetConfig" : 1 }, None)
+        .unwrap();
+    let members = response
+        .get_document("config")
+        .unwrap()
+        .get_array("members")
+        .unwrap();
+
+    assert_eq!(members.len(), 4);
+}
+
+#[test]
+fn remove_node_from_running_repl_set() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+    let new_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    cluster
+        .add_replset_node(new_dir.path().to_path_buf())
+        .unwrap();
+
+    let port = cluster.nodes.last().unwrap().options.port;
+    cluster.remove_replset_node(port).unwrap();
+
+    let config = cluster.current_repl_set_config().unwrap();
+    assert_eq!(config.get_array("members").unwrap().len(), 3);
+    assert!(TcpStream::connect(("localhost", port)).is_err());
+}
+
+#[test]
+fn wait_for_replication_after_write() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    cluster
+        .client
+        .database("test")
+        .collection("test")
+        .insert_one(doc! { "x": 1 }, None)
+        .unwrap();
+
+    cluster
+        .wait_for_replication(Duration::from_secs(30))
+        .unwrap();
+}
+
+#[test]
+fn version_reports_server_build() {
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    assert!(cluster.version().unwrap().starts_with("4.2."));
+    assert!(!cluster.tls_enabled());
+    assert!(!cluster.auth_enabled());
+    assert_eq!(cluster.auth_username(), None);
+}
+
+#[test]
+fn primary_address_is_captured_during_startup() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    let primary = cluster.primary_address().cloned().unwrap();
+
+    assert!(cluster.client_options.hosts.contains(&primary));
+    assert_eq!(cluster.refresh_primary().unwrap(), Some(&primary));
+}
+
+#[test]
+fn step_down_primary_forces_an_election() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    let old_primary = cluster.primary_address().cloned().unwrap();
+
+    cluster.step_down_primary(Duration::from_secs(60)).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        match cluster.refresh_primary().unwrap() {
+            Some(primary) if *primary != old_primary => break,
+            _ if Instant::now() > deadline => panic!("no new primary was elected"),
+            _ => std::thread::sleep(Duration::from_millis(250)),
+        }
+    }
+}
+
+#[test]
+fn step_down_primary_requires_a_replica_set() {
+    let cluster = AutoShutdownCluster::new(
+        ClusterOptions::builder()
+            .topology(Topology::Single)
+            .version_id("4.2".into())
+            .build(),
+    );
+
+    assert!(matches!(
+        cluster.step_down_primary(Duration::from_secs(10)),
+        Err(Error::InvalidOptions { .. })
+    ));
+}
+
+#[test]
+fn reproduce_command_for_repl_set() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    assert_eq!(
+        cluster.reproduce_command(),
+        "phil replset 4.2 --nodes 3 --set-name test-repl-set"
+    );
+}
+
+#[test]
+fn reproduce_command_includes_launch_options() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .enable_test_commands(true)
+        .feature_compatibility_version(Some("4.2".into()))
+        .extra_mongod_args(vec![
+            "--setParameter".into(),
+            "diagnosticDataCollectionEnabled=false".into(),
+        ])
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    assert_eq!(
+        cluster.reproduce_command(),
+        "phil replset 4.2 --nodes 3 --set-name test-repl-set --fcv 4.2 --enable-test-commands -- \
+         --setParameter diagnosticDataCollectionEnabled=false"
+    );
+}
+
+#[test]
+fn repl_set_hosts_match_launched_ports() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let launched: Vec<_> = cluster
+        .nodes
+        .iter()
+        .map(|node| localhost_address(node.options.port))
+        .collect();
+
+    assert_eq!(cluster.client_options.hosts, launched);
+}
+
+#[test]
+fn single_server_uses_first_allocated_port() {
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    assert_eq!(cluster.nodes[0].options.port, 27017);
+    assert_eq!(cluster.client_options.hosts, vec![localhost_address(27017)]);
+}
+
+#[test]
+fn repl_set_ports_are_allocated_sequentially() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    let ports: Vec<_> = cluster.nodes.iter().map(|node| node.options.port).collect();
+
+    assert_eq!(ports, vec![27017, 27018, 27019]);
+}
+
+#[test]
+fn sharded_ports_start_with_the_routers() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(2).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    let mongos_ports: Vec<_> = cluster
+        .routers
+        .iter()
+        .map(|router| router.options.port)
+        .collect();
+
+    assert_eq!(mongos_ports, vec![27017, 27018]);
+    assert_eq!(cluster.config_server_addresses()[0].port, Some(27019));
+}
+
+#[test]
+fn rewrite_member_hosts_updates_config_and_addresses() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    cluster.rewrite_member_hosts("127.0.0.1").unwrap();
+
+    let hosts: Vec<_> = cluster
+        .current_repl_set_config()
+        .unwrap()
+        .get_array("members")
+        .unwrap()
+        .iter()
+        .map(|member| {
+            member
+                .as_document()
+                .unwrap()
+                .get_str("host")
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+
+    assert_eq!(
+        hosts,
+        vec!["127.0.0.1:27017", "127.0.0.1:27018", "127.0.0.1:27019"]
+    );
+    assert!(cluster
+        .client_options
+        .hosts
+        .iter()
+        .all(|host| host.hostname == "127.0.0.1"));
+    assert!(cluster.rewrite_member_hosts("127.0.0.1:27017").is_err());
+
+    // The client is rebuilt for the new hosts, so it can still write to the primary.
+    cluster
+        .client
+        .database("test")
+        .collection("test")
+        .insert_one(doc! { "x": 1 }, None)
+        .unwrap();
+}
+
+#[test]
+fn reconfig_bumps_initial_config_version() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .repl_set_config_version(Some(5))
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let config = cluster.current_repl_set_config().unwrap();
+    assert_eq!(config_version(&config), 5);
+
+    cluster.reconfigure_replset(config, false).unwrap();
+    assert_eq!(
+        config_version(&cluster.current_repl_set_config().unwrap()),
+        6
+    );
+}
+
+#[test]
+fn rejected_reconfig_returns_error() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let mut config = cluster.current_repl_set_config().unwrap();
+    config.insert("_id", "wrong-set-name");
+
+    assert!(matches!(
+        cluster.reconfigure_replset(config, false),
+        Err(Error::ReplicaSetConfigError { .. })
+    ));
+}
+
+#[test]
+fn mongos_config_refresh_sets_server_parameter() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.4".into())
+        .mongos_config_refresh_ms(Some(12345))
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let response = admin_db(&cluster.client)
+        .run_command(
+            doc! { "getParameter": 1, "findChunksOnConfigTimeoutMS": 1 },
+            None,
+        )
+        .unwrap();
+    assert_eq!(
+        response
+            .get("findChunksOnConfigTimeoutMS")
+            .and_then(Bson::as_i32),
+        Some(12345)
+    );
+}
+
+#[test]
+fn mongod_starts_with_profiling_enabled() {
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .startup_profile_level(Some(2))
+        .startup_slow_ms(Some(7))
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let response = cluster
+        .client
+        .database("test")
+        .run_command(doc! { "profile": -1 }, None)
+        .unwrap();
+    assert_eq!(response.get("was").and_then(Bson::as_i32), Some(2));
+    assert_eq!(response.get("slowms").and_then(Bson::as_i32), Some(7));
+}
+
+#[test]
+fn auth_on_sharded_cluster() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+    let key_dir = create_temp_dir();
+
+    let key_file = key_dir.path().join("keyfile");
+    std::fs::write(&key_file, "c2VjcmV0LWtleS1mb3ItdGVzdHM=").unwrap();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(&key_file, std::fs::Permissions::from_mode(0o600)).unwrap();
+    }
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(2).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .auth(Some(Credential {
+            username: "phil-user".into(),
+            password: "phil-password".into(),
+            key_file,
+            source: None,
+            roles: Vec::new(),
+        }))
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    assert!(cluster.auth_enabled());
+
+    // Each mongos should accept the user on a fresh connection.
+    for address in cluster.mongos_addresses() {
+        let options = ClientOptions::builder()
+            .hosts(vec![address])
+            .credential(cluster.client_options.credential.clone())
+            .direct_connection(true)
+            .build();
+
+        Client::with_options(options)
+            .unwrap()
+            .database("admin")
+            .run_command(doc! { "listDatabases": 1, "nameOnly": true }, None)
+            .unwrap();
+    }
+}
+
+#[test]
+fn new_with_monger_uses_provided_monger() {
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster {
+        cluster: Some(Cluster::new_with_monger(cluster_options, Monger::new().unwrap()).unwrap()),
+    };
+
+    assert_eq!(cluster.nodes.len(), 1);
+}
+
+#[test]
+fn new_with_monger_rejects_monger_home() {
+    let home = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .monger_home(Some(home.path().to_path_buf()))
+        .build();
+
+    assert!(matches!(
+        Cluster::new_with_monger(cluster_options, Monger::new().unwrap()),
+        Err(Error::InvalidOptions { .. })
+    ));
+}
+
+#[test]
+fn ping_all_reaches_every_process() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(2).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    let results = cluster.ping_all();
+
+    assert_eq!(results.len(), cluster.nodes.len() + cluster.routers.len());
+    assert!(results.iter().all(|(_, result)| result.is_ok()));
+}
+
+#[test]
+fn dropping_cluster_terminates_processes() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = Cluster::new(cluster_options).unwrap();
+    let ports: Vec<_> = cluster.nodes.iter().map(|node| node.options.port).collect();
+
+    drop(cluster);
+
+    for port in ports {
+        assert!(TcpStream::connect(("localhost", port)).is_err());
+    }
+}
+
+#[test]
+fn auto_port_skips_ports_in_use() {
+    let _listener = std::net::TcpListener::bind(("127.0.0.1", 29117)).unwrap();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .start_port(29117)
+        .auto_port(true)
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    assert_eq!(cluster.nodes[0].options.port, 29118);
+    assert!(cluster.reproduce_command().contains("--auto-port"));
+}
+
+#[test]
+fn repl_set_ports_begin_at_start_port() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .start_port(28017)
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    let ports: Vec<_> = cluster.nodes.iter().map(|node| node.options.port).collect();
+
+    assert_eq!(ports, vec![28017, 28018, 28019]);
+    assert_eq!(
+        cluster.client_options.hosts,
+        vec![
+            localhost_address(28017),
+            localhost_address(28018),
+            localhost_address(28019),
+        ]
+    );
+    assert!(cluster.reproduce_command().ends_with("--start-port 28017"));
+}
+
+#[test]
+fn start_port_must_leave_room_for_every_process() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .start_port(u16::MAX - 1)
+        .version_id("4.2".into())
+        .build();
+
+    assert!(matches!(
+        cluster_options.validate(),
+        Err(Error::InvalidOptions { .. })
+    ));
+}
+
+#[test]
+fn nodes_and_routers_describe_sharded_processes() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(2).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    let routers = cluster.routers();
+    assert_eq!(
+        routers.iter().map(|router| router.port).collect::<Vec<_>>(),
+        vec![27017, 27018]
+    );
+
+    let nodes = cluster.nodes();
+    assert_eq!(nodes.len(), 2);
+    assert_eq!(nodes.iter().filter(|node| node.config_server).count(), 1);
+    assert_eq!(nodes.iter().filter(|node| node.shard_server()).count(), 1);
+    assert!(nodes
+        .iter()
+        .all(|node| node.address == localhost_address(node.port)));
+}
+
+#[test]
+fn mongod_that_exits_immediately_is_reported() {
+    // mongod exits right away if its port is already in use.
+    let _listener = std::net::TcpListener::bind(("127.0.0.1", 29017)).unwrap();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .start_port(29017)
+        .version_id("4.2".into())
+        .build();
+
+    match Cluster::new(cluster_options) {
+        Err(Error::NodeStartupFailed { port, exit_status }) => {
+            assert_eq!(port, 29017);
+            assert!(!exit_status.success());
+        }
+        other => panic!("expected startup failure, got {:?}", other),
+    }
+}
+
+#[test]
+fn add_shard_to_running_sharded_cluster() {
+    let shard_dir = create_temp_dir();
+    let new_shard_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    cluster
+        .add_shard(
+            new_shard_dirs
+                .iter()
+                .map(|t| t.path().to_path_buf())
+                .collect(),
+        )
+        .unwrap();
+
+    let response = cluster
+        .client
+        .database("admin")
+        .run_command(doc! { "listShards": 1 }, None)
+        .unwrap();
+    assert_eq!(response.get_array("shards").unwrap().len(), 2);
+    assert_eq!(
+        cluster.shard_replica_sets()["phil-replset-shard-1"].len(),
+        3
+    );
+}
+
+#[test]
+fn add_shard_keeps_one_node_replset_shards() {
+    let shard_dir = create_temp_dir();
+    let new_shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .one_node_replset_shards(true)
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    cluster
+        .add_shard(vec![new_shard_dir.path().to_path_buf()])
+        .unwrap();
+
+    assert_eq!(
+        cluster.shard_replica_sets()["phil-replset-shard-1"].len(),
+        1
+    );
+}
+
+#[test]
+fn add_shard_requires_sharded_cluster() {
+    let new_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .build();
+
+    let mut cluster = AutoShutdownCluster::new(cluster_options);
+    assert!(matches!(
+        cluster.add_shard(vec![new_dir.path().to_path_buf()]),
+        Err(Error::InvalidOptions { .. })
+    ));
+}
+
+#[test]
+fn legacy_config_servers_are_rejected_for_modern_servers() {
+    let shard_dir = create_temp_dir();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![vec![shard_dir.path().to_path_buf()]],
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .legacy_config_servers(true)
+        .build();
+
+    assert!(matches!(
+        Cluster::new(cluster_options),
+        Err(Error::InvalidOptions { .. })
+    ));
+}
+
+#[test]
+fn wait_until_ready_sees_shards_on_every_router() {
+    let shard_dirs: Vec<_> = (0..2).map(|_| create_temp_dir()).collect();
+    let config_dir = create_temp_dir();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(2).unwrap(),
+            shard_db_paths: shard_dirs
+                .iter()
+                .map(|t| vec![t.path().to_path_buf()])
+                .collect(),
+            config_db_paths: vec![config_dir.path().to_path_buf()],
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    cluster.wait_until_ready(Duration::from_secs(30)).unwrap();
+}
+
+#[test]
+fn wait_until_ready_waits_for_repl_set_members() {
+    let db_dirs: Vec<_> = (0..3).map(|_| create_temp_dir()).collect();
+
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::ReplicaSet {
+            set_name: "test-repl-set".into(),
+            db_paths: db_dirs.iter().map(|t| t.path().to_path_buf()).collect(),
+            members: Vec::new(),
+        })
+        .version_id("4.2".into())
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+    cluster.wait_until_ready(Duration::from_secs(30)).unwrap();
+}
+
+#[test]
+fn unresolvable_advertised_host_is_only_used_in_the_uri() {
+    let cluster_options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.2".into())
+        .advertise_host(Some("phil-test.invalid".into()))
+        .build();
+
+    let cluster = AutoShutdownCluster::new(cluster_options);
+
+    assert_eq!(
+        cluster.client_options().hosts[0].hostname,
+        "phil-test.invalid"
+    );
+    assert!(cluster.version().is_ok());
+}
@@ -1,4 +1,7 @@
-use std::{collections::HashMap, fmt, ops::Deref, time::Duration};
+#[cfg(test)]
+mod test;
+
+use std::{collections::HashMap, fmt, ops::Deref, path::PathBuf, time::Duration};
 
 use mongodb::options::{
     Acknowledgment,
@@ -15,13 +18,29 @@ use mongodb::options::{
 use percent_encoding::NON_ALPHANUMERIC;
 
 #[derive(Debug)]
-pub(crate) struct ClientOptionsWrapper<'a>(pub(crate) &'a ClientOptions);
+pub(crate) struct ClientOptionsWrapper<'a> {
+    pub(crate) options: &'a ClientOptions,
+
+    // The driver version we use doesn't support load-balanced mode, so this is tracked separately
+    // from the `ClientOptions`.
+    pub(crate) load_balanced: Option<bool>,
+
+    // Likewise, the driver version we use doesn't support `maxConnecting`.
+    pub(crate) max_connecting: Option<u32>,
+
+    // When non-empty, these are rendered as the hosts rather than the TCP addresses in the
+    // `ClientOptions`.
+    pub(crate) socket_paths: &'a [PathBuf],
+
+    // When set, the URI uses the `mongodb+srv` scheme with this hostname in place of the hosts.
+    pub(crate) srv_hostname: Option<&'a str>,
+}
 
 impl<'a> Deref for ClientOptionsWrapper<'a> {
     type Target = ClientOptions;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        self.options
     }
 }
 
@@ -37,6 +56,10 @@ fn fmt_hashmap_value(fmt: &mut fmt::Formatter, value: &HashMap<String, String>)
     Ok(())
 }
 
+fn compressors_string(compressors: &[String]) -> String {
+    compressors.join(",")
+}
+
 fn acknowlegdment_as_str(acknowledgement: &Acknowledgment) -> String {
     match acknowledgement {
         Acknowledgment::Nodes(i) => format!("{}", i),
@@ -142,7 +165,10 @@ impl<'a> fmt::Display for ClientOptionsWrapper<'a> {
                 }
             };
         }
-        write!(fmt, "mongodb://")?;
+        match self.srv_hostname {
+            Some(..) => write!(fmt, "mongodb+srv://")?,
+            None => write!(fmt, "mongodb://")?,
+        }
 
         if let Some(ref credential) = self.credential {
             let has_credential = credential.username.is_some() || credential.password.is_some();
@@ -164,28 +190,55 @@ impl<'a> fmt::Display for ClientOptionsWrapper<'a> {
             }
         }
 
-        for (i, host) in self.hosts.iter().enumerate() {
-            if i != 0 {
-                write!(fmt, ",")?;
+        if let Some(hostname) = self.srv_hostname {
+            write!(fmt, "{}", hostname)?;
+        } else if self.socket_paths.is_empty() {
+            for (i, host) in self.hosts.iter().enumerate() {
+                if i != 0 {
+                    write!(fmt, ",")?;
+                }
+
+                write!(fmt, "{}", host)?;
             }
+        } else {
+            for (i, path) in self.socket_paths.iter().enumerate() {
+                if i != 0 {
+                    write!(fmt, ",")?;
+                }
 
-            write!(fmt, "{}", host)?;
+                write!(
+                    fmt,
+                    "{}",
+                    percent_encoding::utf8_percent_encode(
+                        &path.to_string_lossy(),
+                        NON_ALPHANUMERIC
+                    )
+                )?;
+            }
         }
 
         write!(fmt, "/")?;
 
-        let no_options_written = write_options!(
+        let mut no_options_written = write_options!(
             fmt,
+            "appName", { app_name };
             "authMechanism", AuthMechanism::as_str { credential } => |credential| credential.mechanism.as_ref();
             "authSource", { credential } => |credential| credential.source.as_ref();
+            "compressors", compressors_string { compressors };
             "connectTimeoutMS", Duration::as_millis { connect_timeout };
+            "directConnection", { direct_connection };
             "heartbeatFrequencyMS", Duration::as_millis { heartbeat_freq };
             "journal", { write_concern } => |concern| concern.journal.as_ref();
+            "loadBalanced", { load_balanced };
             "localThresholdMS", Duration::as_millis { local_threshold };
+            "maxConnecting", { max_connecting };
             "maxPoolSize", { max_pool_size };
+            "minPoolSize", { min_pool_size };
             "readConcernLevel", read_concern_string { read_concern };
             "readPreference", read_pref_mode { selection_criteria } => selection_criteria_as_read_pref;
             "replicaSet", { repl_set_name };
+            "retryReads", { retry_reads };
+            "retryWrites", { retry_writes };
             "tls", tls_enabled { tls } ;
             "tlsAllowInvalidCertificates", { tls } => |tls| options_from_tls(tls).and_then(|opts| opts.allow_invalid_certificates);
             "tlsCAFile", { tls } => |tls| options_from_tls(tls).and_then(|opts| opts.ca_file_path.as_ref());
@@ -193,10 +246,19 @@ impl<'a> fmt::Display for ClientOptionsWrapper<'a> {
             "serverSelectionTimeoutMS", Duration::as_millis { server_selection_timeout };
             "w",  { write_concern } => |concern| concern.w.as_ref().map(|w| acknowlegdment_as_str(w));
             "wTimeoutMS", Duration::as_millis { write_concern } => |concern| concern.w_timeout;
+            "zlibCompressionLevel", { zlib_compression };
 
             // TODO: new options
         );
 
+        // Drivers enable TLS by default for `mongodb+srv` URIs, so it needs to be explicitly
+        // disabled when the cluster doesn't use it.
+        if self.srv_hostname.is_some() && self.tls.is_none() {
+            let separator = if no_options_written { "?" } else { "&" };
+            write!(fmt, "{}tls=false", separator)?;
+            no_options_written = false;
+        }
+
         if let Some(tag_sets) = self
             .selection_criteria
             .as_ref()
@@ -0,0 +1,104 @@
+use mongodb::options::StreamAddress;
+
+use super::*;
+
+fn connection_string(options: &ClientOptions) -> String {
+    ClientOptionsWrapper {
+        options,
+        load_balanced: None,
+        max_connecting: None,
+        socket_paths: &[],
+        srv_hostname: None,
+    }
+    .to_string()
+}
+
+fn localhost() -> StreamAddress {
+    StreamAddress {
+        hostname: "localhost".into(),
+        port: Some(27017),
+    }
+}
+
+#[test]
+fn render_compressors_and_zlib_compression_level() {
+    let options = ClientOptions::builder()
+        .hosts(vec![localhost()])
+        .compressors(vec!["zlib".to_string()])
+        .zlib_compression(6)
+        .build();
+
+    let uri = connection_string(&options);
+
+    assert!(uri.contains("compressors=zlib"));
+    assert!(uri.contains("zlibCompressionLevel=6"));
+    assert!(uri.find("compressors=").unwrap() < uri.find("zlibCompressionLevel=").unwrap());
+}
+
+#[test]
+fn render_retry_writes() {
+    let options = ClientOptions::builder()
+        .hosts(vec![localhost()])
+        .retry_writes(false)
+        .build();
+
+    assert!(connection_string(&options).contains("retryWrites=false"));
+
+    let options = ClientOptions::builder().hosts(vec![localhost()]).build();
+
+    assert!(!connection_string(&options).contains("retryWrites"));
+}
+
+#[test]
+fn render_app_name_direct_connection_and_retry_reads() {
+    let options = ClientOptions::builder()
+        .hosts(vec![localhost()])
+        .app_name("my app".to_string())
+        .direct_connection(true)
+        .retry_reads(false)
+        .build();
+
+    let uri = connection_string(&options);
+
+    assert!(uri.contains("?appName=my%20app"));
+    assert!(uri.contains("directConnection=true"));
+    assert!(uri.contains("retryReads=false"));
+}
+
+#[test]
+fn render_pool_options() {
+    let options = ClientOptions::builder()
+        .hosts(vec![localhost()])
+        .max_pool_size(20)
+        .min_pool_size(5)
+        .build();
+
+    let uri = ClientOptionsWrapper {
+        options: &options,
+        load_balanced: None,
+        max_connecting: Some(3),
+        socket_paths: &[],
+        srv_hostname: None,
+    }
+    .to_string();
+
+    assert!(uri.contains("maxConnecting=3"));
+    assert!(uri.contains("maxPoolSize=20"));
+    assert!(uri.contains("minPoolSize=5"));
+}
+
+#[test]
+fn render_srv_hostname() {
+    let options = ClientOptions::builder().hosts(vec![localhost()]).build();
+
+    let uri = ClientOptionsWrapper {
+        options: &options,
+        load_balanced: None,
+        max_connecting: None,
+        socket_paths: &[],
+        srv_hostname: Some("cluster.example.com"),
+    }
+    .to_string();
+
+    assert_eq!(uri, "mongodb+srv://cluster.example.com/?tls=false");
+}
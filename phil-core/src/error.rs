@@ -5,8 +5,12 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("error when adding shard: {response}")]
-    AddShardError { response: Document },
+    #[error("error when adding shard{}: {response}", describe_origin(.set_name, .port))]
+    AddShardError {
+        response: Document,
+        set_name: Option<String>,
+        port: Option<u16>,
+    },
 
     #[error("{inner}")]
     BsonDecoder {
@@ -14,12 +18,27 @@ pub enum Error {
         inner: mongodb::bson::de::Error,
     },
 
+    #[error(
+        "mongod on port {port} exited immediately after being started with encryption at rest \
+         enabled; note that encryption at rest requires MongoDB Enterprise"
+    )]
+    EncryptionUnsupported { port: u16 },
+
+    #[error("invalid cluster options: {message}")]
+    InvalidOptions { message: String },
+
     #[error("{inner}")]
     Io {
         #[from]
         inner: std::io::Error,
     },
 
+    #[error("{inner}")]
+    Json {
+        #[from]
+        inner: serde_json::Error,
+    },
+
     #[error("{inner}")]
     Monger {
         #[from]
@@ -32,6 +51,64 @@ pub enum Error {
         inner: mongodb::error::Error,
     },
 
-    #[error("error when configuring replica set: {response}")]
-    ReplicaSetConfigError { response: Document },
+    #[error("no installed version matches '{requirement}'")]
+    NoMatchingVersion { requirement: String },
+
+    #[error("replica set '{set_name}' has no primary")]
+    NoPrimary { set_name: String },
+
+    #[error(
+        "replica set '{set_name}' did not elect a primary before timing out; last seen member \
+         states: {}",
+        .members.join(", ")
+    )]
+    NoPrimaryElected {
+        set_name: String,
+        members: Vec<String>,
+    },
+
+    #[error(
+        "the process on port {port} exited during startup ({exit_status}); check its log for \
+         details"
+    )]
+    NodeStartupFailed {
+        port: u16,
+        exit_status: std::process::ExitStatus,
+    },
+
+    #[error("no process started by phil is listening on port {port}")]
+    NoProcess { port: u16 },
+
+    #[error(
+        "error when configuring replica set{}: {response}",
+        describe_origin(.set_name, .port)
+    )]
+    ReplicaSetConfigError {
+        response: Document,
+        set_name: Option<String>,
+        port: Option<u16>,
+    },
+
+    #[error(
+        "replica set '{set_name}' from another cluster is already running on port {port}; choose \
+         a different config set name or shard name prefix"
+    )]
+    SetNameConflict { set_name: String, port: u16 },
+
+    #[error("mongod on port {port} exited after rejecting --setParameter {parameter}")]
+    SetParameterRejected { port: u16, parameter: String },
+
+    #[error("timed out while {operation}")]
+    Timeout { operation: String },
+}
+
+/// Describes which replica set or port an error came from, as a suffix for a message like "error
+/// when adding shard". This is empty if neither is known.
+pub fn describe_origin(set_name: &Option<String>, port: &Option<u16>) -> String {
+    match (set_name, port) {
+        (Some(set_name), Some(port)) => format!(" '{}' (port {})", set_name, port),
+        (Some(set_name), None) => format!(" '{}'", set_name),
+        (None, Some(port)) => format!(" on port {}", port),
+        (None, None) => String::new(),
+    }
 }
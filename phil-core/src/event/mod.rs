@@ -0,0 +1,95 @@
+#[cfg(test)]
+mod test;
+
+use std::{
+    fmt,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::error::Result;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    NodeStarted {
+        port: u16,
+        role: &'a str,
+        repl_set_name: Option<&'a str>,
+    },
+    RouterStarted {
+        port: u16,
+    },
+    ReplSetConfigured {
+        set_name: &'a str,
+    },
+    ShardAdded {
+        shard_name: &'a str,
+    },
+    Ready,
+}
+
+#[derive(Debug, Serialize)]
+struct EventRecord<'a> {
+    timestamp_ms: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cluster_name: Option<&'a str>,
+
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+/// A destination for lifecycle events, which are written to it as lines of JSON. Clones share the
+/// same underlying writer.
+#[derive(Clone)]
+pub struct EventWriter {
+    inner: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl EventWriter {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+impl fmt::Debug for EventWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EventWriter")
+    }
+}
+
+/// Writes the event as a line of JSON to the given writer.
+pub(crate) fn write_event(
+    writer: &EventWriter,
+    cluster_name: Option<&str>,
+    event: Event,
+) -> Result<()> {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default();
+
+    let json = serde_json::to_string(&EventRecord {
+        timestamp_ms,
+        cluster_name,
+        event,
+    })?;
+
+    // A writer that panicked while it was locked may have written a partial line, but later events
+    // are still worth reporting.
+    let mut writer = writer
+        .inner
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    writeln!(writer, "{}", json)?;
+    writer.flush()?;
+
+    Ok(())
+}
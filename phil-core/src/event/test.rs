@@ -0,0 +1,88 @@
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use serde_json::{json, Value};
+
+use super::*;
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn written_events(buffer: &SharedBuffer) -> Vec<Value> {
+    String::from_utf8(buffer.0.lock().unwrap().clone())
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn events_are_written_as_json_lines() {
+    let buffer = SharedBuffer::default();
+    let writer = EventWriter::new(Box::new(buffer.clone()));
+
+    write_event(
+        &writer,
+        Some("test-cluster"),
+        Event::NodeStarted {
+            port: 27017,
+            role: "shard",
+            repl_set_name: Some("phil-replset-shard-0"),
+        },
+    )
+    .unwrap();
+    write_event(&writer, None, Event::Ready).unwrap();
+
+    let mut events = written_events(&buffer);
+    assert_eq!(events.len(), 2);
+
+    for event in &mut events {
+        assert!(event["timestamp_ms"].as_u64().unwrap() > 0);
+        event.as_object_mut().unwrap().remove("timestamp_ms");
+    }
+
+    assert_eq!(
+        events[0],
+        json!({
+            "cluster_name": "test-cluster",
+            "phase": "node_started",
+            "port": 27017,
+            "role": "shard",
+            "repl_set_name": "phil-replset-shard-0",
+        })
+    );
+    assert_eq!(events[1], json!({ "phase": "ready" }));
+}
+
+#[test]
+fn clones_share_the_same_writer() {
+    let buffer = SharedBuffer::default();
+    let writer = EventWriter::new(Box::new(buffer.clone()));
+
+    write_event(&writer, None, Event::RouterStarted { port: 27018 }).unwrap();
+    write_event(
+        &writer.clone(),
+        None,
+        Event::ShardAdded {
+            shard_name: "phil-replset-shard-0",
+        },
+    )
+    .unwrap();
+
+    let events = written_events(&buffer);
+    assert_eq!(events[0]["phase"], "router_started");
+    assert_eq!(events[1]["phase"], "shard_added");
+    assert_eq!(events[1]["shard_name"], "phil-replset-shard-0");
+}
@@ -0,0 +1,3416 @@
+#[cfg(test)]
+mod test;
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::File,
+    net::{IpAddr, TcpListener, TcpStream},
+    num::NonZeroU8,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use monger_core::{LogFile, LogFileType, Monger};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::ErrorKind,
+    options::{ClientOptions, ReadConcern, ReadPreference, SelectionCriteria, StreamAddress},
+    sync::{Client, Database},
+};
+use rand::seq::IteratorRandom;
+use serde::Deserialize;
+
+use crate::{
+    cluster::{
+        Cluster,
+        ClusterOptions,
+        Credential,
+        ReplSetMemberConfig,
+        Role,
+        TlsOptions,
+        Topology,
+    },
+    error::{Error, Result},
+    event::{write_event, Event, EventWriter},
+    version::{resolve_version, ServerVersion},
+};
+
+/// The port of the first process when no other start port is given.
+pub(crate) const DEFAULT_PORT: u16 = 27017;
+
+const DEFAULT_MAX_NODES: usize = 12;
+
+// The longest to wait after spawning a process for it to either accept connections or exit.
+// Problems that are only detected once the process is running (e.g. an unsupported option) take
+// longer to cause an exit, so a longer grace period is used when they're possible. A process whose
+// port is already taken can't be seen accepting connections, so it's given even longer to exit.
+const STARTUP_GRACE_PERIOD: Duration = Duration::from_millis(200);
+const EXTENDED_STARTUP_GRACE_PERIOD: Duration = Duration::from_millis(500);
+const PORT_CONFLICT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// Servers before 4.2 report a duplicate key error rather than a dedicated error code.
+const USER_ALREADY_EXISTS_CODES: &[i32] = &[11000, 51003];
+const USER_WRITE_CONCERN_TIMEOUT_MS: i32 = 10_000;
+
+// Errors from `replSetInitiate`/`replSetReconfig` that retrying won't fix. Anything else (e.g.
+// `NodeNotFound` while the other members are still starting up) is retried until the deadline.
+const FATAL_REPL_SET_CONFIG_ERRORS: &[&str] = &[
+    "BadValue",
+    "FailedToParse",
+    "InvalidOptions",
+    "InvalidReplicaSetConfig",
+    "NewReplicaSetConfigurationIncompatible",
+    "NoSuchKey",
+    "TypeMismatch",
+    "Unauthorized",
+];
+
+const ADMIN_DB: &str = "admin";
+
+/// All of the setup commands (e.g. `replSetInitiate`, `addShard`, `createUser`) are run against
+/// the database returned by this.
+pub(crate) fn admin_db(client: &Client) -> Database {
+    client.database(ADMIN_DB)
+}
+
+pub(crate) fn localhost_address(port: u16) -> StreamAddress {
+    StreamAddress {
+        hostname: "localhost".into(),
+        port: Some(port),
+    }
+}
+
+/// The address other hosts should use to reach the process on the given port. This is only used in
+/// the replica set configs, the `addShard` and `--configdb` strings, and the emitted connection
+/// string; the advertised host may not resolve locally, so our own connections go through
+/// `local_client_options` instead.
+pub(crate) fn advertised_address(advertise_host: Option<&str>, port: u16) -> StreamAddress {
+    match advertise_host {
+        Some(hostname) => StreamAddress {
+            hostname: hostname.into(),
+            port: Some(port),
+        },
+        None => localhost_address(port),
+    }
+}
+
+/// The options to use for our own connections to the cluster described by `client_options`, whose
+/// hosts are the advertised addresses. Each host is replaced with its localhost address. The
+/// members of a replica set with an advertised host report their advertised addresses, so a
+/// localhost seed list can't be used to discover the set; the primary (or the first member, if the
+/// primary isn't known) is connected to directly instead.
+pub(crate) fn local_client_options(
+    client_options: &ClientOptions,
+    advertise_host: Option<&str>,
+    primary_port: Option<u16>,
+) -> ClientOptions {
+    let mut options = client_options.clone();
+    let direct_port = primary_port.or_else(|| options.hosts.first().and_then(|host| host.port));
+
+    match (advertise_host, direct_port) {
+        (Some(..), Some(port)) if options.repl_set_name.is_some() => {
+            options.hosts = vec![localhost_address(port)];
+            options.repl_set_name = None;
+            options.direct_connection = Some(true);
+        }
+        _ => {
+            options.hosts = options
+                .hosts
+                .iter()
+                .filter_map(|host| host.port)
+                .map(localhost_address)
+                .collect();
+        }
+    }
+
+    options
+}
+
+fn port_is_free(port: u16) -> bool {
+    TcpListener::bind(("127.0.0.1", port)).is_ok()
+}
+
+/// Polls a newly spawned process until it either accepts connections on its port or exits,
+/// returning the exit status in the latter case. A process that does neither within the grace
+/// period is assumed to still be starting up. If the port was already in use before the process
+/// was spawned, whatever is listening on it isn't the new process, so only an exit is waited for.
+fn wait_for_startup(
+    process: &mut Child,
+    port: u16,
+    port_was_free: bool,
+    grace_period: Duration,
+) -> Result<Option<ExitStatus>> {
+    let grace_period = if port_was_free {
+        grace_period
+    } else {
+        PORT_CONFLICT_GRACE_PERIOD
+    };
+    let deadline = Instant::now() + grace_period;
+
+    loop {
+        if let Some(exit_status) = process.try_wait()? {
+            return Ok(Some(exit_status));
+        }
+
+        if port_was_free && TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return Ok(None);
+        }
+
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+
+        std::thread::sleep(Duration::from_millis(25));
+    }
+}
+
+/// Whether the bind address listens on every interface rather than a specific host.
+fn binds_all_interfaces(bind_ip: &str) -> bool {
+    bind_ip == "0.0.0.0" || bind_ip == "::"
+}
+
+/// The server arguments to listen on the given address. The launcher connects to the processes
+/// over localhost, so that's bound as well.
+pub(crate) fn bind_ip_args(bind_ip: &str) -> Vec<OsString> {
+    let is_loopback = bind_ip == "localhost"
+        || matches!(bind_ip.parse::<IpAddr>(), Ok(address) if address.is_loopback());
+
+    match bind_ip {
+        "0.0.0.0" => vec!["--bind_ip_all".into()],
+        "::" => vec!["--bind_ip_all".into(), "--ipv6".into()],
+        _ if is_loopback => vec!["--bind_ip".into(), bind_ip.into()],
+        _ => vec!["--bind_ip".into(), format!("localhost,{}", bind_ip).into()],
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Node {
+    pub(crate) process: Child,
+    pub(crate) options: MongodOptions,
+    pub(crate) args: Vec<OsString>,
+}
+
+#[derive(Debug)]
+pub(crate) struct MongodOptions {
+    pub(crate) port: u16,
+    pub(crate) db_path: Option<PathBuf>,
+    pub(crate) config_server: bool,
+    pub(crate) shard_num: Option<usize>,
+    pub(crate) repl_set_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Router {
+    pub(crate) process: Child,
+    pub(crate) options: MongosOptions,
+    pub(crate) args: Vec<OsString>,
+    pub(crate) version: String,
+    /// Whether monger saves the router's logs, in which case it needs to be told again when the
+    /// router is restarted.
+    pub(crate) save_logs: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct MongosOptions {
+    pub(crate) port: u16,
+    config_db_name: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct Launcher {
+    monger: Monger,
+    topology: Topology,
+    version: String,
+    config_server_version: Option<String>,
+    shard_version: Option<String>,
+    mongos_version: Option<String>,
+    tls: Option<TlsOptions>,
+    credential: Option<Credential>,
+    nodes: Vec<Node>,
+    routers: Vec<Router>,
+    /// The port to try for the next process, or `None` once the highest port has been used.
+    next_port: Option<u16>,
+    shard_count: u8,
+    deprecated_tls_options: bool,
+    server_version: Option<ServerVersion>,
+    save_logs: bool,
+    cluster_id: String,
+    extra_mongod_args: Vec<OsString>,
+    load_balanced: bool,
+    mongos_config_refresh_ms: Option<u32>,
+    max_incoming_connections: Option<u32>,
+    mongos_max_incoming_connections: Option<u32>,
+    extra_mongos_args: Vec<OsString>,
+    member_tags: Vec<Option<Document>>,
+    startup_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    feature_compatibility_version: Option<String>,
+    default_write_concern: Option<String>,
+    default_read_concern: Option<String>,
+    encryption_key_file: Option<PathBuf>,
+    config_set_name: String,
+    shard_name_prefix: String,
+    one_node_replset_shards: bool,
+    legacy_config_servers: bool,
+    unix_socket: bool,
+    srv_hostname: Option<String>,
+    advertise_host: Option<String>,
+    bind_ip: Option<String>,
+    retry_writes: Option<bool>,
+    max_pool_size: Option<u32>,
+    min_pool_size: Option<u32>,
+    max_connecting: Option<u32>,
+    cluster_ip_source_allowlist: Vec<String>,
+    enable_test_commands: bool,
+    auto_port: bool,
+    event_writers: Vec<EventWriter>,
+    name: Option<String>,
+    require_sessions: bool,
+    disable_majority_read_concern: bool,
+    read_preference: Option<ReadPreference>,
+    compressors: Vec<String>,
+    cpu_affinity: Vec<usize>,
+    max_nodes: Option<usize>,
+    allow_large: bool,
+    next_cpu: usize,
+    log_dir: Option<PathBuf>,
+    temp_log_dir: bool,
+    journal_commit_interval_ms: Option<u32>,
+    startup_profile_level: Option<u8>,
+    startup_slow_ms: Option<u32>,
+    collection_block_compressor: Option<String>,
+    journal_compressor: Option<String>,
+    storage_engine: Option<String>,
+    wired_tiger_cache_size_gb: Option<f64>,
+    mongod_verbosity: Option<u8>,
+    no_initiate: bool,
+    wait_for_primary: bool,
+    repl_set_config_version: Option<i32>,
+    primary: Option<StreamAddress>,
+    uninitiated_repl_set_config: Option<Document>,
+}
+
+impl Launcher {
+    pub(crate) fn new(options: ClusterOptions) -> Result<Self> {
+        let monger = match options.monger_home {
+            Some(ref home) => Monger::with_home(home)?,
+            None => Monger::new()?,
+        };
+
+        Self::with_monger(options, monger)
+    }
+
+    pub(crate) fn with_monger(options: ClusterOptions, monger: Monger) -> Result<Self> {
+        // The messages `verbose` used to print are now logged at the debug level.
+        if options.verbose && log::max_level() < log::LevelFilter::Debug {
+            log::set_max_level(log::LevelFilter::Debug);
+        }
+
+        let version = resolve_version(&monger, options.version_id)?;
+        let resolve_override = |version_id: Option<String>| {
+            version_id
+                .map(|version_id| resolve_version(&monger, version_id))
+                .transpose()
+        };
+        let config_server_version = resolve_override(options.config_server_version_id)?;
+        let shard_version = resolve_override(options.shard_version_id)?;
+        let mongos_version = resolve_override(options.mongos_version_id)?;
+
+        // Newer servers still accept the `--ssl*` options, so the oldest version being used
+        // determines which options are passed to every process.
+        let server_version = std::iter::once(&version)
+            .chain(config_server_version.iter())
+            .chain(shard_version.iter())
+            .chain(mongos_version.iter())
+            .filter_map(|version| ServerVersion::parse(version))
+            .min();
+        let cluster_id: String = (0..8)
+            .map(|_| alpha_numeric().choose(&mut rand::thread_rng()).unwrap())
+            .collect();
+
+        // Sending the servers' output to log files keeps their startup warnings out of our stdout.
+        // A directory we create for this is removed when the cluster is shut down.
+        let temp_log_dir = options.log_dir.is_none() && options.suppress_startup_warnings;
+        let log_dir = match options.log_dir {
+            None if options.suppress_startup_warnings => {
+                Some(std::env::temp_dir().join(format!("phil-logs-{}", cluster_id)))
+            }
+            log_dir => log_dir,
+        };
+
+        Ok(Self {
+            monger,
+            topology: options.topology,
+            version,
+            config_server_version,
+            shard_version,
+            mongos_version,
+            tls: options.tls,
+            credential: options.auth,
+            nodes: Default::default(),
+            routers: Default::default(),
+            next_port: Some(options.start_port),
+            shard_count: 0,
+            deprecated_tls_options: options.deprecated_tls_options,
+            server_version,
+            save_logs: options.save_logs,
+            cluster_id,
+            extra_mongod_args: options.extra_mongod_args,
+            load_balanced: options.load_balanced,
+            mongos_config_refresh_ms: options.mongos_config_refresh_ms,
+            max_incoming_connections: options.max_incoming_connections,
+            mongos_max_incoming_connections: options.mongos_max_incoming_connections,
+            extra_mongos_args: options.extra_mongos_args,
+            member_tags: options.member_tags,
+            startup_timeout: options.startup_timeout,
+            deadline: None,
+            feature_compatibility_version: options.feature_compatibility_version,
+            default_write_concern: options.default_write_concern,
+            default_read_concern: options.default_read_concern,
+            encryption_key_file: options.encryption_key_file,
+            config_set_name: match (options.config_set_name, options.shard_name_prefix.as_ref()) {
+                (Some(name), _) => name,
+                (None, Some(prefix)) => format!("{}-config", prefix),
+                (None, None) => "phil-config-server".into(),
+            },
+            shard_name_prefix: options
+                .shard_name_prefix
+                .unwrap_or_else(|| "phil-replset-shard".into()),
+            one_node_replset_shards: options.one_node_replset_shards,
+            legacy_config_servers: options.legacy_config_servers,
+            unix_socket: options.unix_socket,
+            srv_hostname: options.srv_hostname,
+            // A specific bind address is advertised unless told otherwise, so that the replica
+            // set configs and connection string use the address the servers listen on.
+            advertise_host: match (options.advertise_host, options.bind_ip.as_deref()) {
+                (Some(host), _) => Some(host),
+                (None, Some(bind_ip))
+                    if !options.unix_socket
+                        && !binds_all_interfaces(bind_ip)
+                        && validate_advertise_host(bind_ip).is_ok() =>
+                {
+                    Some(bind_ip.into())
+                }
+                (None, _) => None,
+            },
+            bind_ip: options.bind_ip,
+            retry_writes: options.retry_writes,
+            max_pool_size: options.max_pool_size,
+            min_pool_size: options.min_pool_size,
+            max_connecting: options.max_connecting,
+            cluster_ip_source_allowlist: options.cluster_ip_source_allowlist,
+            enable_test_commands: options.enable_test_commands,
+            auto_port: options.auto_port,
+            event_writers: options
+                .events_file
+                .map(|path| -> Result<_> { Ok(EventWriter::new(Box::new(File::create(path)?))) })
+                .transpose()?
+                .into_iter()
+                .chain(options.events_writer)
+                .collect(),
+            name: options.name,
+            require_sessions: options.require_sessions,
+            disable_majority_read_concern: options.disable_majority_read_concern,
+            read_preference: options.read_preference,
+            compressors: options.compressors,
+            cpu_affinity: options.cpu_affinity,
+            max_nodes: options.max_nodes,
+            allow_large: options.allow_large,
+            next_cpu: 0,
+            temp_log_dir,
+            log_dir,
+            journal_commit_interval_ms: options.journal_commit_interval_ms,
+            startup_profile_level: options.startup_profile_level,
+            startup_slow_ms: options.startup_slow_ms,
+            collection_block_compressor: options.collection_block_compressor,
+            journal_compressor: options.journal_compressor,
+            storage_engine: options.storage_engine,
+            wired_tiger_cache_size_gb: options.wired_tiger_cache_size_gb,
+            mongod_verbosity: options.mongod_verbosity,
+            no_initiate: options.no_initiate,
+            wait_for_primary: options.wait_for_primary,
+            repl_set_config_version: options.repl_set_config_version,
+            primary: None,
+            uninitiated_repl_set_config: None,
+        })
+    }
+
+    fn mongod_version(&self, config_server: bool) -> &str {
+        let version = if config_server {
+            &self.config_server_version
+        } else {
+            &self.shard_version
+        };
+
+        version.as_deref().unwrap_or(&self.version)
+    }
+
+    fn mongos_version(&self) -> &str {
+        self.mongos_version.as_deref().unwrap_or(&self.version)
+    }
+
+    /// The `--tls*` options were added in 4.2, so older servers only support the `--ssl*` ones.
+    /// If the version can't be determined, it's assumed to be recent.
+    fn use_ssl_options(&self) -> bool {
+        self.deprecated_tls_options
+            || matches!(self.server_version, Some(version) if !version.at_least(4, 2))
+    }
+
+    fn next_port(&mut self) -> Result<u16> {
+        allocate_port(&mut self.next_port, self.auto_port)
+    }
+
+    fn next_shard_name(&mut self) -> String {
+        let id = self.next_shard_id();
+
+        format!("{}-{}", self.shard_name_prefix, id)
+    }
+
+    fn next_shard_id(&mut self) -> u8 {
+        let next_count = self.shard_count + 1;
+        std::mem::replace(&mut self.shard_count, next_count)
+    }
+
+    fn emit(&self, event: Event) -> Result<()> {
+        for writer in &self.event_writers {
+            write_event(writer, self.name.as_deref(), event.clone())?;
+        }
+
+        Ok(())
+    }
+
+    fn deadline_passed(&self) -> bool {
+        matches!(self.deadline, Some(deadline) if Instant::now() >= deadline)
+    }
+
+    fn check_deadline(&self, operation: &str) -> Result<()> {
+        check_deadline(self.deadline, operation)
+    }
+
+    fn run_command_until_ok(&self, db: &Database, cmd: Document, operation: &str) -> Result<()> {
+        self.run_command_until_done(db, cmd, operation, |_| false)
+    }
+
+    /// Like `run_command_until_ok`, but also stops retrying when `already_done` returns true for
+    /// an error, which allows retrying commands that fail if an earlier attempt succeeded.
+    fn run_command_until_done(
+        &self,
+        db: &Database,
+        cmd: Document,
+        operation: &str,
+        already_done: impl Fn(&mongodb::error::Error) -> bool,
+    ) -> Result<()> {
+        loop {
+            self.check_deadline(operation)?;
+
+            let response = db.run_command(cmd.clone(), None);
+
+            let response = match response {
+                Ok(response) => response,
+                Err(error) if already_done(&error) => return Ok(()),
+                Err(..) => {
+                    std::thread::sleep(Duration::from_millis(250));
+
+                    continue;
+                }
+            };
+
+            let CommandResponse { ok, .. } = mongodb::bson::from_document(response)?;
+
+            if ok == 1.0 {
+                return Ok(());
+            }
+
+            std::thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    fn repl_set_addresses(&self, repl_set_name: String) -> impl Iterator<Item = u16> + '_ {
+        self.nodes.iter().filter_map(move |node| {
+            if node.options.repl_set_name == Some(repl_set_name.clone()) {
+                Some(node.options.port)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn advertised_address(&self, port: u16) -> StreamAddress {
+        advertised_address(self.advertise_host.as_deref(), port)
+    }
+
+    /// The options for our own connections to the cluster described by `client_options`. For a
+    /// replica set with an advertised host, the primary is looked up again first, since restarting
+    /// the members (e.g. to enable auth) may have caused an election.
+    fn local_client_options(&mut self, client_options: &ClientOptions) -> Result<ClientOptions> {
+        if let (Some(..), Some(set_name)) = (&self.advertise_host, &client_options.repl_set_name) {
+            if self.wait_for_primary {
+                let hosts = self.repl_set_hosts(set_name);
+                let client = self.direct_client(hosts[0].port.unwrap_or_default())?;
+                self.primary = Some(self.wait_for_primary_election(&admin_db(&client), set_name)?);
+            }
+        }
+
+        Ok(local_client_options(
+            client_options,
+            self.advertise_host.as_deref(),
+            self.primary.as_ref().and_then(|primary| primary.port),
+        ))
+    }
+
+    fn repl_set_hosts(&self, repl_set_name: &str) -> Vec<StreamAddress> {
+        self.repl_set_addresses(repl_set_name.into())
+            .map(|port| self.advertised_address(port))
+            .collect()
+    }
+
+    fn socket_path(&self, port: u16) -> PathBuf {
+        // Processes without a data directory use the server's default socket prefix.
+        self.nodes
+            .iter()
+            .find(|node| node.options.port == port)
+            .and_then(|node| node.options.db_path.clone())
+            .unwrap_or_else(|| PathBuf::from("/tmp"))
+            .join(format!("mongodb-{}.sock", port))
+    }
+
+    /// The `phil` arguments for the options that only the launcher keeps track of, for
+    /// `Cluster::reproduce_command`. Options left at their defaults are omitted, and the extra
+    /// mongod arguments (if any) come last, after a `--`.
+    pub(crate) fn reproduce_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = Vec::new();
+
+        let mut push_value = |flag: &str, value: Option<String>| {
+            if let Some(value) = value {
+                args.extend(vec![flag.into(), value]);
+            }
+        };
+
+        push_value("--name", self.name.clone());
+        push_value(
+            "--config-server-version",
+            self.config_server_version.clone(),
+        );
+        push_value("--shard-version", self.shard_version.clone());
+        push_value("--mongos-version", self.mongos_version.clone());
+        push_value(
+            "--shard-name-prefix",
+            Some(self.shard_name_prefix.clone())
+                .filter(|prefix| prefix != "phil-replset-shard")
+                .filter(|_| matches!(self.topology, Topology::Sharded { .. })),
+        );
+        push_value(
+            "--tags",
+            Some(member_tags_arg(&self.member_tags)).filter(|tags| !tags.is_empty()),
+        );
+        push_value(
+            "--replset-config-version",
+            self.repl_set_config_version
+                .map(|version| version.to_string()),
+        );
+        push_value(
+            "--mongos-config-refresh-ms",
+            self.mongos_config_refresh_ms.map(|ms| ms.to_string()),
+        );
+        push_value(
+            "--mongos-max-incoming-connections",
+            self.mongos_max_incoming_connections
+                .map(|max| max.to_string()),
+        );
+        push_value(
+            "--encryption-key-file",
+            self.encryption_key_file
+                .as_ref()
+                .map(|path| path.display().to_string()),
+        );
+        push_value(
+            "--mongod-verbosity",
+            self.mongod_verbosity.map(|verbosity| verbosity.to_string()),
+        );
+        push_value(
+            "--cluster-ip-source-allowlist",
+            Some(self.cluster_ip_source_allowlist.join(",")).filter(|list| !list.is_empty()),
+        );
+        push_value(
+            "--journal-commit-interval-ms",
+            self.journal_commit_interval_ms.map(|ms| ms.to_string()),
+        );
+        push_value(
+            "--startup-profile-level",
+            self.startup_profile_level.map(|level| level.to_string()),
+        );
+        push_value(
+            "--startup-slow-ms",
+            self.startup_slow_ms.map(|ms| ms.to_string()),
+        );
+        push_value(
+            "--collection-block-compressor",
+            self.collection_block_compressor.clone(),
+        );
+        push_value("--journal-compressor", self.journal_compressor.clone());
+        push_value("--storage-engine", self.storage_engine.clone());
+        push_value(
+            "--wired-tiger-cache-size-gb",
+            self.wired_tiger_cache_size_gb.map(|size| size.to_string()),
+        );
+        push_value(
+            "--max-incoming-connections",
+            self.max_incoming_connections.map(|max| max.to_string()),
+        );
+        push_value(
+            "--cpu-affinity",
+            Some(
+                self.cpu_affinity
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+            .filter(|cpus| !cpus.is_empty()),
+        );
+        push_value(
+            "--read-preference",
+            self.read_preference.as_ref().map(|read_preference| {
+                match read_preference {
+                    ReadPreference::Primary => "primary",
+                    ReadPreference::PrimaryPreferred { .. } => "primaryPreferred",
+                    ReadPreference::Secondary { .. } => "secondary",
+                    ReadPreference::SecondaryPreferred { .. } => "secondaryPreferred",
+                    ReadPreference::Nearest { .. } => "nearest",
+                }
+                .into()
+            }),
+        );
+        push_value("--fcv", self.feature_compatibility_version.clone());
+        push_value(
+            "--default-write-concern",
+            self.default_write_concern.clone(),
+        );
+        push_value("--default-read-concern", self.default_read_concern.clone());
+        push_value("--max-nodes", self.max_nodes.map(|max| max.to_string()));
+        push_value(
+            "--startup-timeout",
+            match self.startup_timeout {
+                Some(timeout) if timeout == Duration::from_secs(60) => None,
+                Some(timeout) => Some(timeout.as_secs().to_string()),
+                None => Some("0".into()),
+            },
+        );
+
+        let flags = [
+            ("--encrypt-at-rest", self.encryption_key_file.is_some()),
+            ("--enable-test-commands", self.enable_test_commands),
+            ("--legacy-ssl-options", self.deprecated_tls_options),
+            ("--save-logs", self.save_logs),
+            ("--suppress-startup-warnings", self.temp_log_dir),
+            ("--require-sessions", self.require_sessions),
+            (
+                "--disable-majority-read-concern",
+                self.disable_majority_read_concern,
+            ),
+            ("--allow-large", self.allow_large),
+            ("--no-initiate", self.no_initiate),
+            ("--no-wait-for-primary", !self.wait_for_primary),
+        ];
+
+        args.extend(
+            flags
+                .iter()
+                .filter(|(_, set)| *set)
+                .map(|(flag, _)| flag.to_string()),
+        );
+
+        for arg in &self.extra_mongos_args {
+            args.extend(vec![
+                "--mongos-arg".into(),
+                arg.to_string_lossy().into_owned(),
+            ]);
+        }
+
+        if !self.extra_mongod_args.is_empty() {
+            args.push("--".into());
+            args.extend(
+                self.extra_mongod_args
+                    .iter()
+                    .map(|arg| arg.to_string_lossy().into_owned()),
+            );
+        }
+
+        args
+    }
+
+    /// A suffix for startup messages describing which security features are enabled.
+    fn security_description(&self) -> &'static str {
+        match (self.credential.is_some(), self.tls.is_some()) {
+            (true, true) => " with auth and TLS enabled",
+            (true, false) => " with auth enabled",
+            (false, true) => " with TLS enabled",
+            (false, false) => "",
+        }
+    }
+
+    /// The command line arguments for a mongod started with the given options.
+    fn mongod_args(&self, options: &MongodOptions) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec!["--port".into(), options.port.to_string().into()];
+
+        if let Some(ref bind_ip) = self.bind_ip {
+            args.extend(bind_ip_args(bind_ip));
+        }
+
+        if let Some(ref path) = options.db_path {
+            args.push("--dbpath".into());
+            args.push(path.clone().into());
+
+            if self.unix_socket {
+                args.push("--unixSocketPrefix".into());
+                args.push(path.clone().into());
+            }
+        }
+
+        if let Some(ref credential) = self.credential {
+            args.extend_from_slice(&[
+                "--auth".into(),
+                "--keyFile".into(),
+                credential.key_file.as_os_str().into(),
+            ]);
+        }
+
+        if let Some(ref set_name) = options.repl_set_name {
+            args.extend_from_slice(&["--replSet".into(), set_name.into()]);
+        }
+
+        if options.config_server {
+            args.push("--configsvr".into());
+        }
+
+        if let Some(ref tls_options) = self.tls {
+            if self.use_ssl_options() {
+                args.extend_from_slice(&[
+                    "--sslMode".into(),
+                    "requireSSL".into(),
+                    "--sslCAFile".into(),
+                    tls_options.ca_file_path.clone().into(),
+                    "--sslPEMKeyFile".into(),
+                    tls_options.server_cert_file_path.clone().into(),
+                ]);
+            } else {
+                args.extend_from_slice(&[
+                    "--tlsMode".into(),
+                    "requireTLS".into(),
+                    "--tlsCAFile".into(),
+                    tls_options.ca_file_path.clone().into(),
+                    "--tlsCertificateKeyFile".into(),
+                    tls_options.server_cert_file_path.clone().into(),
+                ]);
+            }
+
+            if let Some(ref password) = tls_options.cert_key_password {
+                args.extend_from_slice(&[
+                    if self.use_ssl_options() {
+                        "--sslPEMKeyPassword".into()
+                    } else {
+                        "--tlsCertificateKeyFilePassword".into()
+                    },
+                    password.into(),
+                ]);
+            }
+
+            if tls_options.weak_tls {
+                args.push(
+                    if self.use_ssl_options() {
+                        "--sslAllowConnectionsWithoutCertificates".into()
+                    } else {
+                        "--tlsAllowConnectionsWithoutCertificates".into()
+                    },
+                );
+            }
+
+            if tls_options.server_allow_invalid_certificates {
+                args.push(
+                    if self.use_ssl_options() {
+                        "--sslAllowInvalidCertificates".into()
+                    } else {
+                        "--tlsAllowInvalidCertificates".into()
+                    },
+                );
+            }
+        }
+
+        if options.shard_num.is_some() {
+            args.push("--shardsvr".into());
+        }
+
+        if !self.cluster_ip_source_allowlist.is_empty() {
+            args.extend_from_slice(&[
+                "--setParameter".into(),
+                format!(
+                    "clusterIpSourceAllowlist={}",
+                    self.cluster_ip_source_allowlist.join(",")
+                )
+                .into(),
+            ]);
+        }
+
+        if let Some(ref key_file) = self.encryption_key_file {
+            args.extend_from_slice(&[
+                "--enableEncryption".into(),
+                "--encryptionKeyFile".into(),
+                key_file.as_os_str().into(),
+            ]);
+        }
+
+        if self.enable_test_commands {
+            args.extend_from_slice(&["--setParameter".into(), "enableTestCommands=1".into()]);
+        }
+
+        if !self.compressors.is_empty() {
+            args.extend_from_slice(&[
+                "--networkMessageCompressors".into(),
+                self.compressors.join(",").into(),
+            ]);
+        }
+
+        if let Some(ref log_dir) = self.log_dir {
+            let role = if options.config_server {
+                "config".into()
+            } else if let Some(shard_num) = options.shard_num {
+                format!("shard{}", shard_num)
+            } else {
+                "mongod".into()
+            };
+
+            args.extend(log_path_args(log_dir, &role, options.port));
+        }
+
+        if let Some(verbosity) = self.mongod_verbosity.filter(|verbosity| *verbosity > 0) {
+            args.push(format!("-{}", "v".repeat(verbosity.into())).into());
+        }
+
+        if self.disable_majority_read_concern && !options.config_server {
+            args.extend_from_slice(&["--enableMajorityReadConcern".into(), "false".into()]);
+        }
+
+        if let Some(interval) = self.journal_commit_interval_ms {
+            args.extend_from_slice(&[
+                "--journalCommitInterval".into(),
+                interval.to_string().into(),
+            ]);
+        }
+
+        if !options.config_server {
+            if let Some(level) = self.startup_profile_level {
+                args.extend_from_slice(&["--profile".into(), level.to_string().into()]);
+            }
+
+            if let Some(slow_ms) = self.startup_slow_ms {
+                args.extend_from_slice(&["--slowms".into(), slow_ms.to_string().into()]);
+            }
+
+            if let Some(ref compressor) = self.collection_block_compressor {
+                args.extend_from_slice(&[
+                    "--wiredTigerCollectionBlockCompressor".into(),
+                    compressor.into(),
+                ]);
+            }
+
+            if let Some(ref compressor) = self.journal_compressor {
+                args.extend_from_slice(&[
+                    "--wiredTigerJournalCompressor".into(),
+                    compressor.into(),
+                ]);
+            }
+        }
+
+        if let Some(ref engine) = self.storage_engine {
+            args.extend_from_slice(&["--storageEngine".into(), engine.into()]);
+        }
+
+        if let Some(size) = self.wired_tiger_cache_size_gb {
+            args.extend_from_slice(&["--wiredTigerCacheSizeGB".into(), size.to_string().into()]);
+        }
+
+        if let Some(max_conns) = self.max_incoming_connections {
+            args.extend_from_slice(&["--maxConns".into(), max_conns.to_string().into()]);
+        }
+
+        if !self.extra_mongod_args.is_empty() {
+            args.extend_from_slice(&self.extra_mongod_args);
+        }
+
+        args
+    }
+
+    fn start_mongod(&mut self, options: MongodOptions) -> Result<Node> {
+        let args = self.mongod_args(&options);
+
+        if log::log_enabled!(log::Level::Debug) {
+            let mut message = String::from("starting");
+
+            if options.config_server {
+                message.push_str(" config server");
+            }
+
+            if options.shard_num.is_some() {
+                message.push_str(" shard server");
+            }
+
+            message.push_str(&format!(" mongod on port {}", options.port));
+
+            if let Some(ref name) = options.repl_set_name {
+                message.push_str(&format!(" in repl set '{}'", name));
+            }
+
+            debug!("{}{}...", message, self.security_description());
+        }
+
+        let log_file = if self.save_logs {
+            let node_type = if options.config_server {
+                LogFileType::ConfigServer
+            } else if let Some(shard_num) = options.shard_num {
+                LogFileType::ShardNode { shard_num }
+            } else {
+                LogFileType::DataNode
+            };
+
+            Some(LogFile {
+                cluster_id: self.cluster_id.clone(),
+                port: options.port,
+                node_type,
+            })
+        } else {
+            None
+        };
+
+        let version = self.mongod_version(options.config_server).to_string();
+        let port_was_free = port_is_free(options.port);
+        let mut process = self
+            .monger
+            .start_mongod(args.clone(), &version, false, log_file)?;
+        track_process(&process);
+
+        if cfg!(target_os = "linux") && !self.cpu_affinity.is_empty() {
+            let cpu = self.cpu_affinity[self.next_cpu % self.cpu_affinity.len()];
+            self.next_cpu += 1;
+
+            debug!("pinning mongod on port {} to CPU {}...", options.port, cpu);
+
+            // monger spawns the process, so it can only be pinned after it's started. `-a` pins
+            // every thread the mongod has already started rather than just its main thread.
+            let status = Command::new("taskset")
+                .args(&["-a", "-pc", &cpu.to_string(), &process.id().to_string()])
+                .stdout(Stdio::null())
+                .status();
+
+            match status {
+                Ok(status) if !status.success() => warn!(
+                    "unable to pin mongod on port {} to CPU {} (taskset {})",
+                    options.port, cpu, status
+                ),
+                Err(error) => warn!(
+                    "unable to pin mongod on port {} to CPU {} (failed to run taskset: {})",
+                    options.port, cpu, error
+                ),
+                Ok(..) => {}
+            }
+        }
+
+        // A mongod that exits immediately (e.g. because its port is in use or its data directory
+        // is unusable) would otherwise cause us to retry connecting to it until the deadline.
+        let set_parameters = set_parameter_names(&self.extra_mongod_args);
+        let grace_period = if self.encryption_key_file.is_some() || !set_parameters.is_empty() {
+            EXTENDED_STARTUP_GRACE_PERIOD
+        } else {
+            STARTUP_GRACE_PERIOD
+        };
+
+        if let Some(exit_status) =
+            wait_for_startup(&mut process, options.port, port_was_free, grace_period)?
+        {
+            untrack_process(&process);
+
+            // The process may have exited for an unrelated reason (e.g. its port being in use), so
+            // the more specific errors are only returned when its log confirms the cause.
+            let log = arg_value(&args, "--logpath")
+                .and_then(|path| std::fs::read_to_string(path).ok())
+                .unwrap_or_default();
+
+            // Community builds of mongod don't recognize the encryption options.
+            if self.encryption_key_file.is_some() && rejects_encryption_options(&log) {
+                return Err(Error::EncryptionUnsupported { port: options.port });
+            }
+
+            if let Some(parameter) = find_rejected_set_parameter(&log) {
+                return Err(Error::SetParameterRejected {
+                    port: options.port,
+                    parameter,
+                });
+            }
+
+            return Err(Error::NodeStartupFailed {
+                port: options.port,
+                exit_status,
+            });
+        }
+
+        let role = if options.config_server {
+            "config_server"
+        } else if options.shard_num.is_some() {
+            "shard"
+        } else {
+            "data"
+        };
+
+        self.emit(Event::NodeStarted {
+            port: options.port,
+            role,
+            repl_set_name: options.repl_set_name.as_deref(),
+        })?;
+
+        let node = Node {
+            process,
+            options,
+            args,
+        };
+
+        Ok(node)
+    }
+
+    fn repl_set_config(&self, set_name: &str, config_server: bool) -> Document {
+        // Member tags and configuration only apply to the replica set topology, not to shards or
+        // config servers.
+        let (member_tags, member_configs): (&[Option<Document>], &[ReplSetMemberConfig]) =
+            match self.topology {
+                Topology::ReplicaSet { ref members, .. } => (&self.member_tags, members),
+                _ => (&[], &[]),
+            };
+
+        let hosts = self.repl_set_hosts(set_name);
+
+        // Like member tags, the config version only applies to the replica set topology.
+        let version = match self.topology {
+            Topology::ReplicaSet { .. } => self.repl_set_config_version,
+            _ => None,
+        };
+
+        let nodes: Vec<_> = hosts
+            .iter()
+            .enumerate()
+            .map(|(i, host)| {
+                let mut member = doc! {
+                    "_id": i as i32,
+                    "host": host.to_string(),
+                };
+
+                if let Some(Some(tags)) = member_tags.get(i) {
+                    member.insert("tags", tags.clone());
+                }
+
+                if let Some(member_config) = member_configs.get(i) {
+                    member_config.apply(&mut member);
+                }
+
+                Bson::Document(member)
+            })
+            .collect();
+
+        let mut config = doc! {
+            "_id": set_name,
+            "configsvr": config_server,
+            "members": nodes
+        };
+
+        if let Some(version) = version {
+            config.insert("version", version);
+        }
+
+        config
+    }
+
+    /// Builds a `replSetReconfig` command for a set that was already initiated. The server only
+    /// accepts a reconfig with a higher version than the current config, so the version is bumped
+    /// past the current one if needed.
+    fn reconfig_command(&self, db: &Database, config: &mut Document) -> Result<Document> {
+        let response = db.run_command(doc! { "replSetGetConfig": 1 }, None)?;
+        let current = response
+            .get_document("config")
+            .map(config_version)
+            .unwrap_or_default();
+
+        config.insert(
+            "version",
+            next_config_version(current, self.repl_set_config_version),
+        );
+
+        Ok(doc! {
+            "replSetReconfig": config.clone(),
+        })
+    }
+
+    /// Initiates the replica set and waits for a primary to be elected, returning its address.
+    /// `None` is returned if the launcher was configured not to wait for a primary.
+    fn configure_repl_set(
+        &self,
+        set_name: &str,
+        config_server: bool,
+        log: bool,
+    ) -> Result<Option<StreamAddress>> {
+        let hosts = self.repl_set_hosts(set_name);
+        let mut config = self.repl_set_config(set_name, config_server);
+
+        let client = self.direct_client(hosts[0].port.unwrap_or_default())?;
+        let db = admin_db(&client);
+        let mut cmd = doc! {
+            "replSetInitiate": config.clone(),
+        };
+        let mut already_initialized = false;
+
+        if log {
+            info!("configuring replica set...");
+        } else {
+            debug!("configuring replica set '{}'...", set_name);
+        }
+
+        let operation = format!("configuring replica set '{}'", set_name);
+
+        loop {
+            self.check_deadline(&operation)?;
+
+            let response = db.run_command(cmd.clone(), None);
+
+            // The driver reports command failures as errors, so they're turned back into responses
+            // to be classified below. Other errors (e.g. the node not accepting connections yet)
+            // are always retried.
+            let response = match response {
+                Ok(response) => response,
+                Err(error) => match command_error_response(&error) {
+                    Some(response) => response,
+                    None => {
+                        std::thread::sleep(Duration::from_millis(250));
+
+                        continue;
+                    }
+                },
+            };
+
+            let CommandResponse { ok, code_name } = mongodb::bson::from_document(response.clone())?;
+
+            if ok == 1.0 {
+                break;
+            }
+
+            match code_name.as_deref() {
+                Some("AlreadyInitialized") if !already_initialized => {
+                    cmd = self.reconfig_command(&db, &mut config)?;
+                    already_initialized = true;
+
+                    continue;
+                }
+                Some(code_name) if is_fatal_repl_set_config_error(code_name) => {
+                    return Err(Error::ReplicaSetConfigError {
+                        response,
+                        set_name: Some(set_name.into()),
+                        port: hosts[0].port,
+                    });
+                }
+                _ => {}
+            }
+
+            std::thread::sleep(Duration::from_millis(250));
+        }
+
+        if !self.wait_for_primary && !config_server {
+            self.emit(Event::ReplSetConfigured { set_name })?;
+
+            return Ok(None);
+        }
+
+        if log {
+            info!("waiting for primary to be elected...");
+        }
+
+        let primary = self.wait_for_primary_election(&db, set_name)?;
+        self.emit(Event::ReplSetConfigured { set_name })?;
+
+        Ok(Some(primary))
+    }
+
+    /// A client connected directly to the process on the given port over localhost.
+    fn direct_client(&self, port: u16) -> Result<Client> {
+        let options = ClientOptions::builder()
+            .hosts(vec![localhost_address(port)])
+            .tls(self.tls.clone().map(Into::into))
+            .credential(self.credential.clone().map(Into::into))
+            .direct_connection(true)
+            .build();
+
+        Ok(Client::with_options(options)?)
+    }
+
+    /// Polls the status of the replica set through `db` until it has a primary, returning the
+    /// primary's advertised address.
+    fn wait_for_primary_election(&self, db: &Database, set_name: &str) -> Result<StreamAddress> {
+        let hosts = self.repl_set_hosts(set_name);
+
+        // The member states from the most recent status check, which are reported if the set
+        // never elects a primary.
+        let mut last_seen_members = Vec::new();
+
+        loop {
+            if self.deadline_passed() {
+                return Err(Error::NoPrimaryElected {
+                    set_name: set_name.into(),
+                    members: last_seen_members,
+                });
+            }
+
+            let response = db.run_command(doc! { "replSetGetStatus": 1 }, None);
+            let response = match response {
+                Ok(response) => response,
+                Err(..) => {
+                    std::thread::sleep(Duration::from_millis(250));
+
+                    continue;
+                }
+            };
+
+            let ReplSetStatus { members } = mongodb::bson::from_document(response)?;
+            last_seen_members = member_states(&members);
+
+            let primary = members
+                .iter()
+                .find(|member| member.state_str == "PRIMARY")
+                .and_then(|member| hosts.iter().find(|host| host.to_string() == member.name));
+
+            if let Some(primary) = primary {
+                return Ok(primary.clone());
+            }
+
+            std::thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    fn start_repl_set(
+        &mut self,
+        repl_set_name: &str,
+        config_server: bool,
+        shard_num: Option<usize>,
+        db_paths: Vec<PathBuf>,
+        log: bool,
+    ) -> Result<()> {
+        if log {
+            info!("starting replica set servers...");
+        }
+
+        for db_path in db_paths {
+            let options = MongodOptions {
+                port: self.next_port()?,
+                db_path: Some(db_path),
+                config_server,
+                shard_num,
+                repl_set_name: Some(repl_set_name.into()),
+            };
+
+            let node = self.start_mongod(options)?;
+
+            self.nodes.push(node);
+        }
+
+        if self.no_initiate {
+            self.uninitiated_repl_set_config =
+                Some(self.repl_set_config(repl_set_name, config_server));
+
+            warn!("replica set '{}' has not been initiated", repl_set_name);
+
+            return Ok(());
+        }
+
+        let primary = self.configure_repl_set(repl_set_name, config_server, log)?;
+
+        if matches!(self.topology, Topology::ReplicaSet { .. }) {
+            self.primary = primary;
+        }
+
+        Ok(())
+    }
+
+    fn add_config_db(&mut self, name: &str, db_paths: Vec<PathBuf>) -> Result<()> {
+        if self.legacy_config_servers {
+            for db_path in db_paths {
+                let config_db_options = MongodOptions {
+                    port: self.next_port()?,
+                    db_path: Some(db_path),
+                    config_server: true,
+                    shard_num: None,
+                    repl_set_name: None,
+                };
+
+                let node = self.start_mongod(config_db_options)?;
+                self.nodes.push(node);
+            }
+
+            return Ok(());
+        }
+
+        let start = Instant::now();
+
+        // `start_repl_set` doesn't return until a primary has been elected, which ensures that the
+        // mongos routers aren't started until the config server is ready.
+        self.start_repl_set(name, true, None, db_paths, false)?;
+
+        debug!(
+            "config server replica set '{}' elected a primary after {:.1}s",
+            name,
+            start.elapsed().as_secs_f64()
+        );
+
+        Ok(())
+    }
+
+    /// The command line arguments for a mongos started with the given options.
+    fn mongos_args(&self, options: &MongosOptions) -> Result<Vec<OsString>> {
+        // Legacy config servers are standalone, so they're listed without a replica set name.
+        let config_db = if self.legacy_config_servers {
+            self.nodes
+                .iter()
+                .filter(|node| node.options.config_server)
+                .map(|node| self.advertised_address(node.options.port).to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        } else {
+            let config_hosts: Vec<_> = self
+                .repl_set_hosts(&options.config_db_name)
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+
+            format!("{}/{}", options.config_db_name, config_hosts.join(","))
+        };
+
+        let mut args: Vec<OsString> = vec![
+            "--port".into(),
+            options.port.to_string().into(),
+            "--configdb".into(),
+            config_db.into(),
+        ];
+
+        if let Some(ref bind_ip) = self.bind_ip {
+            args.extend(bind_ip_args(bind_ip));
+        }
+
+        // monger's default arguments are meant for mongod, but server parameters set through them
+        // still apply to mongos.
+        if let Some(default_args) = self.monger.get_default_args()? {
+            args.extend(set_parameter_args(&split_args(&default_args)));
+        }
+
+        if let Some(ref tls_options) = self.tls {
+            if self.use_ssl_options() {
+                args.extend_from_slice(&[
+                    "--sslMode".into(),
+                    "requireSSL".into(),
+                    "--sslCAFile".into(),
+                    tls_options.ca_file_path.clone().into(),
+                    "--sslPEMKeyFile".into(),
+                    tls_options.server_cert_file_path.clone().into(),
+                ]);
+            } else {
+                args.extend_from_slice(&[
+                    "--tlsMode".into(),
+                    "requireTLS".into(),
+                    "--tlsCAFile".into(),
+                    tls_options.ca_file_path.clone().into(),
+                    "--tlsCertificateKeyFile".into(),
+                    tls_options.server_cert_file_path.clone().into(),
+                ]);
+            }
+
+            if let Some(ref password) = tls_options.cert_key_password {
+                args.extend_from_slice(&[
+                    if self.use_ssl_options() {
+                        "--sslPEMKeyPassword".into()
+                    } else {
+                        "--tlsCertificateKeyFilePassword".into()
+                    },
+                    password.into(),
+                ]);
+            }
+
+            if tls_options.weak_tls {
+                args.push(
+                    if self.use_ssl_options() {
+                        "--sslAllowConnectionsWithoutCertificates".into()
+                    } else {
+                        "--tlsAllowConnectionsWithoutCertificates".into()
+                    },
+                );
+            }
+
+            if tls_options.server_allow_invalid_certificates {
+                args.push(
+                    if self.use_ssl_options() {
+                        "--sslAllowInvalidCertificates".into()
+                    } else {
+                        "--tlsAllowInvalidCertificates".into()
+                    },
+                );
+            }
+        }
+
+        if self.enable_test_commands {
+            args.extend_from_slice(&["--setParameter".into(), "enableTestCommands=1".into()]);
+        }
+
+        if let Some(ref credential) = self.credential {
+            args.extend_from_slice(&["--keyFile".into(), credential.key_file.as_os_str().into()]);
+        }
+
+        if !self.compressors.is_empty() {
+            args.extend_from_slice(&[
+                "--networkMessageCompressors".into(),
+                self.compressors.join(",").into(),
+            ]);
+        }
+
+        if let Some(ref log_dir) = self.log_dir {
+            args.extend(log_path_args(log_dir, "mongos", options.port));
+        }
+
+        if let Some(refresh_ms) = self.mongos_config_refresh_ms {
+            args.extend_from_slice(&[
+                "--setParameter".into(),
+                format!("findChunksOnConfigTimeoutMS={}", refresh_ms).into(),
+            ]);
+        }
+
+        if let Some(max_conns) = self
+            .mongos_max_incoming_connections
+            .or(self.max_incoming_connections)
+        {
+            args.extend_from_slice(&["--maxConns".into(), max_conns.to_string().into()]);
+        }
+
+        args.extend_from_slice(&self.extra_mongos_args);
+
+        Ok(args)
+    }
+
+    fn start_mongos(&self, options: MongosOptions) -> Result<Router> {
+        let args = self.mongos_args(&options)?;
+
+        debug!(
+            "starting mongos sharding router on port {}{}...",
+            options.port,
+            self.security_description()
+        );
+
+        let log_file = if self.save_logs {
+            Some(router_log_file(&self.cluster_id, options.port))
+        } else {
+            None
+        };
+
+        let version = self.mongos_version().to_string();
+        let port_was_free = port_is_free(options.port);
+        let mut process = self
+            .monger
+            .start_mongos(args.clone(), &version, false, log_file)?;
+        track_process(&process);
+
+        if let Some(exit_status) = wait_for_startup(
+            &mut process,
+            options.port,
+            port_was_free,
+            STARTUP_GRACE_PERIOD,
+        )? {
+            untrack_process(&process);
+
+            return Err(Error::NodeStartupFailed {
+                port: options.port,
+                exit_status,
+            });
+        }
+
+        self.emit(Event::RouterStarted { port: options.port })?;
+
+        let router = Router {
+            process,
+            options,
+            args,
+            version,
+            save_logs: self.save_logs,
+        };
+
+        Ok(router)
+    }
+
+    fn add_singleton_shard(
+        &mut self,
+        shard_num: usize,
+        port: u16,
+        mongos_port: u16,
+        db_path: PathBuf,
+    ) -> Result<()> {
+        let options = MongodOptions {
+            port,
+            db_path: Some(db_path),
+            config_server: false,
+            shard_num: Some(shard_num),
+            repl_set_name: None,
+        };
+
+        let node = self.start_mongod(options)?;
+        self.nodes.push(node);
+
+        let client_options = ClientOptions::builder()
+            .hosts(vec![localhost_address(mongos_port)])
+            .credential(self.credential.clone().map(Into::into))
+            .tls(self.tls.clone().map(Into::into))
+            .build();
+
+        let client = Client::with_options(client_options)?;
+
+        let name = self.next_shard_name();
+
+        debug!("adding single shard on port {} to cluster...", port);
+
+        let db = admin_db(&client);
+        let cmd = doc! {
+            "addShard": self.advertised_address(port).to_string(),
+            "name": name.clone()
+        };
+
+        self.run_add_shard(&db, cmd, None, Some(port))?;
+
+        self.emit(Event::ShardAdded { shard_name: &name })
+    }
+
+    fn add_replset_shard(
+        &mut self,
+        shard_num: usize,
+        mongos_port: u16,
+        db_paths: Vec<PathBuf>,
+    ) -> Result<()> {
+        let name = self.next_shard_name();
+        self.start_repl_set(&name, false, Some(shard_num), db_paths, false)?;
+
+        let options = ClientOptions::builder()
+            .hosts(vec![localhost_address(mongos_port)])
+            .credential(self.credential.clone().map(Into::into))
+            .tls(self.tls.clone().map(Into::into))
+            .build();
+
+        let client = Client::with_options(options)?;
+
+        let node_addresses: Vec<_> = self
+            .repl_set_hosts(&name)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        debug!(
+            "adding replica set shard with set name {} to cluster...",
+            name
+        );
+
+        let db = admin_db(&client);
+        let cmd = doc! {
+            "addShard": format!("{}/{}", name, node_addresses.join(",")),
+            "name": name.clone()
+        };
+
+        self.run_add_shard(&db, cmd, Some(&name), None)?;
+
+        self.emit(Event::ShardAdded { shard_name: &name })
+    }
+
+    /// Runs an `addShard` command until it succeeds, returning an error if the server rejects the
+    /// shard outright rather than just not being ready for it yet. Errors that don't come from the
+    /// server (e.g. the new shard not accepting connections yet) are retried.
+    fn run_add_shard(
+        &self,
+        db: &Database,
+        cmd: Document,
+        set_name: Option<&str>,
+        port: Option<u16>,
+    ) -> Result<()> {
+        let operation = format!("adding shard '{}'", cmd.get_str("name").unwrap_or_default());
+
+        loop {
+            self.check_deadline(&operation)?;
+
+            let response = match db.run_command(cmd.clone(), None) {
+                Ok(response) => response,
+                Err(error) => match command_error_response(&error) {
+                    Some(response) => response,
+                    None => {
+                        std::thread::sleep(Duration::from_millis(250));
+
+                        continue;
+                    }
+                },
+            };
+
+            let CommandResponse { ok, code_name } = mongodb::bson::from_document(response.clone())?;
+
+            if ok == 1.0 {
+                return Ok(());
+            }
+
+            if matches!(
+                code_name.as_deref(),
+                Some("IllegalOperation") | Some("InvalidOptions")
+            ) {
+                return Err(Error::AddShardError {
+                    response,
+                    set_name: set_name.map(Into::into),
+                    port,
+                });
+            }
+
+            std::thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    pub(crate) fn monger(&self) -> &Monger {
+        &self.monger
+    }
+
+    /// Runs `f` with the processes of a cluster that has already been started, so that anything
+    /// it starts is launched the same way as the rest of the cluster. The processes, including
+    /// any that `f` started, are handed back afterwards even if `f` fails.
+    pub(crate) fn with_processes<T>(
+        &mut self,
+        nodes: &mut Vec<Node>,
+        routers: &mut Vec<Router>,
+        next_port: Option<u16>,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.nodes = std::mem::take(nodes);
+        self.routers = std::mem::take(routers);
+        self.next_port = next_port;
+        self.deadline = self.startup_timeout.map(|timeout| Instant::now() + timeout);
+
+        let result = f(self);
+
+        *nodes = std::mem::take(&mut self.nodes);
+        *routers = std::mem::take(&mut self.routers);
+
+        result
+    }
+
+    pub(crate) fn add_shard(&mut self, mongos_port: u16, db_paths: Vec<PathBuf>) -> Result<()> {
+        let shard_num = self.shard_count as usize;
+
+        match db_paths.len() {
+            0 => Err(Error::InvalidOptions {
+                message: "a shard must have at least one data directory".into(),
+            }),
+            1 if !self.one_node_replset_shards => {
+                let port = self.next_port()?;
+                let db_path = db_paths.into_iter().next().unwrap();
+
+                self.add_singleton_shard(shard_num, port, mongos_port, db_path)
+            }
+            _ => self.add_replset_shard(shard_num, mongos_port, db_paths),
+        }
+    }
+
+    /// Refreshes the routing metadata of every mongos and waits until each of them reports all of
+    /// the shards that have been added.
+    fn wait_for_routers_to_see_shards(&self) -> Result<()> {
+        let expected_shards = self.shard_count as usize;
+
+        debug!("waiting for sharding routers to see all shards...");
+
+        for router in &self.routers {
+            let port = router.options.port;
+
+            debug!("refreshing mongos on port {}...", port);
+
+            let options = ClientOptions::builder()
+                .hosts(vec![localhost_address(port)])
+                .credential(self.credential.clone().map(Into::into))
+                .tls(self.tls.clone().map(Into::into))
+                .build();
+
+            let client = Client::with_options(options)?;
+            let db = admin_db(&client);
+
+            let operation = format!("waiting for mongos on port {} to see all shards", port);
+
+            self.run_command_until_ok(&db, doc! { "flushRouterConfig": 1 }, &operation)?;
+
+            loop {
+                self.check_deadline(&operation)?;
+
+                if let Ok(response) = db.run_command(doc! { "listShards": 1 }, None) {
+                    let ListShardsResponse { shards } = mongodb::bson::from_document(response)?;
+
+                    if shards.len() >= expected_shards {
+                        break;
+                    }
+                }
+
+                std::thread::sleep(Duration::from_millis(250));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn start_config_repl_set(
+        &mut self,
+        set_name: &str,
+        db_paths: Vec<PathBuf>,
+    ) -> Result<()> {
+        if db_paths.is_empty() {
+            return Err(Error::InvalidOptions {
+                message: "a config server must have at least one data directory".into(),
+            });
+        }
+
+        self.check_assembled_process_count(db_paths.len())?;
+        self.start_repl_set(set_name, true, None, db_paths, false)
+    }
+
+    /// Checks that starting the given number of additional processes keeps an assembled cluster
+    /// within the maximum number of processes.
+    pub(crate) fn check_assembled_process_count(&self, additional: usize) -> Result<()> {
+        validate_process_count(
+            self.nodes.len() + self.routers.len() + additional,
+            self.max_nodes,
+            self.allow_large,
+        )
+    }
+
+    pub(crate) fn start_router(&mut self, config_set_name: &str) -> Result<u16> {
+        if self
+            .repl_set_addresses(config_set_name.into())
+            .next()
+            .is_none()
+        {
+            return Err(Error::InvalidOptions {
+                message: format!(
+                    "no config server replica set named '{}' has been started",
+                    config_set_name
+                ),
+            });
+        }
+
+        self.check_assembled_process_count(1)?;
+
+        let options = MongosOptions {
+            port: self.next_port()?,
+            config_db_name: config_set_name.into(),
+        };
+        let port = options.port;
+
+        let router = self.start_mongos(options)?;
+        self.routers.push(router);
+
+        Ok(port)
+    }
+
+    pub(crate) fn finish_assembly(
+        &mut self,
+        credential: Option<Credential>,
+    ) -> Result<ClientOptions> {
+        let mongos_ports: Vec<_> = self
+            .routers
+            .iter()
+            .map(|router| router.options.port)
+            .collect();
+
+        let num_mongos =
+            NonZeroU8::new(mongos_ports.len() as u8).ok_or_else(|| Error::InvalidOptions {
+                message: "at least one mongos router must be started".into(),
+            })?;
+
+        let mut shard_db_paths: BTreeMap<usize, Vec<PathBuf>> = BTreeMap::new();
+        let mut config_db_paths = Vec::new();
+
+        for node in &self.nodes {
+            if let Some(ref db_path) = node.options.db_path {
+                if let Some(shard_num) = node.options.shard_num {
+                    shard_db_paths
+                        .entry(shard_num)
+                        .or_default()
+                        .push(db_path.clone());
+                } else if node.options.config_server {
+                    config_db_paths.push(db_path.clone());
+                }
+            }
+        }
+
+        self.topology = Topology::Sharded {
+            num_mongos,
+            shard_db_paths: shard_db_paths.into_values().collect(),
+            config_db_paths,
+        };
+
+        self.wait_for_routers_to_see_shards()?;
+
+        let mut client_options = self.base_client_options();
+        client_options.hosts = mongos_ports
+            .into_iter()
+            .map(|port| self.advertised_address(port))
+            .collect();
+
+        if let Some(credential) = credential {
+            self.enable_auth(credential, &mut client_options)?;
+        }
+
+        let local_options = self.local_client_options(&client_options)?;
+        self.set_feature_compatibility_version(&local_options)?;
+        self.set_default_rw_concern(&local_options)?;
+
+        Ok(client_options)
+    }
+
+    pub(crate) fn kill_all_processes(&mut self) {
+        for node in &mut self.nodes {
+            untrack_process(&node.process);
+            let _ = node.process.kill();
+            let _ = node.process.wait();
+        }
+
+        for router in &mut self.routers {
+            untrack_process(&router.process);
+            let _ = router.process.kill();
+            let _ = router.process.wait();
+        }
+    }
+
+    pub(crate) fn initialize_cluster(mut self) -> Result<Cluster> {
+        let client_options = self.start_cluster();
+        self.into_cluster(client_options)
+    }
+
+    pub(crate) fn into_cluster(mut self, client_options: Result<ClientOptions>) -> Result<Cluster> {
+        // Until ownership of the processes is transferred to the `Cluster`, we're responsible for
+        // making sure they don't outlive a failed startup.
+        let read_preference = self.read_preference.clone();
+
+        let (client_options, client) = match client_options
+            .map(|mut options| {
+                options.selection_criteria = read_preference.map(SelectionCriteria::ReadPreference);
+                options
+            })
+            .and_then(|options| {
+                let local_options = self.local_client_options(&options)?;
+                Ok((options, Client::with_options(local_options)?))
+            }) {
+            Ok(result) => result,
+            Err(error) => {
+                self.kill_all_processes();
+
+                return Err(error);
+            }
+        };
+
+        if let Err(error) = self.emit(Event::Ready) {
+            self.kill_all_processes();
+
+            return Err(error);
+        }
+
+        match self.name {
+            Some(ref name) => info!("Cluster '{}' is ready!", name),
+            None => info!("Cluster is ready!"),
+        }
+
+        let load_balanced = self.load_balanced && matches!(self.topology, Topology::Sharded { .. });
+
+        let socket_paths = if self.unix_socket {
+            client_options
+                .hosts
+                .iter()
+                .filter_map(|host| host.port)
+                .map(|port| self.socket_path(port))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let shard_version = self.mongod_version(false).to_string();
+
+        let cluster = Cluster {
+            version: self.version.clone(),
+            shard_version,
+            shard_name_prefix: self.shard_name_prefix.clone(),
+            client,
+            client_options,
+            topology: self.topology.clone(),
+            tls: self.tls.clone(),
+            auth: self.credential.clone(),
+            nodes: std::mem::take(&mut self.nodes),
+            routers: std::mem::take(&mut self.routers),
+            uninitiated_repl_set_config: self.uninitiated_repl_set_config.take(),
+            log_dir: self.log_dir.clone(),
+            temp_log_dir: self.temp_log_dir,
+            cluster_id: self.cluster_id.clone(),
+            startup_timeout: self.startup_timeout,
+            load_balanced,
+            max_connecting: self.max_connecting,
+            socket_paths,
+            srv_hostname: self.srv_hostname.clone(),
+            advertise_host: self.advertise_host.clone(),
+            bind_ip: self.bind_ip.clone(),
+            auto_port: self.auto_port,
+            primary: self.primary.clone(),
+            log_tailers: Vec::new(),
+            stop_log_tailers: Default::default(),
+            launcher: self,
+        };
+
+        Ok(cluster)
+    }
+
+    /// Validates the options and prepares for starting processes, which starts the clock on the
+    /// startup timeout. The returned credential (if any) should be passed to `enable_auth` once all
+    /// of the processes have been started.
+    pub(crate) fn prepare(&mut self) -> Result<Option<Credential>> {
+        self.deadline = self.startup_timeout.map(|timeout| Instant::now() + timeout);
+
+        let overrides_version = self.config_server_version.is_some()
+            || self.shard_version.is_some()
+            || self.mongos_version.is_some();
+
+        if overrides_version {
+            if let Some(warning) = mixed_version_warning(
+                self.mongod_version(true),
+                self.mongod_version(false),
+                self.mongos_version(),
+            ) {
+                warn!("{}", warning);
+            }
+        }
+
+        if self.legacy_config_servers
+            && matches!(self.server_version, Some(version) if version.at_least(3, 4))
+        {
+            return Err(Error::InvalidOptions {
+                message: "legacy config servers are only supported before 3.4".into(),
+            });
+        }
+
+        // With `auto_port`, ports that are in use are skipped, so there's nothing to conflict with.
+        if matches!(self.topology, Topology::Sharded { .. }) && !self.auto_port {
+            self.check_set_name_conflicts(planned_process_count(&self.topology))?;
+        }
+
+        let sets_default_rw_concern =
+            self.default_write_concern.is_some() || self.default_read_concern.is_some();
+
+        if sets_default_rw_concern
+            && matches!(self.server_version, Some(version) if !version.at_least(4, 4))
+        {
+            return Err(Error::InvalidOptions {
+                message: "cluster-wide default read/write concerns require MongoDB 4.4 or later"
+                    .into(),
+            });
+        }
+
+        if !cfg!(target_os = "linux") && !self.cpu_affinity.is_empty() {
+            warn!("CPU affinity is only supported on Linux and will be ignored");
+        }
+
+        if let Some(ref log_dir) = self.log_dir {
+            std::fs::create_dir_all(log_dir)?;
+        }
+
+        self.monger.clear_cluster_logs(&self.cluster_id)?;
+
+        if self.save_logs {
+            match self.name {
+                Some(ref name) => info!(
+                    "log files for cluster '{}' saved under cluster id '{}'",
+                    name, self.cluster_id
+                ),
+                None => info!("log files saved under cluster id '{}'", self.cluster_id),
+            }
+        }
+
+        Ok(self.credential.take())
+    }
+
+    /// Checks whether any of the ports the cluster will use already belong to a replica set with
+    /// one of the names the cluster would give its own sets. This happens when several sharded
+    /// clusters are run on the same host, and the members of the new cluster could otherwise end up
+    /// talking to the existing one.
+    fn check_set_name_conflicts(&self, num_processes: usize) -> Result<()> {
+        let config_set_name = if self.legacy_config_servers {
+            None
+        } else {
+            Some(self.config_set_name.as_str())
+        };
+
+        let first_port = match self.next_port {
+            Some(port) => port,
+            None => return Ok(()),
+        };
+
+        for port in (first_port..=u16::MAX).take(num_processes) {
+            // Nothing is listening, so there's no conflict.
+            if TcpStream::connect(("localhost", port)).is_err() {
+                continue;
+            }
+
+            let options = ClientOptions::builder()
+                .hosts(vec![localhost_address(port)])
+                .tls(self.tls.clone().map(Into::into))
+                .direct_connection(true)
+                .server_selection_timeout(Duration::from_secs(1))
+                .build();
+
+            // Whatever is on the port might not be a replica set member (or a mongod at all); in
+            // that case, starting the process on the port will fail with a clearer error.
+            let set_name = Client::with_options(options)
+                .and_then(|client| {
+                    admin_db(&client).run_command(doc! { "replSetGetStatus": 1 }, None)
+                })
+                .ok()
+                .and_then(|response| response.get_str("set").ok().map(ToString::to_string));
+
+            if let Some(set_name) = set_name {
+                if set_name_conflicts(&set_name, config_set_name, &self.shard_name_prefix) {
+                    return Err(Error::SetNameConflict { set_name, port });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn base_client_options(&self) -> ClientOptions {
+        ClientOptions::builder()
+            .tls(self.tls.clone().map(Into::into))
+            .retry_writes(self.retry_writes)
+            .max_pool_size(self.max_pool_size)
+            .min_pool_size(self.min_pool_size)
+            .compressors(
+                if self.compressors.is_empty() {
+                    None
+                } else {
+                    Some(self.compressors.clone())
+                },
+            )
+            .read_concern(
+                if self.require_sessions {
+                    Some(ReadConcern::majority())
+                } else {
+                    None
+                },
+            )
+            .build()
+    }
+
+    fn start_cluster(&mut self) -> Result<ClientOptions> {
+        let credential = self.prepare()?;
+
+        let mut client_options = self.base_client_options();
+
+        match self.topology.clone() {
+            Topology::Single => {
+                let port = self.next_port()?;
+                let options = MongodOptions {
+                    port,
+                    db_path: None,
+                    config_server: false,
+                    shard_num: None,
+                    repl_set_name: None,
+                };
+
+                info!("starting single server...");
+
+                let node = self.start_mongod(options)?;
+                self.nodes.push(node);
+
+                client_options.hosts = vec![self.advertised_address(port)];
+            }
+            Topology::ReplicaSet {
+                set_name, db_paths, ..
+            } => {
+                self.start_repl_set(&set_name, false, None, db_paths, true)?;
+
+                client_options.hosts = self.repl_set_hosts(&set_name);
+                client_options.repl_set_name = Some(set_name.into());
+            }
+            Topology::Sharded {
+                num_mongos,
+                shard_db_paths,
+                config_db_paths,
+            } => {
+                let mongos_ports = (0..num_mongos.get())
+                    .map(|_| self.next_port())
+                    .collect::<Result<Vec<_>>>()?;
+
+                info!("starting config server...");
+
+                let config_db_name = self.config_set_name.clone();
+                self.add_config_db(&config_db_name, config_db_paths)?;
+
+                info!("starting sharding routers...");
+
+                for mongos_port in &mongos_ports {
+                    let mongos_options1 = MongosOptions {
+                        port: *mongos_port,
+                        config_db_name: config_db_name.clone(),
+                    };
+
+                    let router = self.start_mongos(mongos_options1)?;
+                    self.routers.push(router);
+                }
+
+                info!("adding shards...");
+
+                for shard_db_path_set in shard_db_paths {
+                    self.add_shard(mongos_ports[0], shard_db_path_set)?;
+                }
+
+                self.wait_for_routers_to_see_shards()?;
+
+                // A load-balanced URI must contain exactly one host, so we point it at the first
+                // mongos in lieu of a real load balancer.
+                client_options.hosts = if self.load_balanced {
+                    vec![self.advertised_address(mongos_ports[0])]
+                } else {
+                    mongos_ports
+                        .into_iter()
+                        .map(|port| self.advertised_address(port))
+                        .collect()
+                };
+            }
+        };
+
+        if let Some(credential) = credential {
+            self.enable_auth(credential, &mut client_options)?;
+        }
+
+        let local_options = self.local_client_options(&client_options)?;
+        self.set_feature_compatibility_version(&local_options)?;
+        self.set_default_rw_concern(&local_options)?;
+
+        Ok(client_options)
+    }
+
+    pub(crate) fn enable_auth(
+        &mut self,
+        credential: Credential,
+        client_options: &mut ClientOptions,
+    ) -> Result<()> {
+        self.credential = Some(credential.clone());
+
+        info!("adding user...");
+
+        // For sharded clusters, the user is created through a mongos so that it's stored on the
+        // config servers and visible to the whole cluster.
+        let mut user_client_options = local_client_options(
+            client_options,
+            self.advertise_host.as_deref(),
+            self.primary.as_ref().and_then(|primary| primary.port),
+        );
+
+        if let Some(router) = self.routers.first() {
+            user_client_options.hosts = vec![localhost_address(router.options.port)];
+        }
+
+        let client = Client::with_options(user_client_options)?;
+        let db = client.database(credential.source.as_deref().unwrap_or(ADMIN_DB));
+
+        let roles: Vec<_> = if credential.roles.is_empty() {
+            vec![doc! { "role": "root", "db": ADMIN_DB }]
+        } else {
+            credential
+                .roles
+                .iter()
+                .map(|role| doc! { "role": role.role.clone(), "db": role.db.clone() })
+                .collect()
+        };
+
+        let mut cmd = doc! {
+            "createUser": credential.username.clone(),
+            "pwd": credential.password.clone(),
+            "roles": roles,
+        };
+
+        // Waiting for a majority ensures the user isn't lost if an election happens right after
+        // it's created.
+        if !matches!(self.topology, Topology::Single) {
+            cmd.insert(
+                "writeConcern",
+                doc! { "w": "majority", "wtimeout": USER_WRITE_CONCERN_TIMEOUT_MS },
+            );
+        }
+
+        // If an attempt creates the user but its response is lost, retrying will fail because the
+        // user already exists.
+        self.run_command_until_done(&db, cmd, "adding user", |error| {
+            matches!(
+                error.kind.as_ref(),
+                ErrorKind::CommandError(ref error) if USER_ALREADY_EXISTS_CODES.contains(&error.code)
+            )
+        })?;
+
+        client_options.credential = Some(credential.into());
+
+        let pre_auth_nodes = std::mem::replace(&mut self.nodes, Vec::new());
+
+        info!("restarting servers with auth enabled...");
+
+        for mut pre_auth_node in pre_auth_nodes {
+            debug!(
+                "shutting down mongod on port {}...",
+                pre_auth_node.options.port
+            );
+
+            terminate(&mut pre_auth_node.process)?;
+
+            let auth_node = self.start_mongod(pre_auth_node.options)?;
+            self.nodes.push(auth_node);
+        }
+
+        let pre_auth_routers = std::mem::replace(&mut self.routers, Vec::new());
+
+        if !pre_auth_routers.is_empty() {
+            info!("restarting sharding routers with auth enabled...");
+        }
+
+        for mut pre_auth_router in pre_auth_routers {
+            debug!(
+                "shutting down mongos on port {}...",
+                pre_auth_router.options.port
+            );
+
+            terminate(&mut pre_auth_router.process)?;
+
+            let auth_router = self.start_mongos(pre_auth_router.options)?;
+            self.routers.push(auth_router);
+        }
+
+        self.wait_for_routers_to_authenticate(&credential)?;
+
+        Ok(())
+    }
+
+    /// Waits until the user can authenticate through each of the restarted routers with a fresh
+    /// connection, which requires the routers to have reconnected to the restarted config servers.
+    fn wait_for_routers_to_authenticate(&self, credential: &Credential) -> Result<()> {
+        for router in &self.routers {
+            let port = router.options.port;
+
+            debug!(
+                "checking that the user can log in through mongos on port {}...",
+                port
+            );
+
+            let options = ClientOptions::builder()
+                .hosts(vec![localhost_address(port)])
+                .tls(self.tls.clone().map(Into::into))
+                .credential(Some(credential.clone().into()))
+                .direct_connection(true)
+                .build();
+            let client = Client::with_options(options)?;
+
+            // The driver authenticates when connecting, and `ping` doesn't require any privileges,
+            // so this works regardless of the user's roles.
+            self.run_command_until_ok(
+                &admin_db(&client),
+                doc! { "ping": 1 },
+                &format!("logging in through mongos on port {}", port),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn set_feature_compatibility_version(&self, client_options: &ClientOptions) -> Result<()> {
+        if let Some(ref fcv) = self.feature_compatibility_version {
+            info!("setting feature compatibility version to {}...", fcv);
+
+            let mut cmd = doc! { "setFeatureCompatibilityVersion": fcv.clone() };
+
+            let major = fcv
+                .split('.')
+                .next()
+                .and_then(|major| major.parse::<u32>().ok());
+
+            // Starting in 7.0, the server requires explicit confirmation of FCV changes.
+            if major >= Some(7) {
+                cmd.insert("confirm", true);
+            }
+
+            // For replica sets, the client will route the command to the primary, and for sharded
+            // clusters, the hosts are the mongos routers.
+            let client = Client::with_options(client_options.clone())?;
+            self.run_command_until_ok(
+                &admin_db(&client),
+                cmd,
+                "setting the feature compatibility version",
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn set_default_rw_concern(&self, client_options: &ClientOptions) -> Result<()> {
+        if self.default_write_concern.is_none() && self.default_read_concern.is_none() {
+            return Ok(());
+        }
+
+        info!("setting default read/write concern...");
+
+        let cmd = default_rw_concern_command(
+            self.default_write_concern.as_deref(),
+            self.default_read_concern.as_deref(),
+        );
+
+        // Like the FCV, this is routed to the primary or run through the mongos routers.
+        let client = Client::with_options(client_options.clone())?;
+        let db = admin_db(&client);
+
+        loop {
+            self.check_deadline("setting the default read/write concern")?;
+
+            match db.run_command(cmd.clone(), None) {
+                Ok(..) => return Ok(()),
+                // Unlike a connection error, the server rejecting the command (e.g. because of an
+                // invalid concern) won't change on a retry.
+                Err(error) if command_error_response(&error).is_some() => return Err(error.into()),
+                Err(..) => std::thread::sleep(Duration::from_millis(250)),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CommandResponse {
+    pub ok: f64,
+    pub code_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ListShardsResponse {
+    pub(crate) shards: Vec<Document>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReplSetStatus {
+    pub(crate) members: Vec<ReplSetMember>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReplSetMember {
+    pub(crate) name: String,
+    pub(crate) state_str: String,
+
+    // Arbiters don't report an optime.
+    #[serde(default)]
+    pub(crate) optime: Option<Bson>,
+}
+
+impl ReplSetMember {
+    /// The port from the member's `host:port` name. Unlike the host, this doesn't change when the
+    /// member hosts are rewritten, so it's what members are matched to processes by.
+    pub(crate) fn port(&self) -> Option<u16> {
+        self.name.rsplit(':').next()?.parse().ok()
+    }
+}
+
+/// Describes each member's state as `host:port (STATE)` for reporting a stuck replica set.
+fn member_states(members: &[ReplSetMember]) -> Vec<String> {
+    members
+        .iter()
+        .map(|member| format!("{} ({})", member.name, member.state_str))
+        .collect()
+}
+
+/// The version of a replica set config. Depending on the server version, this may be stored as
+/// any numeric type, so it's widened to an `i64` rather than truncated.
+pub(crate) fn config_version(config: &Document) -> i64 {
+    match config.get("version") {
+        Some(Bson::Int32(version)) => i64::from(*version),
+        Some(Bson::Int64(version)) => *version,
+        Some(Bson::Double(version)) => *version as i64,
+        _ => 0,
+    }
+}
+
+/// The version to use when reconfiguring a set whose current config has version `current`. The
+/// requested version is used if the server would accept it.
+fn next_config_version(current: i64, requested: Option<i32>) -> i64 {
+    requested
+        .map(i64::from)
+        .filter(|&requested| requested > current)
+        .unwrap_or(current + 1)
+}
+
+/// Numeric `w` values are sent as numbers, since the server treats a string `w` as a tag set name.
+fn default_rw_concern_command(w: Option<&str>, level: Option<&str>) -> Document {
+    let mut cmd = doc! { "setDefaultRWConcern": 1 };
+
+    if let Some(w) = w {
+        let w = match w.parse::<i32>() {
+            Ok(nodes) => Bson::Int32(nodes),
+            Err(..) => Bson::String(w.into()),
+        };
+
+        cmd.insert("defaultWriteConcern", doc! { "w": w });
+    }
+
+    if let Some(level) = level {
+        cmd.insert("defaultReadConcern", doc! { "level": level });
+    }
+
+    cmd
+}
+
+fn set_name_conflicts(set_name: &str, config_set_name: Option<&str>, shard_prefix: &str) -> bool {
+    config_set_name == Some(set_name)
+        || set_name
+            .strip_prefix(shard_prefix)
+            .and_then(|suffix| suffix.strip_prefix('-'))
+            .map_or(false, |id| id.parse::<u8>().is_ok())
+}
+
+fn is_fatal_repl_set_config_error(code_name: &str) -> bool {
+    FATAL_REPL_SET_CONFIG_ERRORS.contains(&code_name)
+}
+
+/// Rebuilds the server's response from a command error so that it can be reported the same way as
+/// a response with `ok: 0`.
+pub(crate) fn command_error_response(error: &mongodb::error::Error) -> Option<Document> {
+    match error.kind.as_ref() {
+        ErrorKind::CommandError(ref error) => Some(doc! {
+            "ok": 0.0,
+            "errmsg": error.message.clone(),
+            "code": error.code,
+            "codeName": error.code_name.clone(),
+        }),
+        _ => None,
+    }
+}
+
+/// Hands out the port in `next_port` and advances it, skipping ports that are already in use if
+/// `auto_port` is set. `next_port` is `None` once the highest port has been handed out.
+pub(crate) fn allocate_port(next_port: &mut Option<u16>, auto_port: bool) -> Result<u16> {
+    loop {
+        let port = next_port.ok_or_else(|| Error::InvalidOptions {
+            message: format!("ran out of ports; the highest port is {}", u16::MAX),
+        })?;
+        *next_port = port.checked_add(1);
+
+        if !auto_port || port_is_free(port) {
+            return Ok(port);
+        }
+
+        info!("skipping port {}, which is already in use", port);
+    }
+}
+
+/// Returns a timeout error for `operation` if `deadline` has passed. A deadline of `None` never
+/// passes.
+pub(crate) fn check_deadline(deadline: Option<Instant>, operation: &str) -> Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(Error::Timeout {
+            operation: operation.into(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Initiates a replica set that was started without being initiated and waits for it to elect a
+/// primary, giving up once the deadline passes. `options` should point directly at a single
+/// member.
+pub(crate) fn initiate_repl_set(
+    options: ClientOptions,
+    config: Document,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let client = Client::with_options(options)?;
+    let db = admin_db(&client);
+
+    loop {
+        check_deadline(deadline, "initiating the replica set")?;
+
+        let response = match db.run_command(doc! { "replSetInitiate": config.clone() }, None) {
+            Ok(response) => Some(response),
+            Err(error) => command_error_response(&error),
+        };
+
+        if let Some(response) = response {
+            let CommandResponse { ok, code_name } = mongodb::bson::from_document(response.clone())?;
+
+            if ok == 1.0 || code_name.as_deref() == Some("AlreadyInitialized") {
+                break;
+            }
+
+            if code_name.map_or(false, |code_name| {
+                is_fatal_repl_set_config_error(&code_name)
+            }) {
+                return Err(Error::ReplicaSetConfigError {
+                    response,
+                    set_name: config.get_str("_id").ok().map(Into::into),
+                    port: None,
+                });
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(250));
+    }
+
+    loop {
+        check_deadline(deadline, "waiting for the replica set to elect a primary")?;
+
+        if let Ok(response) = db.run_command(doc! { "replSetGetStatus": 1 }, None) {
+            let ReplSetStatus { members } = mongodb::bson::from_document(response)?;
+
+            if members.iter().any(|member| member.state_str == "PRIMARY") {
+                return Ok(());
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// Returns a copy of `args` with the value following `flag` replaced, if `flag` is present.
+pub(crate) fn replace_arg_value(args: &[OsString], flag: &str, value: OsString) -> Vec<OsString> {
+    let mut args = args.to_vec();
+
+    if let Some(index) = args.iter().position(|arg| arg == flag) {
+        if let Some(old_value) = args.get_mut(index + 1) {
+            *old_value = value;
+        }
+    }
+
+    args
+}
+
+// The PIDs of every process that's been started and not yet shut down, which allows them to be
+// cleaned up from a signal handler.
+static RUNNING_PIDS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+pub(crate) fn track_process(process: &Child) {
+    if let Ok(mut pids) = RUNNING_PIDS.lock() {
+        pids.push(process.id());
+    }
+}
+
+pub(crate) fn untrack_process(process: &Child) {
+    if let Ok(mut pids) = RUNNING_PIDS.lock() {
+        pids.retain(|pid| *pid != process.id());
+    }
+}
+
+/// Shuts down a process that this process didn't start (and so has no handle to), without waiting
+/// for it to exit. On unix, the process is sent SIGTERM so that it can shut down cleanly.
+pub(crate) fn terminate_pid(pid: u32) -> Result<()> {
+    if cfg!(unix) {
+        Command::new("kill").arg(pid.to_string()).status()?;
+    } else {
+        Command::new("taskkill")
+            .args(&["/F", "/PID", &pid.to_string()])
+            .status()?;
+    }
+
+    Ok(())
+}
+
+/// Asks every process that's been started and not yet shut down to shut down, without waiting for
+/// them to exit. This is only supported on unix.
+pub(crate) fn terminate_running_processes() {
+    let pids = match RUNNING_PIDS.lock() {
+        Ok(mut pids) => std::mem::take(&mut *pids),
+        Err(..) => return,
+    };
+
+    if cfg!(unix) && !pids.is_empty() {
+        let _ = Command::new("kill")
+            .args(pids.iter().map(ToString::to_string))
+            .status();
+    }
+}
+
+/// Shuts down the process and waits for it to exit. On unix, the process is sent SIGTERM so that it
+/// can shut down cleanly; elsewhere, it's killed outright.
+pub(crate) fn terminate(process: &mut Child) -> Result<()> {
+    untrack_process(process);
+
+    if cfg!(unix) {
+        Command::new("kill")
+            .args(&[process.id().to_string()])
+            .spawn()?
+            .wait()?;
+    } else {
+        process.kill()?;
+    }
+
+    process.wait()?;
+
+    Ok(())
+}
+
+fn set_parameter_args(args: &[OsString]) -> Vec<OsString> {
+    let mut set_parameter_args = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--setParameter" {
+            if let Some(value) = args.next() {
+                set_parameter_args.push(arg.clone());
+                set_parameter_args.push(value.clone());
+            }
+        }
+    }
+
+    set_parameter_args
+}
+
+/// The names of the server parameters set via `--setParameter <name>=<value>`.
+fn set_parameter_names(args: &[OsString]) -> Vec<String> {
+    set_parameter_args(args)
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .filter_map(|value| {
+            value
+                .to_string_lossy()
+                .splitn(2, '=')
+                .next()
+                .map(Into::into)
+        })
+        .collect()
+}
+
+pub(crate) fn arg_value<'a>(args: &'a [OsString], flag: &str) -> Option<&'a OsString> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+}
+
+/// Finds the name of the server parameter that mongod refused at startup in its log output.
+fn find_rejected_set_parameter(log: &str) -> Option<String> {
+    const PATTERNS: &[(&str, char)] = &[
+        ("Unknown --setParameter '", '\''),
+        ("Bad value for parameter \"", '"'),
+        ("Cannot use --setParameter to set \"", '"'),
+    ];
+
+    // Newer servers log JSON, which escapes the quotes around the parameter name.
+    let log = log.replace("\\\"", "\"");
+
+    PATTERNS.iter().find_map(|(prefix, terminator)| {
+        let start = log.find(prefix)? + prefix.len();
+        let end = log[start..].find(*terminator)?;
+
+        Some(log[start..start + end].to_string())
+    })
+}
+
+/// Whether mongod's log shows that it didn't recognize the encryption at rest options, which only
+/// Enterprise builds support.
+fn rejects_encryption_options(log: &str) -> bool {
+    [
+        "unrecognised option '--enableEncryption'",
+        "unrecognized option '--enableEncryption'",
+    ]
+    .iter()
+    .any(|pattern| log.contains(pattern))
+}
+
+/// Formats member tags the way `phil replset --tags` accepts them, e.g. `0:dc=east,1:dc=west`.
+fn member_tags_arg(member_tags: &[Option<Document>]) -> String {
+    member_tags
+        .iter()
+        .enumerate()
+        .filter_map(|(member, tags)| Some((member, tags.as_ref()?)))
+        .flat_map(|(member, tags)| {
+            tags.iter().map(move |(key, value)| {
+                let value = value
+                    .as_str()
+                    .map(Into::into)
+                    .unwrap_or_else(|| value.to_string());
+
+                format!("{}:{}={}", member, key, value)
+            })
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn planned_process_count(topology: &Topology) -> usize {
+    match topology {
+        Topology::Single => 1,
+        Topology::ReplicaSet { db_paths, .. } => db_paths.len(),
+        Topology::Sharded {
+            num_mongos,
+            shard_db_paths,
+            config_db_paths,
+        } => {
+            config_db_paths.len()
+                + usize::from(num_mongos.get())
+                + shard_db_paths.iter().map(Vec::len).sum::<usize>()
+        }
+    }
+}
+
+/// Splits a string of arguments on whitespace, except for whitespace inside of quotes or inside
+/// of braces/brackets (e.g. a JSON failpoint spec). Quotes are removed from the resulting
+/// arguments unless they're inside of braces/brackets.
+fn split_args(args: &str) -> Vec<OsString> {
+    let mut split = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut depth = 0usize;
+
+    for c in args.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => {
+                quote = None;
+
+                if depth > 0 {
+                    current.push(c);
+                }
+            }
+            (Some(_), c) => current.push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                in_arg = true;
+
+                if depth > 0 {
+                    current.push(c);
+                }
+            }
+            (None, c) if c.is_whitespace() && depth == 0 => {
+                if in_arg {
+                    split.push(std::mem::take(&mut current).into());
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                match c {
+                    '{' | '[' => depth += 1,
+                    '}' | ']' => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if in_arg {
+        split.push(current.into());
+    }
+
+    split
+}
+
+/// Checks the options for problems that don't require starting any processes to detect,
+/// returning a description of each one.
+pub(crate) fn option_problems(options: &ClusterOptions) -> Vec<String> {
+    collect_option_problems(options, true)
+}
+
+/// Like `option_problems`, but for a cluster that's assembled piece by piece, where the topology
+/// is ignored and the number of processes is only checked as they're started.
+pub(crate) fn assembly_option_problems(options: &ClusterOptions) -> Vec<String> {
+    collect_option_problems(options, false)
+}
+
+fn collect_option_problems(options: &ClusterOptions, check_topology: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut check = |result: Result<()>| match result {
+        Ok(()) => {}
+        Err(Error::InvalidOptions { message }) => problems.push(message),
+        Err(error) => problems.push(error.to_string()),
+    };
+
+    let invalid = |message: &str| -> Result<()> {
+        Err(Error::InvalidOptions {
+            message: message.into(),
+        })
+    };
+
+    match options.topology {
+        _ if !check_topology => {}
+        Topology::Single => {}
+        Topology::ReplicaSet {
+            ref db_paths,
+            ref members,
+            ..
+        } => {
+            if db_paths.is_empty() {
+                check(invalid("a replica set needs at least one node"));
+            }
+
+            check(validate_repl_set_members(members, db_paths.len()));
+        }
+        Topology::Sharded {
+            ref shard_db_paths,
+            ref config_db_paths,
+            ..
+        } => {
+            if shard_db_paths.is_empty() {
+                check(invalid("a sharded cluster needs at least one shard"));
+            }
+
+            if shard_db_paths.iter().any(Vec::is_empty) {
+                check(invalid("each shard needs at least one node"));
+            }
+
+            if config_db_paths.is_empty() {
+                check(invalid("the config server needs at least one node"));
+            }
+
+            // Legacy config servers are mirrored rather than replicated, which only supports one or
+            // three of them.
+            if options.legacy_config_servers && ![1, 3].contains(&config_db_paths.len()) {
+                check(invalid(
+                    "legacy config servers must have either one or three nodes",
+                ));
+            }
+        }
+    }
+
+    let is_replica_set = matches!(options.topology, Topology::ReplicaSet { .. });
+
+    if let Some(ref tls) = options.tls {
+        for path in &[
+            &tls.ca_file_path,
+            &tls.server_cert_file_path,
+            &tls.client_cert_file_path,
+        ] {
+            if !path.is_file() {
+                check(Err(Error::InvalidOptions {
+                    message: format!("TLS file '{}' does not exist", path.display()),
+                }));
+            }
+        }
+    }
+
+    if let Some(ref auth) = options.auth {
+        if !auth.key_file.is_file() {
+            check(Err(Error::InvalidOptions {
+                message: format!("key file '{}' does not exist", auth.key_file.display()),
+            }));
+        }
+
+        if auth.username.is_empty() {
+            check(invalid("the auth username can't be empty"));
+        }
+
+        let is_root = auth.roles.is_empty() || auth.roles.iter().any(Role::is_root);
+
+        if !is_root
+            && (options.feature_compatibility_version.is_some()
+                || options.default_write_concern.is_some()
+                || options.default_read_concern.is_some())
+        {
+            check(invalid(
+                "the FCV and default read/write concern can only be set when the user has the \
+                 root role",
+            ));
+        }
+
+        // The server rejects `createUser` with an empty password, but the resulting error doesn't
+        // make the cause obvious.
+        if auth.password.is_empty() {
+            check(Err(Error::InvalidOptions {
+                message: format!("the password for user '{}' can't be empty", auth.username),
+            }));
+        }
+    }
+
+    if let Some(ref key_file) = options.encryption_key_file {
+        if !key_file.is_file() {
+            check(Err(Error::InvalidOptions {
+                message: format!(
+                    "encryption key file '{}' does not exist",
+                    key_file.display()
+                ),
+            }));
+        }
+    }
+
+    if options.unix_socket && !cfg!(unix) {
+        check(invalid(
+            "unix domain sockets are only supported on unix platforms",
+        ));
+    }
+
+    if options.unix_socket && options.srv_hostname.is_some() {
+        check(invalid(
+            "an SRV hostname can't be used with unix domain sockets",
+        ));
+    }
+
+    if options.unix_socket && options.advertise_host.is_some() {
+        check(invalid(
+            "an advertised host can't be used with unix domain sockets",
+        ));
+    }
+
+    if let Some(ref host) = options.advertise_host {
+        check(validate_advertise_host(host));
+    }
+
+    if let Some(ref bind_ip) = options.bind_ip {
+        if bind_ip.is_empty() || bind_ip.contains(|c: char| c == ',' || c.is_whitespace()) {
+            check(invalid(
+                "the bind address must be a single hostname or IP address",
+            ));
+        }
+    }
+
+    if let Some(ref fcv) = options.feature_compatibility_version {
+        check(validate_feature_compatibility_version(fcv));
+    }
+
+    let sets_default_rw_concern =
+        options.default_write_concern.is_some() || options.default_read_concern.is_some();
+
+    if let Some(ref level) = options.default_read_concern {
+        if !["local", "available", "majority"].contains(&level.as_str()) {
+            check(invalid(
+                "the default read concern must be one of local, available, or majority",
+            ));
+        }
+    }
+
+    // The server only supports `setDefaultRWConcern` on replica sets and mongos routers.
+    if check_topology && sets_default_rw_concern && matches!(options.topology, Topology::Single) {
+        check(invalid(
+            "the default read/write concern can only be set for replica sets and sharded clusters",
+        ));
+    }
+
+    check(validate_cluster_ip_source_allowlist(
+        &options.cluster_ip_source_allowlist,
+    ));
+
+    let overrides_version = options.config_server_version_id.is_some()
+        || options.shard_version_id.is_some()
+        || options.mongos_version_id.is_some();
+
+    if options.legacy_config_servers && !matches!(options.topology, Topology::Sharded { .. }) {
+        check(invalid(
+            "legacy config servers only apply to sharded clusters",
+        ));
+    }
+
+    if overrides_version && !matches!(options.topology, Topology::Sharded { .. }) {
+        check(invalid(
+            "per-role versions can only be set for sharded clusters",
+        ));
+    }
+
+    if options.require_sessions && matches!(options.topology, Topology::Single) {
+        check(invalid(
+            "sessions are not supported on a single server; start a replica set or sharded \
+             cluster instead",
+        ));
+    }
+
+    if options.require_sessions && options.disable_majority_read_concern {
+        check(invalid(
+            "sessions require majority read concern, so it can't be disabled",
+        ));
+    }
+
+    if options.no_initiate {
+        if !is_replica_set {
+            check(invalid(
+                "only replica sets can be started without being initiated",
+            ));
+        }
+
+        if options.auth.is_some() {
+            check(invalid(
+                "auth cannot be enabled for a replica set that won't be initiated",
+            ));
+        }
+
+        if options.feature_compatibility_version.is_some() {
+            check(invalid(
+                "the FCV cannot be set for a replica set that won't be initiated",
+            ));
+        }
+
+        if sets_default_rw_concern {
+            check(invalid(
+                "the default read/write concern cannot be set for a replica set that won't be \
+                 initiated",
+            ));
+        }
+    }
+
+    if let Some(version) = options.repl_set_config_version {
+        if !is_replica_set {
+            check(invalid(
+                "a replica set config version can only be set for replica sets",
+            ));
+        }
+
+        if version < 1 {
+            check(invalid("the replica set config version must be at least 1"));
+        }
+    }
+
+    if !options.wait_for_primary {
+        if !is_replica_set {
+            check(invalid("only replica sets can skip waiting for a primary"));
+        }
+
+        if options.auth.is_some() {
+            check(invalid(
+                "auth cannot be enabled without waiting for a primary",
+            ));
+        }
+
+        if options.feature_compatibility_version.is_some() {
+            check(invalid(
+                "the FCV cannot be set without waiting for a primary",
+            ));
+        }
+
+        if sets_default_rw_concern {
+            check(invalid(
+                "the default read/write concern cannot be set without waiting for a primary",
+            ));
+        }
+    }
+
+    if let Some(interval) = options.journal_commit_interval_ms {
+        check(validate_journal_commit_interval(interval));
+    }
+
+    check(validate_compressors(&options.compressors));
+
+    for compressor in options
+        .collection_block_compressor
+        .iter()
+        .chain(options.journal_compressor.iter())
+    {
+        check(validate_storage_compressor(compressor));
+    }
+
+    if let Some(size) = options.wired_tiger_cache_size_gb {
+        check(validate_wired_tiger_cache_size(size));
+
+        if matches!(options.storage_engine.as_deref(), Some(engine) if engine != "wiredTiger") {
+            check(invalid(
+                "a WiredTiger cache size can only be used with the wiredTiger storage engine",
+            ));
+        }
+    }
+
+    if options.max_incoming_connections == Some(0)
+        || options.mongos_max_incoming_connections == Some(0)
+    {
+        check(invalid(
+            "the maximum number of incoming connections must be positive",
+        ));
+    }
+
+    if options.mongos_config_refresh_ms == Some(0) {
+        check(invalid(
+            "the mongos config refresh timeout must be positive",
+        ));
+    }
+
+    if matches!(options.startup_profile_level, Some(level) if level > 2) {
+        check(invalid("the profiling level must be between 0 and 2"));
+    }
+
+    if matches!(options.mongod_verbosity, Some(verbosity) if verbosity > 5) {
+        check(invalid("mongod verbosity must be between 0 and 5"));
+    }
+
+    let num_processes = if check_topology {
+        planned_process_count(&options.topology)
+    } else {
+        0
+    };
+
+    // Ports are allocated sequentially from the start port, so every process needs to fit below
+    // the highest port. With `auto_port`, ports that are in use are skipped, so only the free ones
+    // count.
+    let available_ports = if options.auto_port {
+        (options.start_port..=u16::MAX)
+            .filter(|&port| port_is_free(port))
+            .take(num_processes)
+            .count()
+    } else {
+        usize::from(u16::MAX) + 1 - usize::from(options.start_port)
+    };
+
+    if options.start_port == 0 {
+        check(invalid("the start port must be nonzero"));
+    } else if available_ports < num_processes {
+        check(Err(Error::InvalidOptions {
+            message: format!(
+                "the cluster needs {} ports starting at {}, but only {} are available",
+                num_processes, options.start_port, available_ports
+            ),
+        }));
+    }
+
+    check(validate_process_count(
+        num_processes,
+        options.max_nodes,
+        options.allow_large,
+    ));
+
+    problems
+}
+
+fn validate_process_count(
+    num_processes: usize,
+    max_nodes: Option<usize>,
+    allow_large: bool,
+) -> Result<()> {
+    let max_nodes = max_nodes.unwrap_or(DEFAULT_MAX_NODES);
+
+    if num_processes > max_nodes && !allow_large {
+        return Err(Error::InvalidOptions {
+            message: format!(
+                "refusing to start {} processes, which exceeds the maximum of {}; raise the \
+                 maximum or allow large clusters to start anyway",
+                num_processes, max_nodes
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+fn validate_feature_compatibility_version(fcv: &str) -> Result<()> {
+    let components: Vec<_> = fcv.split('.').collect();
+
+    let valid = match components[..] {
+        [major, minor] => match (major.parse::<u32>(), minor.parse::<u32>()) {
+            (Ok(major), Ok(_)) => major >= 3,
+            _ => false,
+        },
+        _ => false,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidOptions {
+            message: format!(
+                "invalid feature compatibility version '{}'; expected <major>.<minor> (e.g. 4.4)",
+                fcv
+            ),
+        })
+    }
+}
+
+pub(crate) fn validate_advertise_host(host: &str) -> Result<()> {
+    // The port is appended to the host for each process, so only a bare hostname makes sense here.
+    if host.is_empty() || host.contains(|c: char| c == ':' || c == '/' || c.is_whitespace()) {
+        Err(Error::InvalidOptions {
+            message: format!(
+                "invalid advertised host '{}'; expected a hostname without a port",
+                host
+            ),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_repl_set_members(members: &[ReplSetMemberConfig], num_nodes: usize) -> Result<()> {
+    let invalid = |message: String| -> Result<()> { Err(Error::InvalidOptions { message }) };
+
+    if members.len() > num_nodes {
+        return invalid(format!(
+            "configuration given for {} members, but the replica set only has {} nodes",
+            members.len(),
+            num_nodes
+        ));
+    }
+
+    // phil initiates the replica set through the first member, which needs to hold data.
+    if members.first().map_or(false, |member| member.arbiter_only) {
+        return invalid("the first member of a replica set can't be an arbiter".into());
+    }
+
+    for (i, member) in members.iter().enumerate() {
+        let priority = member.priority.unwrap_or_default();
+
+        if priority < 0.0 {
+            return invalid(format!("member {} can't have a negative priority", i));
+        }
+
+        if !matches!(member.votes, None | Some(0) | Some(1)) {
+            return invalid(format!("member {} can only have 0 or 1 votes", i));
+        }
+
+        if member.hidden && member.arbiter_only {
+            return invalid(format!("member {} can't be both hidden and an arbiter", i));
+        }
+
+        if priority > 0.0 && (member.hidden || member.arbiter_only || member.votes == Some(0)) {
+            return invalid(format!(
+                "member {} must have a priority of 0, since it's hidden, an arbiter, or non-voting",
+                i
+            ));
+        }
+    }
+
+    // Members past the end of the list use the defaults, which are electable.
+    if members.len() == num_nodes && !members.iter().any(ReplSetMemberConfig::electable) {
+        return invalid("a replica set needs at least one member that can become primary".into());
+    }
+
+    Ok(())
+}
+
+fn validate_cluster_ip_source_allowlist(allowlist: &[String]) -> Result<()> {
+    for entry in allowlist {
+        let mut parts = entry.splitn(2, '/');
+        let address = parts.next().unwrap_or_default().parse::<IpAddr>();
+
+        let valid = match (address, parts.next()) {
+            (Ok(_), None) => true,
+            (Ok(address), Some(prefix)) => {
+                let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+                matches!(prefix.parse::<u8>(), Ok(prefix) if prefix <= max_prefix)
+            }
+            (Err(..), _) => false,
+        };
+
+        if !valid {
+            return Err(Error::InvalidOptions {
+                message: format!(
+                    "invalid cluster IP source allowlist entry '{}'; expected an IP address or \
+                     CIDR range",
+                    entry
+                ),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// The monger log file for the mongos router on the given port.
+pub(crate) fn router_log_file(cluster_id: &str, port: u16) -> LogFile {
+    LogFile {
+        cluster_id: cluster_id.to_string(),
+        port,
+        node_type: LogFileType::ShardingRouter,
+    }
+}
+
+/// The file in `log_dir` that the process with the given role (e.g. `config`, `shard0` or
+/// `mongos`) and port logs to.
+pub(crate) fn log_path(log_dir: &Path, role: &str, port: u16) -> PathBuf {
+    log_dir.join(format!("{}-{}.log", role, port))
+}
+
+/// Servers are restarted when enabling auth, so the log is appended to rather than overwritten.
+fn log_path_args(log_dir: &Path, role: &str, port: u16) -> Vec<OsString> {
+    vec![
+        "--logpath".into(),
+        log_path(log_dir, role, port).into(),
+        "--logappend".into(),
+    ]
+}
+
+/// Checks that a mixed-version sharded cluster looks like one partway through an upgrade, i.e. the
+/// config servers are no older than the shards, which are no older than the mongos routers, and
+/// no more than one major version separates them. Versions that can't be parsed are skipped.
+fn mixed_version_warning(config_server: &str, shard: &str, mongos: &str) -> Option<String> {
+    let config_server = ServerVersion::parse(config_server)?;
+    let shard = ServerVersion::parse(shard)?;
+    let mongos = ServerVersion::parse(mongos)?;
+
+    if config_server < shard || shard < mongos {
+        Some(
+            "mixed-version clusters are only supported with the config servers on the newest \
+             version and the mongos routers on the oldest"
+                .into(),
+        )
+    } else if config_server.major > mongos.major + 1 {
+        Some(format!(
+            "mixed-version clusters are only supported within adjacent releases, but the config \
+             servers use {}.{} and the mongos routers use {}.{}",
+            config_server.major, config_server.minor, mongos.major, mongos.minor
+        ))
+    } else {
+        None
+    }
+}
+
+fn validate_compressors(compressors: &[String]) -> Result<()> {
+    match compressors
+        .iter()
+        .find(|compressor| !["snappy", "zlib", "zstd"].contains(&compressor.as_str()))
+    {
+        Some(compressor) => Err(Error::InvalidOptions {
+            message: format!(
+                "invalid compressor '{}'; expected snappy, zlib, or zstd",
+                compressor
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn validate_storage_compressor(compressor: &str) -> Result<()> {
+    if ["snappy", "zlib", "zstd", "none"].contains(&compressor) {
+        Ok(())
+    } else {
+        Err(Error::InvalidOptions {
+            message: format!(
+                "invalid storage compressor '{}'; expected snappy, zlib, zstd, or none",
+                compressor
+            ),
+        })
+    }
+}
+
+fn validate_journal_commit_interval(interval: u32) -> Result<()> {
+    if (1..=500).contains(&interval) {
+        Ok(())
+    } else {
+        Err(Error::InvalidOptions {
+            message: format!(
+                "invalid journal commit interval {}ms; expected a value between 1 and 500",
+                interval
+            ),
+        })
+    }
+}
+
+fn validate_wired_tiger_cache_size(size: f64) -> Result<()> {
+    if (0.25..=10000.0).contains(&size) {
+        Ok(())
+    } else {
+        Err(Error::InvalidOptions {
+            message: format!(
+                "invalid WiredTiger cache size {}GB; expected a value between 0.25 and 10000",
+                size
+            ),
+        })
+    }
+}
+
+fn alpha_numeric() -> impl Iterator<Item = char> {
+    ('0'..'9').chain('A'..'Z').chain('a'..'z')
+}
@@ -0,0 +1,493 @@
+use super::*;
+
+fn os_strings(args: &[&str]) -> Vec<OsString> {
+    args.iter().map(OsString::from).collect()
+}
+
+#[test]
+fn set_parameter_args_forwards_every_occurrence() {
+    let args = os_strings(&[
+        "--setParameter",
+        "enableTestCommands=1",
+        "--wiredTigerCacheSizeGB",
+        "1",
+        "--setParameter",
+        "diagnosticDataCollectionEnabled=false",
+    ]);
+
+    assert_eq!(
+        set_parameter_args(&args),
+        os_strings(&[
+            "--setParameter",
+            "enableTestCommands=1",
+            "--setParameter",
+            "diagnosticDataCollectionEnabled=false",
+        ])
+    );
+}
+
+#[test]
+fn set_parameter_args_ignores_trailing_flag_without_value() {
+    let args = os_strings(&["--setParameter", "a=1", "--setParameter"]);
+
+    assert_eq!(
+        set_parameter_args(&args),
+        os_strings(&["--setParameter", "a=1"])
+    );
+}
+
+#[test]
+fn split_args_keeps_spaced_values_together() {
+    assert_eq!(
+        split_args(
+            "--setParameter failpoint.x={mode: \"alwaysOn\", data: [1, 2]} --setParameter \
+             'a=b c'  --quiet"
+        ),
+        os_strings(&[
+            "--setParameter",
+            "failpoint.x={mode: \"alwaysOn\", data: [1, 2]}",
+            "--setParameter",
+            "a=b c",
+            "--quiet",
+        ])
+    );
+}
+
+#[test]
+fn planned_process_count_includes_config_server_and_routers() {
+    let topology = Topology::Sharded {
+        num_mongos: NonZeroU8::new(2).unwrap(),
+        shard_db_paths: vec![vec![PathBuf::new(); 3], vec![PathBuf::new(); 3]],
+        config_db_paths: vec![PathBuf::new()],
+    };
+
+    assert_eq!(planned_process_count(&topology), 9);
+}
+
+#[test]
+fn planned_process_count_replica_set() {
+    let topology = Topology::ReplicaSet {
+        set_name: "phil".into(),
+        db_paths: vec![PathBuf::new(); 5],
+        members: Vec::new(),
+    };
+
+    assert_eq!(planned_process_count(&topology), 5);
+    assert_eq!(planned_process_count(&Topology::Single), 1);
+}
+
+#[test]
+fn journal_commit_interval_range() {
+    assert!(validate_journal_commit_interval(0).is_err());
+    assert!(validate_journal_commit_interval(1).is_ok());
+    assert!(validate_journal_commit_interval(500).is_ok());
+    assert!(validate_journal_commit_interval(501).is_err());
+}
+
+#[test]
+fn compressors_must_be_known() {
+    assert!(validate_compressors(&["snappy".into(), "zlib".into(), "zstd".into()]).is_ok());
+    assert!(validate_compressors(&["lz4".into()]).is_err());
+}
+
+#[test]
+fn mixed_version_warning_accepts_partial_upgrades() {
+    assert_eq!(mixed_version_warning("4.4", "4.4", "4.2"), None);
+    assert_eq!(mixed_version_warning("5.0", "4.4", "4.4"), None);
+    assert_eq!(mixed_version_warning("latest", "4.4", "4.2"), None);
+}
+
+#[test]
+fn mixed_version_warning_rejects_unsupported_arrangements() {
+    assert!(mixed_version_warning("4.2", "4.4", "4.4").is_some());
+    assert!(mixed_version_warning("4.4", "4.4", "5.0").is_some());
+    assert!(mixed_version_warning("6.0", "6.0", "4.4").is_some());
+}
+
+#[test]
+fn set_parameter_names_strip_values() {
+    let args = os_strings(&[
+        "--setParameter",
+        "enableTestCommands=1",
+        "--quiet",
+        "--setParameter",
+        "notARealParameter=true",
+    ]);
+
+    assert_eq!(
+        set_parameter_names(&args),
+        vec!["enableTestCommands", "notARealParameter"]
+    );
+}
+
+#[test]
+fn find_rejected_set_parameter_in_plain_log() {
+    let log = "2020-10-01T00:00:00.000+0000 I CONTROL  [main] Unknown --setParameter \
+               'notARealParameter'\n";
+
+    assert_eq!(
+        find_rejected_set_parameter(log).as_deref(),
+        Some("notARealParameter")
+    );
+}
+
+#[test]
+fn find_rejected_set_parameter_in_json_log() {
+    let log = r#"{"t":{"$date":"2020-10-01T00:00:00.000+00:00"},"s":"F","msg":"Bad value for parameter \"maxIndexBuildMemoryUsageMegabytes\": not a number"}"#;
+
+    assert_eq!(
+        find_rejected_set_parameter(log).as_deref(),
+        Some("maxIndexBuildMemoryUsageMegabytes")
+    );
+}
+
+#[test]
+fn encryption_is_only_reported_unsupported_when_logged() {
+    let log = "Error parsing command line: unrecognised option '--enableEncryption'\n";
+    assert!(rejects_encryption_options(log));
+
+    let log = "Failed to set up listener: SocketException: Address already in use\n";
+    assert!(!rejects_encryption_options(log));
+}
+
+#[test]
+fn member_tags_arg_skips_untagged_members() {
+    let tags = vec![
+        None,
+        Some(doc! { "dc": "east", "rack": "1" }),
+        Some(doc! { "dc": "west" }),
+    ];
+
+    assert_eq!(member_tags_arg(&tags), "1:dc=east,1:rack=1,2:dc=west");
+}
+
+#[test]
+fn validate_storage_compressor_accepts_none() {
+    assert!(validate_storage_compressor("none").is_ok());
+    assert!(validate_storage_compressor("zstd").is_ok());
+    assert!(validate_storage_compressor("lz4").is_err());
+}
+
+#[test]
+fn advertise_host_must_be_a_bare_hostname() {
+    assert!(validate_advertise_host("mongodb").is_ok());
+    assert!(validate_advertise_host("db.internal").is_ok());
+    assert!(validate_advertise_host("mongodb:27017").is_err());
+    assert!(validate_advertise_host("").is_err());
+}
+
+#[test]
+fn next_config_version_is_always_newer() {
+    assert_eq!(next_config_version(1, None), 2);
+    assert_eq!(next_config_version(1, Some(5)), 5);
+    assert_eq!(next_config_version(5, Some(5)), 6);
+    assert_eq!(next_config_version(7, Some(3)), 8);
+}
+
+#[test]
+fn invalid_configs_are_not_retried() {
+    assert!(is_fatal_repl_set_config_error("InvalidReplicaSetConfig"));
+    assert!(is_fatal_repl_set_config_error("BadValue"));
+    assert!(!is_fatal_repl_set_config_error("NodeNotFound"));
+    assert!(!is_fatal_repl_set_config_error("NotYetInitialized"));
+}
+
+#[test]
+fn set_name_conflicts_with_config_and_shard_names() {
+    let config = Some("phil-config");
+    let prefix = "phil-replset-shard";
+
+    assert!(set_name_conflicts("phil-config", config, prefix));
+    assert!(set_name_conflicts("phil-replset-shard-1", config, prefix));
+    assert!(!set_name_conflicts("phil-config", None, prefix));
+    assert!(!set_name_conflicts("other-config", config, prefix));
+    assert!(!set_name_conflicts("phil-replset-shard-a", config, prefix));
+}
+
+#[test]
+fn default_rw_concern_command_sends_numeric_w_as_number() {
+    assert_eq!(
+        default_rw_concern_command(Some("2"), Some("majority")),
+        doc! {
+            "setDefaultRWConcern": 1,
+            "defaultWriteConcern": { "w": 2 },
+            "defaultReadConcern": { "level": "majority" },
+        }
+    );
+    assert_eq!(
+        default_rw_concern_command(Some("majority"), None),
+        doc! {
+            "setDefaultRWConcern": 1,
+            "defaultWriteConcern": { "w": "majority" },
+        }
+    );
+}
+
+#[test]
+fn member_config_is_merged_into_member() {
+    let mut member = doc! { "_id": 1, "host": "localhost:27018" };
+
+    ReplSetMemberConfig {
+        hidden: true,
+        votes: Some(0),
+        ..Default::default()
+    }
+    .apply(&mut member);
+
+    assert_eq!(
+        member,
+        doc! {
+            "_id": 1,
+            "host": "localhost:27018",
+            "priority": 0,
+            "votes": 0,
+            "hidden": true,
+        }
+    );
+}
+
+#[test]
+fn repl_set_members_must_include_an_electable_member() {
+    let arbiter = ReplSetMemberConfig {
+        arbiter_only: true,
+        ..Default::default()
+    };
+    let hidden = ReplSetMemberConfig {
+        hidden: true,
+        ..Default::default()
+    };
+
+    assert!(validate_repl_set_members(&[], 3).is_ok());
+    assert!(validate_repl_set_members(&[Default::default(), arbiter.clone()], 3).is_ok());
+    assert!(validate_repl_set_members(&[hidden.clone()], 2).is_ok());
+    assert!(validate_repl_set_members(&[hidden.clone(), hidden], 2).is_err());
+    assert!(validate_repl_set_members(&[arbiter], 3).is_err());
+}
+
+#[test]
+fn bind_ip_args_keep_localhost_reachable() {
+    assert_eq!(bind_ip_args("0.0.0.0"), os_strings(&["--bind_ip_all"]));
+    assert_eq!(
+        bind_ip_args("127.0.0.1"),
+        os_strings(&["--bind_ip", "127.0.0.1"])
+    );
+    assert_eq!(
+        bind_ip_args("10.0.0.5"),
+        os_strings(&["--bind_ip", "localhost,10.0.0.5"])
+    );
+}
+
+#[test]
+fn port_in_use_is_not_free() {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    assert!(!port_is_free(port));
+
+    drop(listener);
+    assert!(port_is_free(port));
+}
+
+#[test]
+fn wired_tiger_cache_size_range() {
+    assert!(validate_wired_tiger_cache_size(0.1).is_err());
+    assert!(validate_wired_tiger_cache_size(0.25).is_ok());
+    assert!(validate_wired_tiger_cache_size(1.5).is_ok());
+    assert!(validate_wired_tiger_cache_size(f64::NAN).is_err());
+}
+
+#[test]
+fn wired_tiger_cache_size_requires_wired_tiger() {
+    let options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.4".into())
+        .storage_engine(Some("inMemory".into()))
+        .wired_tiger_cache_size_gb(Some(1.0))
+        .build();
+
+    assert_eq!(option_problems(&options).len(), 1);
+}
+
+#[test]
+fn storage_options_are_only_passed_to_mongod() {
+    let options = ClusterOptions::builder()
+        .topology(Topology::Sharded {
+            num_mongos: NonZeroU8::new(1).unwrap(),
+            shard_db_paths: vec![vec!["/data/shard".into()]],
+            config_db_paths: vec!["/data/config".into()],
+        })
+        .version_id("4.4".into())
+        .storage_engine(Some("wiredTiger".into()))
+        .wired_tiger_cache_size_gb(Some(0.5))
+        .build();
+    let launcher = Launcher::new(options).unwrap();
+
+    let config_server_args = launcher.mongod_args(&MongodOptions {
+        port: 27017,
+        db_path: Some("/data/config".into()),
+        config_server: true,
+        shard_num: None,
+        repl_set_name: Some("phil-config-server".into()),
+    });
+    let shard_args = launcher.mongod_args(&MongodOptions {
+        port: 27018,
+        db_path: Some("/data/shard".into()),
+        config_server: false,
+        shard_num: Some(0),
+        repl_set_name: Some("phil-replset-shard-0".into()),
+    });
+    let mongos_args = launcher
+        .mongos_args(&MongosOptions {
+            port: 27019,
+            config_db_name: "phil-config-server".into(),
+        })
+        .unwrap();
+
+    for args in vec![config_server_args, shard_args] {
+        assert_eq!(
+            arg_value(&args, "--storageEngine"),
+            Some(&OsString::from("wiredTiger"))
+        );
+        assert_eq!(
+            arg_value(&args, "--wiredTigerCacheSizeGB"),
+            Some(&OsString::from("0.5"))
+        );
+    }
+
+    assert_eq!(arg_value(&mongos_args, "--storageEngine"), None);
+    assert_eq!(arg_value(&mongos_args, "--wiredTigerCacheSizeGB"), None);
+}
+
+#[test]
+fn default_rw_concern_is_rejected_for_single_server() {
+    let options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.4".into())
+        .default_write_concern(Some("majority".into()))
+        .build();
+
+    assert_eq!(option_problems(&options).len(), 1);
+}
+
+#[test]
+fn mongos_config_refresh_must_be_positive() {
+    let options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("4.4".into())
+        .mongos_config_refresh_ms(Some(0))
+        .build();
+
+    assert_eq!(option_problems(&options).len(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn wait_for_startup_reports_immediate_exit() {
+    let mut process = Command::new("false").spawn().unwrap();
+    let port = TcpListener::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let exit_status = wait_for_startup(&mut process, port, true, Duration::from_secs(5)).unwrap();
+    assert!(!exit_status.unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn wait_for_startup_returns_once_port_accepts_connections() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut process = Command::new("sleep").arg("30").spawn().unwrap();
+
+    let start = Instant::now();
+    let exit_status = wait_for_startup(&mut process, port, true, Duration::from_secs(30)).unwrap();
+
+    assert!(exit_status.is_none());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    process.kill().unwrap();
+    process.wait().unwrap();
+}
+
+#[test]
+fn no_primary_elected_reports_member_states() {
+    let ReplSetStatus { members } = mongodb::bson::from_document(doc! {
+        "members": [
+            { "name": "localhost:27017", "stateStr": "SECONDARY" },
+            { "name": "localhost:27018", "stateStr": "SECONDARY" },
+        ],
+    })
+    .unwrap();
+
+    let error = Error::NoPrimaryElected {
+        set_name: "phil".into(),
+        members: member_states(&members),
+    };
+
+    assert_eq!(
+        error.to_string(),
+        "replica set 'phil' did not elect a primary before timing out; last seen member states: \
+         localhost:27017 (SECONDARY), localhost:27018 (SECONDARY)"
+    );
+}
+
+#[test]
+fn repl_set_member_port_ignores_host() {
+    let ReplSetStatus { members } = mongodb::bson::from_document(doc! {
+        "members": [
+            { "name": "localhost:27017", "stateStr": "PRIMARY" },
+            { "name": "db.example.com:27018", "stateStr": "SECONDARY" },
+        ],
+    })
+    .unwrap();
+
+    let ports: Vec<_> = members.iter().map(ReplSetMember::port).collect();
+    assert_eq!(ports, vec![Some(27017), Some(27018)]);
+}
+
+#[test]
+fn legacy_config_servers_require_sharded_topology() {
+    let options = ClusterOptions::builder()
+        .topology(Topology::Single)
+        .version_id("3.2".into())
+        .legacy_config_servers(true)
+        .build();
+
+    assert_eq!(option_problems(&options).len(), 1);
+}
+
+#[test]
+fn legacy_config_servers_need_one_or_three_nodes() {
+    let problems = |num_config_servers| {
+        let options = ClusterOptions::builder()
+            .topology(Topology::Sharded {
+                num_mongos: NonZeroU8::new(1).unwrap(),
+                shard_db_paths: vec![vec!["/data/shard".into()]],
+                config_db_paths: (0..num_config_servers)
+                    .map(|i| format!("/data/config-{}", i).into())
+                    .collect(),
+            })
+            .version_id("3.2".into())
+            .legacy_config_servers(true)
+            .build();
+
+        option_problems(&options).len()
+    };
+
+    assert_eq!(problems(1), 0);
+    assert_eq!(problems(2), 1);
+    assert_eq!(problems(3), 0);
+}
+
+#[test]
+fn allocate_port_stops_after_highest_port() {
+    let mut next_port = Some(u16::MAX);
+
+    assert_eq!(allocate_port(&mut next_port, false).unwrap(), u16::MAX);
+    assert!(matches!(
+        allocate_port(&mut next_port, false),
+        Err(Error::InvalidOptions { .. })
+    ));
+}
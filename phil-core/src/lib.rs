@@ -1,3 +1,8 @@
+pub mod check;
 pub mod cluster;
+mod display;
 pub mod error;
+pub mod event;
 mod launch;
+pub mod shutdown;
+mod version;
@@ -0,0 +1,303 @@
+#[cfg(test)]
+mod test;
+
+use std::{
+    fs::{File, OpenOptions},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{info, warn};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::ErrorKind,
+    options::{ClientOptions, StreamAddress},
+    sync::Client,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    launch::{admin_db, localhost_address, terminate_pid},
+};
+
+/// A mongod or mongos process recorded in a `ClusterState`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProcessState {
+    pub pid: u32,
+    pub port: u16,
+    pub db_path: Option<PathBuf>,
+}
+
+/// What's needed to stop a cluster after the process that started it has exited: a connection
+/// string to shut it down cleanly with, and the processes to fall back to killing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterState {
+    pub uri: String,
+    pub processes: Vec<ProcessState>,
+}
+
+impl ClusterState {
+    pub fn read(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    /// Writes the state as JSON to a new file, failing if anything (including a symlink) already
+    /// exists at `path` rather than replacing another cluster's state. The connection string can
+    /// contain credentials, so on unix, the file is created readable only by its owner.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            options.mode(0o600);
+        }
+
+        serde_json::to_writer_pretty(options.open(path)?, self)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IsMasterResponse {
+    msg: Option<String>,
+    #[serde(default)]
+    hosts: Vec<String>,
+    #[serde(default)]
+    passives: Vec<String>,
+    #[serde(default)]
+    arbiters: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListShardsResponse {
+    shards: Vec<ShardInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShardInfo {
+    host: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CmdLineOptsResponse {
+    parsed: Document,
+}
+
+/// The nodes of a running cluster, grouped in the order they should be shut down.
+#[derive(Debug, Default)]
+struct DiscoveredNodes {
+    routers: Vec<StreamAddress>,
+    shards: Vec<StreamAddress>,
+    config_servers: Vec<StreamAddress>,
+}
+
+impl DiscoveredNodes {
+    fn contains(&self, address: &StreamAddress) -> bool {
+        self.routers.contains(address)
+            || self.shards.contains(address)
+            || self.config_servers.contains(address)
+    }
+
+    fn in_shutdown_order(self) -> impl Iterator<Item = StreamAddress> {
+        self.routers
+            .into_iter()
+            .chain(self.shards)
+            .chain(self.config_servers)
+    }
+}
+
+/// Shuts down every reachable node of the cluster that the given URI points to. For sharded
+/// clusters, the mongos routers are shut down first, then the shards, and then the config servers.
+pub fn shutdown(uri: &str) -> Result<()> {
+    let base_options = ClientOptions::parse(uri)?;
+    let mut nodes = DiscoveredNodes::default();
+
+    for seed in base_options.hosts.clone() {
+        if nodes.contains(&seed) {
+            continue;
+        }
+
+        let client = direct_client(&base_options, &seed)?;
+
+        let response = match admin_command(&client, doc! { "isMaster": 1 }) {
+            Ok(response) => response,
+            Err(..) => {
+                warn!("unable to reach {}; skipping", seed);
+                continue;
+            }
+        };
+
+        let IsMasterResponse {
+            msg,
+            hosts,
+            passives,
+            arbiters,
+        } = mongodb::bson::from_document(response)?;
+
+        if msg.as_deref() == Some("isdbgrid") {
+            nodes.routers.push(seed);
+
+            let ListShardsResponse { shards } =
+                mongodb::bson::from_document(admin_command(&client, doc! { "listShards": 1 })?)?;
+
+            for shard in shards {
+                push_new(&mut nodes.shards, parse_host_list(&shard.host)?);
+            }
+
+            let CmdLineOptsResponse { parsed } = mongodb::bson::from_document(admin_command(
+                &client,
+                doc! { "getCmdLineOpts": 1 },
+            )?)?;
+
+            if let Ok(config_db) = parsed
+                .get_document("sharding")
+                .and_then(|sharding| sharding.get_str("configDB"))
+            {
+                push_new(&mut nodes.config_servers, parse_host_list(config_db)?);
+            }
+        } else if hosts.is_empty() {
+            nodes.shards.push(seed);
+        } else {
+            let members = hosts
+                .iter()
+                .chain(passives.iter())
+                .chain(arbiters.iter())
+                .map(String::as_str)
+                .map(parse_address)
+                .collect::<Result<_>>()?;
+
+            push_new(&mut nodes.shards, members);
+        }
+    }
+
+    for address in nodes.in_shutdown_order() {
+        info!("shutting down {}...", address);
+
+        let client = direct_client(&base_options, &address)?;
+
+        // The server closes the connection when it shuts down, so a network error is the expected
+        // outcome.
+        match admin_command(&client, doc! { "shutdown": 1, "force": true }) {
+            Err(Error::Mongo { inner }) if matches!(inner.kind.as_ref(), ErrorKind::Io(..)) => {}
+            Err(Error::Mongo { .. }) => warn!("unable to shut down {}; skipping", address),
+            Err(error) => return Err(error),
+            Ok(..) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Stops the cluster recorded in the state file at the given path and then deletes the file. The
+/// cluster is shut down cleanly if possible, and any recorded process that's still listening
+/// afterwards is terminated.
+pub fn stop(state_file: &Path) -> Result<()> {
+    let state = ClusterState::read(state_file)?;
+
+    if let Err(error) = shutdown(&state.uri) {
+        warn!("unable to shut down the cluster cleanly: {}", error);
+    }
+
+    for process in &state.processes {
+        if TcpStream::connect(("localhost", process.port)).is_err() {
+            continue;
+        }
+
+        // If the process already exited, its PID and port may have been reused, so a process is
+        // only terminated if the server on its port reports the recorded PID.
+        match server_pid(&state.uri, process.port) {
+            Ok(pid) if pid == process.pid => {
+                info!(
+                    "terminating process {} on port {}...",
+                    process.pid, process.port
+                );
+
+                terminate_pid(process.pid)?;
+            }
+            Ok(pid) => info!(
+                "port {} now belongs to process {} rather than {}; skipping",
+                process.port, pid, process.pid
+            ),
+            Err(error) => warn!(
+                "unable to check the process on port {}, so process {} won't be terminated: {}",
+                process.port, process.pid, error
+            ),
+        }
+    }
+
+    std::fs::remove_file(state_file)?;
+
+    Ok(())
+}
+
+/// The PID reported by the server listening on the given port.
+fn server_pid(uri: &str, port: u16) -> Result<u32> {
+    let mut options = ClientOptions::parse(uri)?;
+    options.server_selection_timeout = Some(Duration::from_secs(2));
+
+    let client = direct_client(&options, &localhost_address(port))?;
+    let response = admin_command(&client, doc! { "serverStatus": 1 })?;
+
+    match response.get("pid") {
+        Some(Bson::Int32(pid)) => Ok(*pid as u32),
+        Some(Bson::Int64(pid)) => Ok(*pid as u32),
+        _ => Err(Error::NoProcess { port }),
+    }
+}
+
+fn direct_client(base_options: &ClientOptions, address: &StreamAddress) -> Result<Client> {
+    let mut options = base_options.clone();
+    options.hosts = vec![address.clone()];
+    options.repl_set_name = None;
+    options.direct_connection = Some(true);
+
+    Ok(Client::with_options(options)?)
+}
+
+fn admin_command(client: &Client, command: Document) -> Result<Document> {
+    Ok(admin_db(&client).run_command(command, None)?)
+}
+
+fn push_new(addresses: &mut Vec<StreamAddress>, new_addresses: Vec<StreamAddress>) {
+    for address in new_addresses {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+}
+
+fn parse_address(address: &str) -> Result<StreamAddress> {
+    let invalid_address = || Error::InvalidOptions {
+        message: format!("invalid host '{}'; expected <hostname>:<port>", address),
+    };
+
+    match address.rsplit_once(':') {
+        Some((hostname, port)) => Ok(StreamAddress {
+            hostname: hostname.into(),
+            port: Some(port.parse().map_err(|_| invalid_address())?),
+        }),
+        None if !address.is_empty() => Ok(StreamAddress {
+            hostname: address.into(),
+            port: None,
+        }),
+        None => Err(invalid_address()),
+    }
+}
+
+/// Parses a host list of the form `[<set name>/]<host>[,<host>...]`, as used by `listShards` and
+/// the `configDB` option of mongos.
+fn parse_host_list(hosts: &str) -> Result<Vec<StreamAddress>> {
+    let hosts = match hosts.split_once('/') {
+        Some((_set_name, hosts)) => hosts,
+        None => hosts,
+    };
+
+    hosts.split(',').map(parse_address).collect()
+}
@@ -0,0 +1,107 @@
+use super::*;
+
+fn address(hostname: &str, port: u16) -> StreamAddress {
+    StreamAddress {
+        hostname: hostname.into(),
+        port: Some(port),
+    }
+}
+
+#[test]
+fn parse_replica_set_host_list() {
+    assert_eq!(
+        parse_host_list("phil-config-server/localhost:27019,localhost:27020").unwrap(),
+        vec![address("localhost", 27019), address("localhost", 27020)]
+    );
+}
+
+#[test]
+fn parse_standalone_host_list() {
+    assert_eq!(
+        parse_host_list("localhost:27021").unwrap(),
+        vec![address("localhost", 27021)]
+    );
+}
+
+#[test]
+fn parse_host_list_with_invalid_port() {
+    assert!(parse_host_list("localhost:port").is_err());
+}
+
+#[test]
+fn cluster_state_round_trip() {
+    let dir = tempdir::TempDir::new("phil-state").unwrap();
+    let path = dir.path().join("state.json");
+
+    let state = ClusterState {
+        uri: "mongodb://localhost:27017".into(),
+        processes: vec![
+            ProcessState {
+                pid: 1234,
+                port: 27017,
+                db_path: Some(dir.path().join("node-0")),
+            },
+            ProcessState {
+                pid: 1235,
+                port: 27018,
+                db_path: None,
+            },
+        ],
+    };
+
+    state.write(&path).unwrap();
+    assert_eq!(ClusterState::read(&path).unwrap(), state);
+}
+
+#[test]
+fn cluster_state_is_not_written_over_existing_file() {
+    let dir = tempdir::TempDir::new("phil-state").unwrap();
+    let path = dir.path().join("state.json");
+    std::fs::write(&path, "another cluster's state").unwrap();
+
+    let state = ClusterState {
+        uri: "mongodb://localhost:27017".into(),
+        processes: Vec::new(),
+    };
+
+    assert!(state.write(&path).is_err());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "another cluster's state"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn stop_only_terminates_processes_that_still_own_their_port() {
+    use std::{net::TcpListener, process::Command};
+
+    let dir = tempdir::TempDir::new("phil-state").unwrap();
+    let path = dir.path().join("state.json");
+
+    // Something other than the recorded process is now listening on the recorded port.
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut process = Command::new("sleep").arg("30").spawn().unwrap();
+
+    let state = ClusterState {
+        uri: format!(
+            "mongodb://localhost:{}/?serverSelectionTimeoutMS=500&connectTimeoutMS=500",
+            port
+        ),
+        processes: vec![ProcessState {
+            pid: process.id(),
+            port,
+            db_path: None,
+        }],
+    };
+    state.write(&path).unwrap();
+
+    stop(&path).unwrap();
+
+    assert!(process.try_wait().unwrap().is_none());
+    assert!(!path.exists());
+
+    process.kill().unwrap();
+    process.wait().unwrap();
+}
@@ -0,0 +1,74 @@
+#[cfg(test)]
+mod test;
+
+use monger_core::Monger;
+use semver::{Version, VersionReq};
+
+use crate::error::{Error, Result};
+
+fn is_version_range(version_id: &str) -> bool {
+    version_id.starts_with(|c: char| "~^<>=*".contains(c))
+}
+
+// monger IDs are often abbreviated (e.g. "4.2"), so missing components are filled in with zeros.
+fn parse_version(id: &str) -> Option<Version> {
+    Version::parse(id)
+        .or_else(|_| Version::parse(&format!("{}.0", id)))
+        .or_else(|_| Version::parse(&format!("{}.0.0", id)))
+        .ok()
+}
+
+/// The version of the server binaries being used, parsed from the monger ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ServerVersion {
+    pub(crate) major: u64,
+    pub(crate) minor: u64,
+    pub(crate) patch: u64,
+}
+
+impl ServerVersion {
+    /// Returns `None` for IDs that aren't version numbers (e.g. "latest").
+    pub(crate) fn parse(id: &str) -> Option<Self> {
+        parse_version(id).map(|version| Self {
+            major: version.major,
+            minor: version.minor,
+            patch: version.patch,
+        })
+    }
+
+    pub(crate) fn at_least(&self, major: u64, minor: u64) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+
+fn latest_matching_version(
+    requirement: &VersionReq,
+    ids: impl IntoIterator<Item = String>,
+) -> Option<String> {
+    ids.into_iter()
+        .filter_map(|id| parse_version(&id).map(|version| (version, id)))
+        .filter(|(version, _)| requirement.matches(version))
+        .max_by(|(v1, _), (v2, _)| v1.cmp(v2))
+        .map(|(_, id)| id)
+}
+
+/// Resolves a version range (e.g. "~5.0") to the latest matching version installed in monger.
+/// Anything that isn't a range is assumed to be an exact monger ID and is returned as-is.
+pub(crate) fn resolve_version(monger: &Monger, version_id: String) -> Result<String> {
+    if !is_version_range(&version_id) {
+        return Ok(version_id);
+    }
+
+    let requirement = VersionReq::parse(&version_id).map_err(|e| Error::InvalidOptions {
+        message: format!("invalid version range '{}': {}", version_id, e),
+    })?;
+
+    let installed = monger
+        .list_versions()?
+        .into_iter()
+        .filter_map(|id| id.into_string().ok());
+
+    latest_matching_version(&requirement, installed).ok_or(Error::NoMatchingVersion {
+        requirement: version_id,
+    })
+}
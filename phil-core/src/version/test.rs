@@ -0,0 +1,73 @@
+use super::*;
+
+fn ids(ids: &[&str]) -> Vec<String> {
+    ids.iter().map(ToString::to_string).collect()
+}
+
+#[test]
+fn exact_ids_are_not_ranges() {
+    assert!(!is_version_range("4.2"));
+    assert!(!is_version_range("4.2.1"));
+    assert!(!is_version_range("latest"));
+    assert!(is_version_range("~5.0"));
+    assert!(is_version_range(">=4.4"));
+}
+
+#[test]
+fn latest_matching_version_picks_highest_match() {
+    let requirement = VersionReq::parse("~5.0").unwrap();
+    let installed = ids(&["4.4.1", "5.0.2", "5.0.10", "5.1.0", "latest"]);
+
+    assert_eq!(
+        latest_matching_version(&requirement, installed),
+        Some("5.0.10".to_string())
+    );
+}
+
+#[test]
+fn latest_matching_version_handles_abbreviated_ids() {
+    let requirement = VersionReq::parse("^4").unwrap();
+    let installed = ids(&["3.6", "4.2", "4.4"]);
+
+    assert_eq!(
+        latest_matching_version(&requirement, installed),
+        Some("4.4".to_string())
+    );
+}
+
+#[test]
+fn latest_matching_version_no_match() {
+    let requirement = VersionReq::parse("~6.0").unwrap();
+
+    assert_eq!(
+        latest_matching_version(&requirement, ids(&["4.4", "5.0.2"])),
+        None
+    );
+}
+
+#[test]
+fn parse_server_version() {
+    assert_eq!(
+        ServerVersion::parse("4.2"),
+        Some(ServerVersion {
+            major: 4,
+            minor: 2,
+            patch: 0
+        })
+    );
+    assert_eq!(
+        ServerVersion::parse("4.4.1-ent").map(|version| version.patch),
+        Some(1)
+    );
+    assert_eq!(ServerVersion::parse("latest"), None);
+}
+
+#[test]
+fn server_version_at_least() {
+    let version = ServerVersion::parse("4.2.8").unwrap();
+
+    assert!(version.at_least(4, 2));
+    assert!(version.at_least(3, 6));
+    assert!(!version.at_least(4, 4));
+    assert!(!version.at_least(5, 0));
+}
@@ -0,0 +1,37 @@
+use mongodb::bson::Document;
+use phil_core::error::{describe_origin, Error as CoreError};
+
+/// Formats an error for display to the user. Server errors from phil-core contain the raw command
+/// response, so the relevant fields are pulled out of it rather than printing the whole document.
+pub(crate) fn describe(error: &anyhow::Error) -> String {
+    match error.downcast_ref::<CoreError>() {
+        Some(CoreError::AddShardError {
+            response,
+            set_name,
+            port,
+        }) => format!(
+            "error when adding shard{}: {}",
+            describe_origin(set_name, port),
+            server_message(response)
+        ),
+        Some(CoreError::ReplicaSetConfigError {
+            response,
+            set_name,
+            port,
+        }) => format!(
+            "error when configuring replica set{}: {}",
+            describe_origin(set_name, port),
+            server_message(response)
+        ),
+        _ => format!("{:#}", error),
+    }
+}
+
+fn server_message(response: &Document) -> String {
+    let message = response.get_str("errmsg").unwrap_or("unknown error");
+
+    match response.get_str("codeName") {
+        Ok(code_name) => format!("{} ({})", message, code_name),
+        Err(..) => message.into(),
+    }
+}
@@ -1,29 +1,60 @@
-mod display;
+mod error;
+#[cfg(test)]
+mod test;
+#[cfg(feature = "self-update")]
+mod update;
 
 use std::{
     convert::{TryFrom, TryInto},
     ffi::OsString,
+    io::Write,
+    num::{NonZeroU32, NonZeroU8},
     path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
 };
 
-use anyhow::{Error, Result};
-use phil_core::cluster::{Cluster, ClusterOptions, Credential, TlsOptions, Topology};
-use self_update::backends::github::Update;
-use structopt::StructOpt;
+use anyhow::{anyhow, Context, Error, Result};
+use log::{Level, LevelFilter};
+use mongodb::{
+    bson::Document,
+    options::{ReadPreference, ReadPreferenceOptions},
+};
+use phil_core::{
+    check::{check_installation, check_tls_files},
+    cluster::{
+        terminate_started_processes,
+        Cluster,
+        ClusterOptions,
+        Credential,
+        Role,
+        TlsOptions,
+        Topology,
+    },
+};
+use structopt::{
+    clap::{AppSettings, Shell},
+    StructOpt,
+};
 use uuid::Uuid;
 
-use crate::display::ClientOptionsWrapper;
+fn temp_name(prefix: &str, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{}-{}-{}", prefix, label, Uuid::new_v4()),
+        None => format!("{}-{}", prefix, Uuid::new_v4()),
+    }
+}
 
-fn create_tempdir() -> Result<PathBuf> {
-    let dir = std::env::temp_dir().join(format!("phil-mongodb-{}", Uuid::new_v4()));
+fn create_tempdir(base: &Path, label: Option<&str>) -> Result<PathBuf> {
+    let dir = base.join(temp_name("phil-mongodb", label));
     std::fs::create_dir(&dir)?;
 
     Ok(dir)
 }
 
-fn create_tempfile() -> Result<PathBuf> {
-    let path = std::env::temp_dir().join(format!("phil-keyfile-{}", Uuid::new_v4()));
-    std::fs::write(&path, &"phil and ravi")?;
+fn create_tempfile(name: &str, contents: &str, label: Option<&str>) -> Result<PathBuf> {
+    let path = std::env::temp_dir().join(temp_name(&format!("phil-{}", name), label));
+    std::fs::write(&path, contents)?;
 
     if cfg!(unix) {
         use std::{fs::Permissions, os::unix::fs::PermissionsExt};
@@ -34,6 +65,142 @@ fn create_tempfile() -> Result<PathBuf> {
     Ok(path)
 }
 
+fn writable_dir(dir: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+
+    let metadata = std::fs::metadata(dir)?;
+
+    if !metadata.is_dir() || metadata.permissions().readonly() {
+        return Err(anyhow!("'{}' is not a writable directory", dir.display()));
+    }
+
+    Ok(dir.canonicalize()?)
+}
+
+fn parse_label(label: &str) -> Result<String> {
+    if !label.is_empty()
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(label.into())
+    } else {
+        Err(anyhow!(
+            "invalid label '{}'; only letters, numbers, '-', and '_' are allowed",
+            label
+        ))
+    }
+}
+
+fn parse_role(role: &str) -> Result<Role> {
+    let mut parts = role.splitn(2, '@');
+
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(db)) if !name.is_empty() && !db.is_empty() => Ok(Role {
+            role: name.into(),
+            db: db.into(),
+        }),
+        _ => Err(anyhow!("invalid role '{}'; expected <role>@<db>", role)),
+    }
+}
+
+/// Resolves one of the TLS files, naming the file in the error if it can't be found.
+fn tls_file_path(role: &str, path: &str) -> Result<PathBuf> {
+    Path::new(path).canonicalize().map_err(|error| {
+        if error.kind() == std::io::ErrorKind::NotFound {
+            anyhow!("TLS {} file not found: {}", role, path)
+        } else {
+            anyhow!("unable to read TLS {} file {}: {}", role, path, error)
+        }
+    })
+}
+
+/// Writes the connection string for `--uri-file`, without the `MONGODB_URI=` prefix that's printed.
+fn write_uri_file(path: &Path, uri: &str) -> Result<()> {
+    std::fs::write(path, uri).with_context(|| format!("unable to write {}", path.display()))
+}
+
+/// The state file used when `--state-file` isn't given. It contains the connection string, so it's
+/// kept in a per-user directory (`~/.phil`) rather than the shared temp directory.
+fn default_state_file() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+
+    Some(PathBuf::from(home).join(".phil").join("state.json"))
+}
+
+/// Records the cluster's state for `phil stop`. Failing to do so doesn't stop the cluster from
+/// being usable, so it's only reported as a warning.
+fn write_state_file(cluster: &Cluster, path: Option<PathBuf>) {
+    let path = match path.or_else(default_state_file) {
+        Some(path) => path,
+        None => {
+            log::warn!(
+                "no home directory was found, so the cluster's state wasn't recorded; pass \
+                 --state-file to be able to stop it with `phil stop`"
+            );
+            return;
+        }
+    };
+
+    let created_dir = match path.parent() {
+        Some(dir) => create_private_dir(dir).map_err(Into::into),
+        None => Ok(()),
+    };
+    let result = created_dir.and_then(|()| cluster.state().write(&path));
+
+    if let Err(error) = result {
+        log::warn!(
+            "unable to record the cluster's state in {}, so `phil stop` won't be able to stop it: \
+             {}",
+            path.display(),
+            error
+        );
+    }
+}
+
+/// Creates a directory (and its parents) that only the current user can access.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+
+        builder.mode(0o700);
+    }
+
+    builder.create(dir)
+}
+
+fn find_in_path(binary: &str) -> Option<PathBuf> {
+    let binary = if cfg!(windows) {
+        format!("{}.exe", binary)
+    } else {
+        binary.to_string()
+    };
+
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(&binary))
+        .find(|path| path.is_file())
+}
+
+/// Resolves the value of `phil connect --shell`. A bare name like `mongosh` or `mongo` is looked
+/// up in the PATH, and anything containing a path separator is used as-is.
+fn find_shell(shell: &str) -> Result<PathBuf> {
+    let path = Path::new(shell);
+
+    if path.components().count() > 1 {
+        if path.is_file() {
+            return Ok(path.to_path_buf());
+        }
+
+        return Err(anyhow!("the shell '{}' does not exist", path.display()));
+    }
+
+    find_in_path(shell).ok_or_else(|| anyhow!("unable to find '{}' in the PATH", shell))
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(about, author)]
 enum Command {
@@ -56,8 +223,125 @@ enum Command {
         options: ShardedOptions,
     },
 
+    /// open a MongoDB shell connected to a running cluster
+    Connect {
+        /// the connection string of the cluster
+        #[structopt(long)]
+        uri: String,
+
+        /// the shell to use, either `mongosh`, `mongo`, or a path to a shell binary (defaults to
+        /// mongosh if it's installed, and mongo otherwise)
+        #[structopt(long)]
+        shell: Option<String>,
+    },
+
+    /// shut down every reachable node of a running cluster
+    Shutdown {
+        /// the connection string of the cluster
+        #[structopt(long)]
+        uri: String,
+    },
+
+    /// stop the cluster recorded in a state file, killing any process that doesn't shut down
+    Stop {
+        /// the state file written when the cluster was started (defaults to ~/.phil/state.json)
+        #[structopt(long, parse(from_os_str))]
+        state_file: Option<PathBuf>,
+    },
+
+    /// check that a version and its binaries are installed without starting anything
+    Check {
+        #[structopt(flatten)]
+        options: CheckOptions,
+    },
+
     /// updates phil to the latest version
-    SelfUpdate,
+    #[cfg(feature = "self-update")]
+    SelfUpdate {
+        /// only report whether a newer version is available rather than installing it
+        #[structopt(long)]
+        check_only: bool,
+    },
+
+    /// print shell completions to stdout
+    #[structopt(setting(AppSettings::Hidden))]
+    Completions {
+        #[structopt(possible_values(&Shell::variants()))]
+        shell: Shell,
+    },
+}
+
+impl Command {
+    fn verbose(&self) -> bool {
+        match self {
+            Self::Single { options } => options.common.verbose,
+            Self::ReplSet { options } => options.common.verbose,
+            Self::Sharded { options } => options.common.verbose,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct CheckOptions {
+    /// the ID of the database version managed by monger to check, or a version range (e.g. ~5.0)
+    #[structopt(name = "ID")]
+    id: String,
+
+    /// also check for the binaries needed to start a sharded cluster
+    #[structopt(long)]
+    sharded: bool,
+
+    /// also check that the TLS files exist
+    #[structopt(long)]
+    tls: bool,
+
+    /// the certificate authority file to check (defaults to ./ca.pem)
+    #[structopt(long, requires("tls"))]
+    ca_file: Option<PathBuf>,
+
+    /// the server private key certificate file to check (defaults to ./server.pem)
+    #[structopt(long, requires("tls"))]
+    server_cert_file: Option<PathBuf>,
+
+    /// the client private key certificate file to check (defaults to ./client.pem)
+    #[structopt(long, requires("tls"))]
+    client_cert_file: Option<PathBuf>,
+
+    /// the directory containing the `.monger` directory to use (defaults to the home directory)
+    #[structopt(long)]
+    monger_home: Option<PathBuf>,
+}
+
+impl CheckOptions {
+    fn run(self) -> Result<()> {
+        let mut results = check_installation(self.id, self.sharded, self.monger_home.as_deref())?;
+
+        if self.tls {
+            let tls_files = [
+                self.ca_file.unwrap_or_else(|| "./ca.pem".into()),
+                self.server_cert_file
+                    .unwrap_or_else(|| "./server.pem".into()),
+                self.client_cert_file
+                    .unwrap_or_else(|| "./client.pem".into()),
+            ];
+
+            results.extend(check_tls_files(tls_files.iter().map(PathBuf::as_path)));
+        }
+
+        for result in &results {
+            let status = if result.passed { "PASS" } else { "FAIL" };
+            println!("[{}] {}", status, result.description);
+        }
+
+        let failures = results.iter().filter(|result| !result.passed).count();
+
+        if failures > 0 {
+            return Err(anyhow!("{} of {} checks failed", failures, results.len()));
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -73,11 +357,67 @@ struct ReplSetOptions {
 
     /// the number of nodes per replica set
     #[structopt(long, short, default_value = "3")]
-    nodes: u8,
+    nodes: NonZeroU8,
 
     /// the name of the replica set
     #[structopt(long, short, default_value = "phil")]
     set_name: String,
+
+    /// tags for the replica set members, specified as a comma-separated list of
+    /// `<member>:<key>=<value>` pairs (e.g. `0:dc=east,1:dc=west`)
+    #[structopt(long)]
+    tags: Option<MemberTags>,
+
+    /// start the replica set members without initiating the replica set
+    #[structopt(long, conflicts_with_all(&[
+        "auth",
+        "fcv",
+        "default-write-concern",
+        "default-read-concern",
+    ]))]
+    no_initiate: bool,
+
+    /// return as soon as the replica set is initiated rather than waiting for a primary
+    #[structopt(long, conflicts_with_all(&[
+        "auth",
+        "fcv",
+        "default-write-concern",
+        "default-read-concern",
+    ]))]
+    no_wait_for_primary: bool,
+
+    /// the version of the initial replica set config (defaults to the server's choice)
+    #[structopt(long)]
+    replset_config_version: Option<i32>,
+}
+
+#[derive(Debug, Default)]
+struct MemberTags(Vec<Option<Document>>);
+
+impl FromStr for MemberTags {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tags: Vec<Option<Document>> = Vec::new();
+
+        for tag in s.split(',') {
+            let invalid_tag = || anyhow!("invalid tag '{}'; expected <member>:<key>=<value>", tag);
+
+            let (member, pair) = tag.split_once(':').ok_or_else(invalid_tag)?;
+            let (key, value) = pair.split_once('=').ok_or_else(invalid_tag)?;
+            let member: usize = member.parse().map_err(|_| invalid_tag())?;
+
+            if tags.len() <= member {
+                tags.resize(member + 1, None);
+            }
+
+            tags[member]
+                .get_or_insert_with(Document::new)
+                .insert(key, value);
+        }
+
+        Ok(Self(tags))
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -87,23 +427,88 @@ struct ShardedOptions {
 
     /// the number of mongos routers to start
     #[structopt(long, default_value = "2")]
-    num_mongos: u8,
+    num_mongos: NonZeroU8,
 
     /// the number of shards to start
     #[structopt(long, default_value = "1")]
-    num_shards: u8,
+    num_shards: NonZeroU8,
 
     /// what type of shards to start
     #[structopt(long, possible_values(&["single", "replset"]), default_value = "replset")]
     shard_type: String,
+
+    /// the number of nodes in each replica set shard (ignored for single shards)
+    #[structopt(long, default_value = "3")]
+    nodes_per_shard: NonZeroU8,
+
+    /// the replica set name of the config server (defaults to "<shard-name-prefix>-config" if
+    /// --shard-name-prefix is given, or "phil-config-server" otherwise)
+    #[structopt(long)]
+    config_set_name: Option<String>,
+
+    /// the prefix of the shard names, which are numbered from 0 (defaults to "phil-replset-shard")
+    #[structopt(long)]
+    shard_name_prefix: Option<String>,
+
+    /// start a standalone config server rather than a replica set, which is needed for servers
+    /// older than 3.2
+    #[structopt(long, conflicts_with = "config-set-name")]
+    legacy_config_servers: bool,
+
+    /// the data directory for the config server, which allows the cluster metadata to persist
+    /// across runs (defaults to a new temporary directory); with more than one config server
+    /// node, each one uses a subdirectory of it
+    #[structopt(long)]
+    config_data_dir: Option<PathBuf>,
+
+    /// the number of nodes in the config server replica set
+    #[structopt(long, default_value = "1")]
+    config_nodes: NonZeroU8,
+
+    /// the version to use for the config servers instead of ID, for mixed-version testing
+    #[structopt(long)]
+    config_server_version: Option<String>,
+
+    /// the version to use for the shard servers instead of ID, for mixed-version testing
+    #[structopt(long)]
+    shard_version: Option<String>,
+
+    /// the version to use for the mongos routers instead of ID, for mixed-version testing
+    #[structopt(long)]
+    mongos_version: Option<String>,
+
+    /// how long (in milliseconds) each mongos waits on the config servers when refreshing its
+    /// catalog cache
+    #[structopt(long)]
+    mongos_config_refresh_ms: Option<u32>,
+
+    /// the maximum number of incoming connections each mongos accepts (defaults to
+    /// --max-incoming-connections)
+    #[structopt(long)]
+    mongos_max_incoming_connections: Option<NonZeroU32>,
+
+    /// an extra argument for each mongos; can be repeated (e.g.
+    /// --mongos-arg=--setParameter --mongos-arg=diagnosticDataCollectionEnabled=false)
+    #[structopt(long = "mongos-arg", allow_hyphen_values(true), number_of_values(1))]
+    mongos_args: Vec<String>,
+
+    /// emit a `loadBalanced=true` URI pointing at a single mongos; note that no actual load
+    /// balancer is started, so the mongos must be configured to accept load-balanced connections
+    #[structopt(long)]
+    load_balanced: bool,
 }
 
 #[derive(Debug, StructOpt)]
 struct CommonOptions {
-    /// the ID of the database version managed by monger to use
+    /// the ID of the database version managed by monger to use, or a version range (e.g. ~5.0)
+    /// to use the latest matching installed version
     #[structopt(name = "ID")]
     id: String,
 
+    /// a label for the cluster used in temp paths, progress output, and lifecycle events
+    #[structopt(long, parse(try_from_str = parse_label))]
+    name: Option<String>,
+
     /// enable (and require) TLS for the cluster
     #[structopt(long)]
     tls: bool,
@@ -125,59 +530,419 @@ struct CommonOptions {
     #[structopt(long, requires("tls"))]
     client_cert_file: Option<String>,
 
+    /// the passphrase for the servers' certificate key file, if it's encrypted
+    #[structopt(long, requires("tls"))]
+    cert_key_password: Option<String>,
+
+    /// fail rather than warn if the client and server certificate files are the same while client
+    /// certificates are required
+    #[structopt(long, requires("tls"))]
+    strict_tls: bool,
+
+    /// have phil's own client (and the emitted URI) reject invalid server certificates
+    #[structopt(long, requires("tls"))]
+    strict_phil_client_tls: bool,
+
+    /// have the servers accept invalid certificates from their peers
+    #[structopt(long, requires("tls"))]
+    server_allow_invalid_certs: bool,
+
+    /// enable encryption at rest (requires MongoDB Enterprise)
+    #[structopt(long)]
+    encrypt_at_rest: bool,
+
+    /// the key file to use for encryption at rest (a new key is generated if not specified)
+    #[structopt(long, requires("encrypt-at-rest"))]
+    encryption_key_file: Option<PathBuf>,
+
+    /// place the data directories on a tmpfs mount for speed (Linux only)
+    #[structopt(long)]
+    tmpfs: bool,
+
+    /// the tmpfs mount to place data directories under (defaults to /dev/shm)
+    #[structopt(long, requires("tmpfs"))]
+    tmpfs_path: Option<PathBuf>,
+
+    /// a directory to keep the data directories in with a fixed layout (e.g. node-0 or
+    /// shard-1-node-2), so that starting the same cluster again reuses its data (defaults to new
+    /// temporary directories)
+    #[structopt(long, parse(from_os_str), conflicts_with = "tmpfs")]
+    data_dir: Option<PathBuf>,
+
     /// require authentication to connect to the cluster
     #[structopt(long)]
     auth: bool,
 
-    /// log verbosely
+    /// the database to create the user in (defaults to admin)
+    #[structopt(long, requires("auth"))]
+    auth_source: Option<String>,
+
+    /// the username of the user created when auth is enabled (defaults to phil)
+    #[structopt(long, requires("auth"))]
+    username: Option<String>,
+
+    /// the password of the user created when auth is enabled (defaults to ravi)
+    #[structopt(long, requires("auth"))]
+    password: Option<String>,
+
+    /// a role to grant the user created when auth is enabled, as <role>@<db> (e.g.
+    /// readWrite@test); can be repeated, and defaults to root@admin
+    #[structopt(
+        long = "role",
+        requires("auth"),
+        number_of_values(1),
+        parse(try_from_str = parse_role)
+    )]
+    roles: Vec<Role>,
+
+    /// log phil's progress verbosely (see --mongod-verbosity for the servers' own logs)
     #[structopt(long, short)]
     verbose: bool,
 
+    /// the log verbosity of each mongod, independent of --verbose
+    #[structopt(long, possible_values(&["0", "1", "2", "3", "4", "5"]))]
+    mongod_verbosity: Option<u8>,
+
     /// use the deprecated `--ssl*` options instead of `--tls*` for the underlying mongod/mongos
-    /// binaries
-    #[structopt(long)]
+    /// binaries, which is needed for versions older than 4.2
+    #[structopt(long = "legacy-ssl-options", alias = "deprecated-tls", requires("tls"))]
     deprecated_tls: bool,
 
     /// whether to save the logs in the monger directory
     #[structopt(long)]
     save_logs: bool,
 
-    /// extra arguments for the mongod being run
+    /// have the servers create their unix domain sockets in their data directories and use the
+    /// socket paths in the emitted URI (unix only)
+    #[structopt(long)]
+    unix_socket: bool,
+
+    /// emit a `mongodb+srv://` URI for the given hostname; the DNS records it needs are printed
+    /// but must be set up separately
+    #[structopt(long, conflicts_with = "unix-socket")]
+    srv_hostname: Option<String>,
+
+    /// the hostname to use instead of `localhost` in the emitted URI and replica set configs (e.g.
+    /// a Docker service name); the servers' bind addresses are unchanged
+    #[structopt(long, conflicts_with = "unix-socket")]
+    advertise_host: Option<String>,
+
+    /// the address for the servers to listen on in addition to localhost (0.0.0.0 listens on every
+    /// interface); unless --advertise-host is given, a specific address is also used in the
+    /// emitted URI and replica set configs
+    #[structopt(long)]
+    bind_ip: Option<String>,
+
+    /// whether to enable retryable writes in the emitted URI (defaults to the driver's default)
+    #[structopt(long, possible_values(&["true", "false"]))]
+    retry_writes: Option<bool>,
+
+    /// the maxPoolSize to use in the emitted URI
+    #[structopt(long)]
+    max_pool_size: Option<u32>,
+
+    /// the minPoolSize to use in the emitted URI
+    #[structopt(long)]
+    min_pool_size: Option<u32>,
+
+    /// the maxConnecting to use in the emitted URI
+    #[structopt(long)]
+    max_connecting: Option<u32>,
+
+    /// a comma-separated list of IP addresses/CIDR ranges allowed to authenticate as cluster
+    /// members (must include the addresses of the cluster members themselves, e.g. 127.0.0.1)
+    #[structopt(long, use_delimiter = true)]
+    cluster_ip_source_allowlist: Vec<String>,
+
+    /// set the `enableTestCommands` server parameter on every mongod and mongos
+    #[structopt(long)]
+    enable_test_commands: bool,
+
+    /// skip ports that are already in use instead of failing to start
+    #[structopt(long)]
+    auto_port: bool,
+
+    /// write lifecycle events as JSON lines to the given file
+    #[structopt(long)]
+    events_file: Option<PathBuf>,
+
+    /// the interval in milliseconds between journal commits on each mongod (1 to 500)
+    #[structopt(long)]
+    journal_commit_interval_ms: Option<u32>,
+
+    /// the profiling level each data-bearing mongod starts with
+    #[structopt(long, possible_values(&["0", "1", "2"]))]
+    startup_profile_level: Option<u8>,
+
+    /// the slow operation threshold in milliseconds each data-bearing mongod starts with
+    #[structopt(long)]
+    startup_slow_ms: Option<u32>,
+
+    /// the WiredTiger block compressor for collection data on each data-bearing mongod
+    #[structopt(long, possible_values(&["snappy", "zlib", "zstd", "none"]))]
+    collection_block_compressor: Option<String>,
+
+    /// the WiredTiger compressor for the journal on each data-bearing mongod
+    #[structopt(long, possible_values(&["snappy", "zlib", "zstd", "none"]))]
+    journal_compressor: Option<String>,
+
+    /// the storage engine of each mongod, including the config servers (e.g. wiredTiger or
+    /// inMemory)
+    #[structopt(long)]
+    storage_engine: Option<String>,
+
+    /// the maximum size in gigabytes of the WiredTiger cache of each mongod, including the config
+    /// servers
+    #[structopt(long)]
+    wired_tiger_cache_size_gb: Option<f64>,
+
+    /// the maximum number of incoming connections each mongod and mongos accepts
+    #[structopt(long)]
+    max_incoming_connections: Option<NonZeroU32>,
+
+    /// the directory containing the `.monger` directory to use (defaults to the home directory)
+    #[structopt(long)]
+    monger_home: Option<PathBuf>,
+
+    /// write each server's log to a file in the given directory
+    #[structopt(long)]
+    log_dir: Option<PathBuf>,
+
+    /// send the servers' startup warnings to log files instead of stdout (see --log-dir)
+    #[structopt(long)]
+    suppress_startup_warnings: bool,
+
+    /// a comma-separated list of CPU cores to pin the mongods to, assigned round-robin (Linux
+    /// only)
+    #[structopt(long, use_delimiter = true)]
+    cpu_affinity: Vec<usize>,
+
+    /// a comma-separated list of network compressors for the servers and the emitted URI to use
+    #[structopt(long, use_delimiter = true, possible_values(&["snappy", "zlib", "zstd"]))]
+    compressors: Vec<String>,
+
+    /// the read preference mode to use in the emitted URI
+    #[structopt(long, possible_values(&[
+        "primary",
+        "primaryPreferred",
+        "secondary",
+        "secondaryPreferred",
+        "nearest",
+    ]))]
+    read_preference: Option<String>,
+
+    /// require a topology that supports sessions and use a "majority" read concern in the emitted
+    /// URI for causal consistency testing
+    #[structopt(long)]
+    require_sessions: bool,
+
+    /// start data-bearing mongods with majority read concern disabled
+    #[structopt(long, conflicts_with = "require-sessions")]
+    disable_majority_read_concern: bool,
+
+    /// the port of the first process started; the rest use the ports after it
+    #[structopt(long, default_value = "27017")]
+    start_port: u16,
+
+    /// the maximum number of mongod and mongos processes to start (defaults to 12)
+    #[structopt(long)]
+    max_nodes: Option<usize>,
+
+    /// start the cluster even if it needs more processes than the maximum
+    #[structopt(long)]
+    allow_large: bool,
+
+    /// the maximum number of seconds to wait for the cluster to start before giving up (0 waits
+    /// indefinitely)
+    #[structopt(long, default_value = "60")]
+    startup_timeout: u64,
+
+    /// the feature compatibility version to set once the cluster is ready (e.g. 4.4)
+    #[structopt(long)]
+    fcv: Option<String>,
+
+    /// the cluster-wide default write concern `w` value to set once the cluster is ready (e.g.
+    /// majority or 1); requires 4.4+ and a replica set or sharded cluster
+    #[structopt(long)]
+    default_write_concern: Option<String>,
+
+    /// the cluster-wide default read concern level to set once the cluster is ready; requires 4.4+
+    /// and a replica set or sharded cluster
+    #[structopt(long, possible_values(&["local", "available", "majority"]))]
+    default_read_concern: Option<String>,
+
+    /// a file recording the installed version the cluster was started with; if it already exists,
+    /// the version in it is used instead of the ID
+    #[structopt(long, parse(from_os_str))]
+    version_lock_file: Option<PathBuf>,
+
+    /// a file to write the cluster's connection string to, without the MONGODB_URI= prefix
+    #[structopt(long, parse(from_os_str))]
+    uri_file: Option<PathBuf>,
+
+    /// the file to record the cluster's processes in for `phil stop` (defaults to
+    /// ~/.phil/state.json); it must not already exist, so give each running cluster its own file
+    #[structopt(long, parse(from_os_str))]
+    state_file: Option<PathBuf>,
+
+    /// extra arguments for each mongod being run (mongos routers take theirs from --mongos-arg)
     #[structopt(name = "MONGODB_ARGS", last(true))]
     mongod_args: Vec<String>,
 }
 
 impl CommonOptions {
+    fn startup_timeout(&self) -> Option<Duration> {
+        match self.startup_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Replaces the ID with the version recorded in the lock file if there is one, returning the
+    /// path to write the resolved version to once the cluster starts.
+    fn apply_version_lock(&mut self) -> Result<Option<PathBuf>> {
+        let path = match self.version_lock_file {
+            Some(ref path) => path.clone(),
+            None => return Ok(None),
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) if !contents.trim().is_empty() => {
+                let locked = contents.trim();
+
+                if locked != self.id {
+                    eprintln!(
+                        "NOTE: using version {} from {} rather than {}\n",
+                        locked,
+                        path.display(),
+                        self.id
+                    );
+                }
+
+                self.id = locked.into();
+            }
+            Ok(..) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+
+        Ok(Some(path))
+    }
+
+    fn data_dir_base(&self) -> PathBuf {
+        if !self.tmpfs {
+            return std::env::temp_dir();
+        }
+
+        if cfg!(target_os = "linux") {
+            self.tmpfs_path
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("/dev/shm"))
+        } else {
+            eprintln!(
+                "WARNING: --tmpfs is only supported on Linux; using the default temp directory"
+            );
+
+            std::env::temp_dir()
+        }
+    }
+
+    /// The data directory for a node. With `--data-dir`, this is the subdirectory with the given
+    /// name, which is reused if it already exists; otherwise, it's a new temporary directory.
+    fn node_data_dir(&self, base: &Path, name: &str) -> Result<PathBuf> {
+        match self.data_dir {
+            Some(ref data_dir) => writable_dir(&data_dir.join(name)),
+            None => create_tempdir(base, self.name.as_deref()),
+        }
+    }
+
     fn tls_options(&self) -> Result<Option<TlsOptions>> {
         if !self.tls {
             return Ok(None);
         }
 
-        let ca_file_path =
-            Path::new(self.ca_file.as_deref().unwrap_or("./ca.pem")).canonicalize()?;
-        let server_cert_file_path =
-            Path::new(self.server_cert_file.as_deref().unwrap_or("./server.pem")).canonicalize()?;
-        let client_cert_file_path =
-            Path::new(self.client_cert_file.as_deref().unwrap_or("./client.pem")).canonicalize()?;
+        let ca_file_path = tls_file_path("CA", self.ca_file.as_deref().unwrap_or("./ca.pem"))?;
+        let server_cert_file_path = tls_file_path(
+            "server certificate",
+            self.server_cert_file.as_deref().unwrap_or("./server.pem"),
+        )?;
+        let client_cert_file_path = tls_file_path(
+            "client certificate",
+            self.client_cert_file.as_deref().unwrap_or("./client.pem"),
+        )?;
+
+        if !self.allow_clients_without_certs && client_cert_file_path == server_cert_file_path {
+            let message = format!(
+                "the client and server certificate files are both '{}', but client certificates \
+                 are required",
+                client_cert_file_path.display()
+            );
+
+            if self.strict_tls {
+                return Err(anyhow!(message));
+            }
+
+            eprintln!("WARNING: {}", message);
+        }
 
         Ok(Some(TlsOptions {
             weak_tls: self.allow_clients_without_certs,
-            allow_invalid_certificates: true,
+            allow_invalid_certificates: !self.strict_phil_client_tls,
+            server_allow_invalid_certificates: self.server_allow_invalid_certs,
             ca_file_path,
             server_cert_file_path,
             client_cert_file_path,
+            cert_key_password: self.cert_key_password.clone(),
         }))
     }
 
+    fn encryption_key_file(&self) -> Result<Option<PathBuf>> {
+        if !self.encrypt_at_rest {
+            return Ok(None);
+        }
+
+        if let Some(ref key_file) = self.encryption_key_file {
+            return Ok(Some(key_file.canonicalize()?));
+        }
+
+        // The server expects a base64-encoded 32-byte key.
+        let key: Vec<u8> = [Uuid::new_v4(), Uuid::new_v4()]
+            .iter()
+            .flat_map(|uuid| uuid.as_bytes().iter().copied())
+            .collect();
+
+        Ok(Some(create_tempfile(
+            "encryption-keyfile",
+            &base64::encode(key),
+            self.name.as_deref(),
+        )?))
+    }
+
+    fn read_preference(&self) -> Option<ReadPreference> {
+        let options = ReadPreferenceOptions::default();
+
+        let read_preference = match self.read_preference.as_deref()? {
+            "primaryPreferred" => ReadPreference::PrimaryPreferred { options },
+            "secondary" => ReadPreference::Secondary { options },
+            "secondaryPreferred" => ReadPreference::SecondaryPreferred { options },
+            "nearest" => ReadPreference::Nearest { options },
+            _ => ReadPreference::Primary,
+        };
+
+        Some(read_preference)
+    }
+
     fn auth_options(&self) -> Result<Option<Credential>> {
         if !self.auth {
             return Ok(None);
         }
 
         Ok(Some(Credential {
-            username: "phil".into(),
-            password: "ravi".into(),
-            key_file: create_tempfile()?,
+            username: self.username.clone().unwrap_or_else(|| "phil".into()),
+            password: self.password.clone().unwrap_or_else(|| "ravi".into()),
+            key_file: create_tempfile("keyfile", "phil and ravi", self.name.as_deref())?,
+            source: self.auth_source.clone(),
+            roles: self.roles.clone(),
         }))
     }
 }
@@ -186,19 +951,66 @@ impl TryFrom<SingleOptions> for ClusterOptions {
     type Error = Error;
 
     fn try_from(opts: SingleOptions) -> Result<Self> {
+        // A single server uses the server's default data directory unless one is passed as an
+        // extra argument.
+        let data_dir_args: Vec<OsString> = if opts.common.data_dir.is_some() {
+            let dir = opts
+                .common
+                .node_data_dir(&opts.common.data_dir_base(), "node-0")?;
+            vec!["--dbpath".into(), dir.into()]
+        } else {
+            Vec::new()
+        };
+
         Ok(ClusterOptions::builder()
             .topology(Topology::Single)
             .tls(opts.common.tls_options()?)
             .auth(opts.common.auth_options()?)
+            .encryption_key_file(opts.common.encryption_key_file()?)
+            .read_preference(opts.common.read_preference())
             .version_id(opts.common.id)
-            .verbose(opts.common.verbose)
             .deprecated_tls_options(opts.common.deprecated_tls)
             .save_logs(opts.common.save_logs)
+            .startup_timeout(opts.common.startup_timeout())
+            .feature_compatibility_version(opts.common.fcv)
+            .default_write_concern(opts.common.default_write_concern)
+            .default_read_concern(opts.common.default_read_concern)
+            .unix_socket(opts.common.unix_socket)
+            .srv_hostname(opts.common.srv_hostname)
+            .advertise_host(opts.common.advertise_host)
+            .bind_ip(opts.common.bind_ip)
+            .retry_writes(opts.common.retry_writes)
+            .max_pool_size(opts.common.max_pool_size)
+            .min_pool_size(opts.common.min_pool_size)
+            .max_connecting(opts.common.max_connecting)
+            .cluster_ip_source_allowlist(opts.common.cluster_ip_source_allowlist)
+            .enable_test_commands(opts.common.enable_test_commands)
+            .auto_port(opts.common.auto_port)
+            .events_file(opts.common.events_file)
+            .journal_commit_interval_ms(opts.common.journal_commit_interval_ms)
+            .startup_profile_level(opts.common.startup_profile_level)
+            .startup_slow_ms(opts.common.startup_slow_ms)
+            .collection_block_compressor(opts.common.collection_block_compressor)
+            .journal_compressor(opts.common.journal_compressor)
+            .storage_engine(opts.common.storage_engine)
+            .wired_tiger_cache_size_gb(opts.common.wired_tiger_cache_size_gb)
+            .max_incoming_connections(opts.common.max_incoming_connections.map(NonZeroU32::get))
+            .mongod_verbosity(opts.common.mongod_verbosity)
+            .monger_home(opts.common.monger_home)
+            .name(opts.common.name)
+            .require_sessions(opts.common.require_sessions)
+            .disable_majority_read_concern(opts.common.disable_majority_read_concern)
+            .compressors(opts.common.compressors)
+            .cpu_affinity(opts.common.cpu_affinity)
+            .log_dir(opts.common.log_dir)
+            .suppress_startup_warnings(opts.common.suppress_startup_warnings)
+            .start_port(opts.common.start_port)
+            .max_nodes(opts.common.max_nodes)
+            .allow_large(opts.common.allow_large)
             .extra_mongod_args(
-                opts.common
-                    .mongod_args
+                data_dir_args
                     .into_iter()
-                    .map(OsString::from)
+                    .chain(opts.common.mongod_args.into_iter().map(OsString::from))
                     .collect(),
             )
             .build())
@@ -209,19 +1021,74 @@ impl TryFrom<ReplSetOptions> for ClusterOptions {
     type Error = Error;
 
     fn try_from(opts: ReplSetOptions) -> Result<Self> {
-        let paths: Result<Vec<_>> = (0..opts.nodes).map(|_| create_tempdir()).collect();
+        let base = opts.common.data_dir_base();
+        let member_tags = opts.tags.unwrap_or_default().0;
+
+        if member_tags.len() > opts.nodes.get() as usize {
+            return Err(anyhow!(
+                "tags specified for member {}, but the replica set only has {} nodes",
+                member_tags.len() - 1,
+                opts.nodes
+            ));
+        }
+
+        let paths: Result<Vec<_>> = (0..opts.nodes.get())
+            .map(|i| opts.common.node_data_dir(&base, &format!("node-{}", i)))
+            .collect();
 
         Ok(ClusterOptions::builder()
             .tls(opts.common.tls_options()?)
             .auth(opts.common.auth_options()?)
+            .encryption_key_file(opts.common.encryption_key_file()?)
+            .read_preference(opts.common.read_preference())
             .topology(Topology::ReplicaSet {
                 set_name: opts.set_name,
                 db_paths: paths?,
+                members: Vec::new(),
             })
+            .member_tags(member_tags)
+            .no_initiate(opts.no_initiate)
+            .wait_for_primary(!opts.no_wait_for_primary)
+            .repl_set_config_version(opts.replset_config_version)
             .version_id(opts.common.id)
-            .verbose(opts.common.verbose)
             .deprecated_tls_options(opts.common.deprecated_tls)
             .save_logs(opts.common.save_logs)
+            .startup_timeout(opts.common.startup_timeout())
+            .feature_compatibility_version(opts.common.fcv)
+            .default_write_concern(opts.common.default_write_concern)
+            .default_read_concern(opts.common.default_read_concern)
+            .unix_socket(opts.common.unix_socket)
+            .srv_hostname(opts.common.srv_hostname)
+            .advertise_host(opts.common.advertise_host)
+            .bind_ip(opts.common.bind_ip)
+            .retry_writes(opts.common.retry_writes)
+            .max_pool_size(opts.common.max_pool_size)
+            .min_pool_size(opts.common.min_pool_size)
+            .max_connecting(opts.common.max_connecting)
+            .cluster_ip_source_allowlist(opts.common.cluster_ip_source_allowlist)
+            .enable_test_commands(opts.common.enable_test_commands)
+            .auto_port(opts.common.auto_port)
+            .events_file(opts.common.events_file)
+            .journal_commit_interval_ms(opts.common.journal_commit_interval_ms)
+            .startup_profile_level(opts.common.startup_profile_level)
+            .startup_slow_ms(opts.common.startup_slow_ms)
+            .collection_block_compressor(opts.common.collection_block_compressor)
+            .journal_compressor(opts.common.journal_compressor)
+            .storage_engine(opts.common.storage_engine)
+            .wired_tiger_cache_size_gb(opts.common.wired_tiger_cache_size_gb)
+            .max_incoming_connections(opts.common.max_incoming_connections.map(NonZeroU32::get))
+            .mongod_verbosity(opts.common.mongod_verbosity)
+            .monger_home(opts.common.monger_home)
+            .name(opts.common.name)
+            .require_sessions(opts.common.require_sessions)
+            .disable_majority_read_concern(opts.common.disable_majority_read_concern)
+            .compressors(opts.common.compressors)
+            .cpu_affinity(opts.common.cpu_affinity)
+            .log_dir(opts.common.log_dir)
+            .suppress_startup_warnings(opts.common.suppress_startup_warnings)
+            .start_port(opts.common.start_port)
+            .max_nodes(opts.common.max_nodes)
+            .allow_large(opts.common.allow_large)
             .extra_mongod_args(
                 opts.common
                     .mongod_args
@@ -233,21 +1100,42 @@ impl TryFrom<ReplSetOptions> for ClusterOptions {
     }
 }
 
+impl ShardedOptions {
+    fn config_db_paths(&self, base: &Path) -> Result<Vec<PathBuf>> {
+        let nodes = self.config_nodes.get();
+
+        match self.config_data_dir {
+            Some(ref dir) if nodes == 1 => Ok(vec![writable_dir(dir)?]),
+            Some(ref dir) => (0..nodes)
+                .map(|i| writable_dir(&dir.join(format!("config-{}", i))))
+                .collect(),
+            None if nodes == 1 => Ok(vec![self.common.node_data_dir(base, "config")?]),
+            None => (0..nodes)
+                .map(|i| self.common.node_data_dir(base, &format!("config-{}", i)))
+                .collect(),
+        }
+    }
+}
+
 impl TryFrom<ShardedOptions> for ClusterOptions {
     type Error = Error;
 
     fn try_from(opts: ShardedOptions) -> Result<Self> {
-        let db_paths: Result<_> = (0..opts.num_shards)
-            .map(|_| {
-                if opts.shard_type == "replset" {
-                    Ok(vec![
-                        create_tempdir()?,
-                        create_tempdir()?,
-                        create_tempdir()?,
-                    ])
-                } else {
-                    Ok(vec![create_tempdir()?])
-                }
+        let base = opts.common.data_dir_base();
+        let nodes_per_shard = if opts.shard_type == "replset" {
+            opts.nodes_per_shard.get()
+        } else {
+            1
+        };
+
+        let db_paths: Result<_> = (0..opts.num_shards.get())
+            .map(|shard| {
+                (0..nodes_per_shard)
+                    .map(|node| {
+                        let name = format!("shard-{}-node-{}", shard, node);
+                        opts.common.node_data_dir(&base, &name)
+                    })
+                    .collect()
             })
             .collect();
 
@@ -255,14 +1143,64 @@ impl TryFrom<ShardedOptions> for ClusterOptions {
             .topology(Topology::Sharded {
                 num_mongos: opts.num_mongos,
                 shard_db_paths: db_paths?,
-                config_db_path: create_tempdir()?,
+                config_db_paths: opts.config_db_paths(&base)?,
             })
+            .load_balanced(opts.load_balanced)
+            .config_server_version_id(opts.config_server_version)
+            .shard_version_id(opts.shard_version)
+            .mongos_version_id(opts.mongos_version)
+            .mongos_config_refresh_ms(opts.mongos_config_refresh_ms)
+            .extra_mongos_args(opts.mongos_args.into_iter().map(OsString::from).collect())
+            .mongos_max_incoming_connections(
+                opts.mongos_max_incoming_connections.map(NonZeroU32::get),
+            )
+            .config_set_name(opts.config_set_name)
+            .shard_name_prefix(opts.shard_name_prefix)
+            .one_node_replset_shards(opts.shard_type == "replset")
+            .legacy_config_servers(opts.legacy_config_servers)
             .tls(opts.common.tls_options()?)
             .auth(opts.common.auth_options()?)
+            .encryption_key_file(opts.common.encryption_key_file()?)
+            .read_preference(opts.common.read_preference())
             .version_id(opts.common.id)
-            .verbose(opts.common.verbose)
             .deprecated_tls_options(opts.common.deprecated_tls)
             .save_logs(opts.common.save_logs)
+            .startup_timeout(opts.common.startup_timeout())
+            .feature_compatibility_version(opts.common.fcv)
+            .default_write_concern(opts.common.default_write_concern)
+            .default_read_concern(opts.common.default_read_concern)
+            .unix_socket(opts.common.unix_socket)
+            .srv_hostname(opts.common.srv_hostname)
+            .advertise_host(opts.common.advertise_host)
+            .bind_ip(opts.common.bind_ip)
+            .retry_writes(opts.common.retry_writes)
+            .max_pool_size(opts.common.max_pool_size)
+            .min_pool_size(opts.common.min_pool_size)
+            .max_connecting(opts.common.max_connecting)
+            .cluster_ip_source_allowlist(opts.common.cluster_ip_source_allowlist)
+            .enable_test_commands(opts.common.enable_test_commands)
+            .auto_port(opts.common.auto_port)
+            .events_file(opts.common.events_file)
+            .journal_commit_interval_ms(opts.common.journal_commit_interval_ms)
+            .startup_profile_level(opts.common.startup_profile_level)
+            .startup_slow_ms(opts.common.startup_slow_ms)
+            .collection_block_compressor(opts.common.collection_block_compressor)
+            .journal_compressor(opts.common.journal_compressor)
+            .storage_engine(opts.common.storage_engine)
+            .wired_tiger_cache_size_gb(opts.common.wired_tiger_cache_size_gb)
+            .max_incoming_connections(opts.common.max_incoming_connections.map(NonZeroU32::get))
+            .mongod_verbosity(opts.common.mongod_verbosity)
+            .monger_home(opts.common.monger_home)
+            .name(opts.common.name)
+            .require_sessions(opts.common.require_sessions)
+            .disable_majority_read_concern(opts.common.disable_majority_read_concern)
+            .compressors(opts.common.compressors)
+            .cpu_affinity(opts.common.cpu_affinity)
+            .log_dir(opts.common.log_dir)
+            .suppress_startup_warnings(opts.common.suppress_startup_warnings)
+            .start_port(opts.common.start_port)
+            .max_nodes(opts.common.max_nodes)
+            .allow_large(opts.common.allow_large)
             .extra_mongod_args(
                 opts.common
                     .mongod_args
@@ -274,37 +1212,161 @@ impl TryFrom<ShardedOptions> for ClusterOptions {
     }
 }
 
-fn main() -> Result<()> {
-    let cluster_options = match Command::from_args() {
-        Command::Single { options } => options.try_into()?,
-        Command::ReplSet { options } => options.try_into()?,
-        Command::Sharded { options } => options.try_into()?,
-        Command::SelfUpdate => {
-            let status = Update::configure()
-                .repo_owner("saghm")
-                .repo_name("phil")
-                .current_version(env!("CARGO_PKG_VERSION"))
-                .bin_name(env!("CARGO_PKG_NAME"))
-                .show_download_progress(true)
-                .build()?
-                .update()?;
-
-            if status.uptodate() {
-                println!("Already have the latest version");
-            } else {
-                println!("Downloaded and installed {}", status.version());
+/// Prints phil-core's progress messages to stderr, leaving stdout for the connection string.
+/// `RUST_LOG` can be used to override the level.
+fn init_logger(verbose: bool) {
+    let level = if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+
+    env_logger::Builder::new()
+        .filter_module("phil_core", level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            Level::Error | Level::Warn => writeln!(buf, "WARNING: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+}
+
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("Error: {}", error::describe(&error));
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let command = Command::from_args();
+    let verbose = command.verbose();
+
+    init_logger(verbose);
+
+    let (cluster_options, lock_file, uri_file, state_file): (ClusterOptions, _, _, _) =
+        match command {
+            Command::Single { mut options } => {
+                let lock_file = options.common.apply_version_lock()?;
+                let uri_file = options.common.uri_file.take();
+                let state_file = options.common.state_file.take();
+                (options.try_into()?, lock_file, uri_file, state_file)
+            }
+            Command::ReplSet { mut options } => {
+                let lock_file = options.common.apply_version_lock()?;
+                let uri_file = options.common.uri_file.take();
+                let state_file = options.common.state_file.take();
+                (options.try_into()?, lock_file, uri_file, state_file)
+            }
+            Command::Sharded { mut options } => {
+                let lock_file = options.common.apply_version_lock()?;
+                let uri_file = options.common.uri_file.take();
+                let state_file = options.common.state_file.take();
+                (options.try_into()?, lock_file, uri_file, state_file)
             }
+            Command::Connect { uri, shell } => {
+                let shell = match shell {
+                    Some(shell) => find_shell(&shell)?,
+                    None => find_in_path("mongosh")
+                        .or_else(|| find_in_path("mongo"))
+                        .ok_or_else(|| {
+                            anyhow!(
+                            "unable to find mongosh or mongo in the PATH; install mongosh from \
+                             https://www.mongodb.com/try/download/shell"
+                        )
+                        })?,
+                };
 
-            return Ok(());
-        }
-    };
+                let status = std::process::Command::new(shell).arg(uri).status()?;
+                std::process::exit(status.code().unwrap_or(1));
+            }
+            Command::Check { options } => return options.run(),
+            Command::Shutdown { uri } => {
+                phil_core::shutdown::shutdown(&uri)?;
+
+                return Ok(());
+            }
+            Command::Stop { state_file } => {
+                let state_file = state_file.or_else(default_state_file).ok_or_else(|| {
+                    anyhow!("no home directory was found; pass the state file with --state-file")
+                })?;
+                phil_core::shutdown::stop(&state_file)?;
+
+                return Ok(());
+            }
+            Command::Completions { shell } => {
+                Command::clap().gen_completions_to(
+                    env!("CARGO_PKG_NAME"),
+                    shell,
+                    &mut std::io::stdout(),
+                );
+
+                return Ok(());
+            }
+            #[cfg(feature = "self-update")]
+            Command::SelfUpdate { check_only } => {
+                return if check_only {
+                    update::check_for_update()
+                } else {
+                    update::update()
+                };
+            }
+        };
+
+    // If phil is interrupted while starting a cluster, shut down the servers that were already
+    // started rather than leaving them orphaned. phil-core can't terminate processes on Windows,
+    // so the handler isn't installed there.
+    if cfg!(unix) {
+        ctrlc::set_handler(|| {
+            terminate_started_processes();
+            std::process::exit(130);
+        })?;
+    }
 
     let cluster = Cluster::new(cluster_options)?;
 
-    println!(
-        "MONGODB_URI='{}'",
-        ClientOptionsWrapper(cluster.client_options())
-    );
+    if let Some(path) = lock_file {
+        std::fs::write(&path, format!("{}\n", cluster.version_id()))?;
+    }
+
+    if verbose {
+        for (set_name, members) in cluster.shard_replica_sets() {
+            let members: Vec<_> = members.iter().map(ToString::to_string).collect();
+            eprintln!("shard '{}': {}", set_name, members.join(","));
+        }
+    }
+
+    let srv_records = cluster.srv_records();
+
+    if !srv_records.is_empty() {
+        eprintln!("WARNING: the following DNS records must exist for the SRV URI to resolve:");
+
+        for record in srv_records {
+            eprintln!("    {}", record);
+        }
+
+        eprintln!();
+    }
+
+    if verbose {
+        eprintln!(
+            "to recreate this cluster, run: {}\n",
+            cluster.reproduce_command()
+        );
+    }
+
+    write_state_file(&cluster, state_file);
+
+    let uri = cluster.connection_string();
+
+    if let Some(path) = uri_file {
+        write_uri_file(&path, &uri)?;
+    }
+
+    println!("MONGODB_URI='{}'", uri);
+
+    // The servers need to keep running after phil exits.
+    cluster.detach();
 
     Ok(())
 }
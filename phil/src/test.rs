@@ -0,0 +1,316 @@
+use super::*;
+
+#[test]
+fn zero_replica_set_nodes_is_rejected() {
+    assert!(Command::from_iter_safe(&["phil", "replset", "4.2", "--nodes", "0"]).is_err());
+}
+
+#[test]
+fn zero_mongos_is_rejected() {
+    assert!(Command::from_iter_safe(&["phil", "sharded", "4.2", "--num-mongos", "0"]).is_err());
+}
+
+#[test]
+fn zero_shards_is_rejected() {
+    assert!(Command::from_iter_safe(&["phil", "sharded", "4.2", "--num-shards", "0"]).is_err());
+}
+
+#[test]
+fn nonzero_counts_are_accepted() {
+    match Command::from_iter_safe(&["phil", "sharded", "4.2", "--num-mongos", "3"]).unwrap() {
+        Command::Sharded { options } => assert_eq!(options.num_mongos.get(), 3),
+        other => panic!("expected sharded command, got {:?}", other),
+    }
+}
+
+#[test]
+fn completions_generates_bash_script() {
+    let mut script = Vec::new();
+    Command::clap().gen_completions_to("phil", Shell::Bash, &mut script);
+
+    assert!(String::from_utf8(script).unwrap().contains("replset"));
+}
+
+#[test]
+fn labels_with_path_separators_are_rejected() {
+    assert!(parse_label("my-cluster_1").is_ok());
+    assert!(parse_label("../cluster").is_err());
+    assert!(parse_label("").is_err());
+}
+
+#[test]
+fn add_shard_errors_show_the_server_message() {
+    let error = anyhow::Error::from(phil_core::error::Error::AddShardError {
+        response: mongodb::bson::doc! {
+            "ok": 0.0,
+            "errmsg": "host is already part of a shard",
+            "codeName": "IllegalOperation",
+        },
+        set_name: Some("phil-replset-shard-0".into()),
+        port: None,
+    });
+
+    assert_eq!(
+        error::describe(&error),
+        "error when adding shard 'phil-replset-shard-0': host is already part of a shard \
+         (IllegalOperation)"
+    );
+}
+
+#[test]
+fn disabling_majority_read_concern_conflicts_with_sessions() {
+    assert!(Command::from_iter_safe(&[
+        "phil",
+        "replset",
+        "4.2",
+        "--require-sessions",
+        "--disable-majority-read-concern",
+    ])
+    .is_err());
+}
+
+#[test]
+fn zero_max_incoming_connections_is_rejected() {
+    assert!(
+        Command::from_iter_safe(&["phil", "single", "4.2", "--max-incoming-connections", "0"])
+            .is_err()
+    );
+}
+
+#[cfg(feature = "self-update")]
+#[test]
+fn self_update_accepts_check_only() {
+    match Command::from_iter_safe(&["phil", "self-update", "--check-only"]).unwrap() {
+        Command::SelfUpdate { check_only } => assert!(check_only),
+        other => panic!("expected self-update command, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "self-update")]
+#[test]
+fn self_update_permission_errors_suggest_sudo() {
+    let error = update::describe(self_update::errors::Error::Io(std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "denied",
+    )));
+
+    assert!(error.to_string().contains("sudo"));
+}
+
+#[test]
+fn version_lock_file_overrides_id() {
+    let lock_file = std::env::temp_dir().join(temp_name("phil-version-lock", None));
+    std::fs::write(&lock_file, "4.2.8\n").unwrap();
+
+    let lock_file_arg = lock_file.to_string_lossy().into_owned();
+    let command = Command::from_iter_safe(&[
+        "phil",
+        "single",
+        "4.2",
+        "--version-lock-file",
+        &lock_file_arg,
+    ])
+    .unwrap();
+
+    match command {
+        Command::Single { mut options } => {
+            assert_eq!(
+                options.common.apply_version_lock().unwrap(),
+                Some(lock_file.clone())
+            );
+            assert_eq!(options.common.id, "4.2.8");
+        }
+        other => panic!("expected single command, got {:?}", other),
+    }
+
+    std::fs::remove_file(lock_file).unwrap();
+}
+
+#[test]
+fn startup_timeout_defaults_to_a_minute() {
+    match Command::from_iter_safe(&["phil", "single", "4.2"]).unwrap() {
+        Command::Single { options } => {
+            assert_eq!(
+                options.common.startup_timeout(),
+                Some(Duration::from_secs(60))
+            )
+        }
+        other => panic!("expected single command, got {:?}", other),
+    }
+
+    match Command::from_iter_safe(&["phil", "single", "4.2", "--startup-timeout", "0"]).unwrap() {
+        Command::Single { options } => assert_eq!(options.common.startup_timeout(), None),
+        other => panic!("expected single command, got {:?}", other),
+    }
+}
+
+#[test]
+fn username_and_password_require_auth() {
+    assert!(Command::from_iter_safe(&["phil", "single", "4.2", "--username", "admin"]).is_err());
+    assert!(Command::from_iter_safe(&["phil", "single", "4.2", "--password", "secret"]).is_err());
+    assert!(Command::from_iter_safe(&[
+        "phil",
+        "single",
+        "4.2",
+        "--auth",
+        "--username",
+        "admin",
+        "--password",
+        "secret",
+    ])
+    .is_ok());
+}
+
+#[test]
+fn roles_are_parsed_as_role_at_db() {
+    assert_eq!(
+        parse_role("readWrite@test").unwrap(),
+        Role {
+            role: "readWrite".into(),
+            db: "test".into(),
+        }
+    );
+    assert!(parse_role("readWrite").is_err());
+    assert!(parse_role("@test").is_err());
+}
+
+#[test]
+fn legacy_ssl_options_require_tls() {
+    assert!(Command::from_iter_safe(&["phil", "single", "3.6", "--legacy-ssl-options"]).is_err());
+
+    match Command::from_iter_safe(&["phil", "single", "3.6", "--tls", "--legacy-ssl-options"])
+        .unwrap()
+    {
+        Command::Single { options } => assert!(options.common.deprecated_tls),
+        other => panic!("expected single command, got {:?}", other),
+    }
+}
+
+#[test]
+fn mongos_args_can_start_with_hyphens() {
+    match Command::from_iter_safe(&[
+        "phil",
+        "sharded",
+        "4.2",
+        "--mongos-arg",
+        "--setParameter",
+        "--mongos-arg",
+        "diagnosticDataCollectionEnabled=false",
+    ])
+    .unwrap()
+    {
+        Command::Sharded { options } => assert_eq!(
+            options.mongos_args,
+            vec!["--setParameter", "diagnosticDataCollectionEnabled=false"]
+        ),
+        other => panic!("expected sharded command, got {:?}", other),
+    }
+}
+
+#[test]
+fn nodes_per_shard_must_be_positive() {
+    assert!(
+        Command::from_iter_safe(&["phil", "sharded", "4.2", "--nodes-per-shard", "0"]).is_err()
+    );
+
+    match Command::from_iter_safe(&["phil", "sharded", "4.2", "--nodes-per-shard", "5"]).unwrap() {
+        Command::Sharded { options } => assert_eq!(options.nodes_per_shard.get(), 5),
+        other => panic!("expected sharded command, got {:?}", other),
+    }
+}
+
+#[test]
+fn data_dir_layout_is_reused() {
+    let base = std::env::temp_dir().join(temp_name("phil-data-dir", None));
+    let base_arg = base.to_string_lossy().into_owned();
+
+    match Command::from_iter_safe(&["phil", "replset", "4.2", "--data-dir", &base_arg]).unwrap() {
+        Command::ReplSet { options } => {
+            let first = options.common.node_data_dir(&base, "node-0").unwrap();
+            let second = options.common.node_data_dir(&base, "node-0").unwrap();
+
+            assert_eq!(first, second);
+            assert!(first.ends_with("node-0"));
+        }
+        other => panic!("expected replset command, got {:?}", other),
+    }
+
+    std::fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn missing_tls_file_error_names_the_file() {
+    let error = tls_file_path("CA", "./phil-missing-ca.pem").unwrap_err();
+
+    assert_eq!(
+        error.to_string(),
+        "TLS CA file not found: ./phil-missing-ca.pem"
+    );
+}
+
+#[test]
+fn same_client_and_server_cert_is_rejected_when_strict() {
+    let cert_file = std::env::temp_dir().join(temp_name("phil-cert", None));
+    std::fs::write(&cert_file, "").unwrap();
+    let cert_file_arg = cert_file.to_string_lossy().into_owned();
+
+    let tls_options = |extra_args: &[&str]| {
+        let mut args = vec![
+            "phil",
+            "single",
+            "4.2",
+            "--tls",
+            "--ca-file",
+            &cert_file_arg,
+            "--server-cert-file",
+            &cert_file_arg,
+            "--client-cert-file",
+            &cert_file_arg,
+        ];
+        args.extend_from_slice(extra_args);
+
+        match Command::from_iter_safe(&args).unwrap() {
+            Command::Single { options } => options.common.tls_options(),
+            other => panic!("expected single command, got {:?}", other),
+        }
+    };
+
+    assert!(tls_options(&[]).unwrap().is_some());
+    assert!(tls_options(&["--strict-tls"]).is_err());
+    assert!(
+        tls_options(&["--strict-tls", "--allow-clients-without-certs"])
+            .unwrap()
+            .is_some()
+    );
+
+    std::fs::remove_file(cert_file).unwrap();
+}
+
+#[test]
+fn uri_file_contains_only_the_connection_string() {
+    let path = std::env::temp_dir().join(format!("phil-uri-{}", Uuid::new_v4()));
+
+    write_uri_file(&path, "mongodb://localhost:27017").unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "mongodb://localhost:27017"
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn uri_file_write_failure_keeps_the_io_error() {
+    let path = std::env::temp_dir()
+        .join(Uuid::new_v4().to_string())
+        .join("uri");
+
+    let error = write_uri_file(&path, "mongodb://localhost:27017").unwrap_err();
+
+    assert_eq!(
+        error
+            .downcast_ref::<std::io::Error>()
+            .map(std::io::Error::kind),
+        Some(std::io::ErrorKind::NotFound)
+    );
+}
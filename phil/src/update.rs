@@ -0,0 +1,94 @@
+use std::{io::ErrorKind, time::Duration};
+
+use anyhow::{anyhow, Result};
+use self_update::{
+    backends::github::Update,
+    errors::Error as UpdateError,
+    update::ReleaseUpdate,
+    version::bump_is_greater,
+};
+
+const RELEASES_URL: &str = "https://github.com/saghm/phil/releases";
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Network errors are retried a few times before giving up, since GitHub occasionally drops
+// requests.
+const MAX_ATTEMPTS: u32 = 3;
+
+fn updater() -> std::result::Result<Box<dyn ReleaseUpdate>, UpdateError> {
+    Update::configure()
+        .repo_owner("saghm")
+        .repo_name("phil")
+        .current_version(CURRENT_VERSION)
+        .bin_name(env!("CARGO_PKG_NAME"))
+        .show_download_progress(true)
+        .build()
+}
+
+fn is_network_error(error: &UpdateError) -> bool {
+    matches!(error, UpdateError::Network(..) | UpdateError::Reqwest(..))
+}
+
+fn with_retries<T>(
+    mut f: impl FnMut() -> std::result::Result<T, UpdateError>,
+) -> std::result::Result<T, UpdateError> {
+    let mut attempt = 1;
+
+    loop {
+        match f() {
+            Err(ref error) if is_network_error(error) && attempt < MAX_ATTEMPTS => {
+                attempt += 1;
+                std::thread::sleep(Duration::from_secs(1));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Turns an error from `self_update` into one that says what the user can do about it.
+pub(crate) fn describe(error: UpdateError) -> anyhow::Error {
+    match error {
+        ref error if is_network_error(error) => anyhow!(
+            "couldn't reach GitHub to check for a newer version of phil ({}); check your network \
+             connection and try again",
+            error
+        ),
+        UpdateError::Io(ref error) if error.kind() == ErrorKind::PermissionDenied => anyhow!(
+            "permission denied while replacing the phil binary; rerun with sudo or download the \
+             latest release manually from {}",
+            RELEASES_URL
+        ),
+        error => anyhow!(
+            "updating phil failed: {}; the latest release can be downloaded manually from {}",
+            error,
+            RELEASES_URL
+        ),
+    }
+}
+
+pub(crate) fn check_for_update() -> Result<()> {
+    let latest = with_retries(|| updater()?.get_latest_release()).map_err(describe)?;
+
+    if bump_is_greater(CURRENT_VERSION, &latest.version).unwrap_or(false) {
+        println!(
+            "phil {} is available (currently on {}); run `phil self-update` to install it",
+            latest.version, CURRENT_VERSION
+        );
+    } else {
+        println!("Already have the latest version");
+    }
+
+    Ok(())
+}
+
+pub(crate) fn update() -> Result<()> {
+    let status = with_retries(|| updater()?.update()).map_err(describe)?;
+
+    if status.uptodate() {
+        println!("Already have the latest version");
+    } else {
+        println!("Downloaded and installed {}", status.version());
+    }
+
+    Ok(())
+}